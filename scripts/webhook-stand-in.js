@@ -0,0 +1,36 @@
+// Local webhook receiver for testing alert webhooks against a local replica.
+// Usage: node scripts/webhook-stand-in.js <port> <secret>
+const http = require("http");
+const crypto = require("crypto");
+
+const port = Number(process.argv[2] || 8080);
+const secret = process.argv[3] || "";
+const seen = new Set();
+
+http
+  .createServer((req, res) => {
+    let body = "";
+    req.on("data", (chunk) => (body += chunk));
+    req.on("end", () => {
+      const delivery = req.headers["x-webhook-delivery"];
+      const timestamp = req.headers["x-webhook-timestamp"];
+      const signature = req.headers["x-webhook-signature"] || "";
+      const expected =
+        "sha256=" +
+        crypto.createHmac("sha256", secret).update(`${timestamp}.${body}`).digest("hex");
+      if (signature !== expected) {
+        res.writeHead(401);
+        res.end();
+        console.log(`delivery ${delivery}: bad signature`);
+        return;
+      }
+      // Every replica sends the request, log each delivery once.
+      if (!seen.has(delivery)) {
+        seen.add(delivery);
+        console.log(`delivery ${delivery}: ${body}`);
+      }
+      res.writeHead(200);
+      res.end();
+    });
+  })
+  .listen(port, () => console.log(`listening on http://localhost:${port}/`));
@@ -0,0 +1,358 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+
+use crate::{anomaly, webhooks};
+use crate::common::types::{AlertEvent, AlertInboxItem, AlertInboxPage, AlertKind, AlertSeverity, AlertState, MemberRole, OrganizeName, ThresholdAction, ThresholdLevel};
+use crate::{is_admin, is_owner, CANISTERS_TO_ORGANIZES, ORGANIZES_TO_MEMBERS, ORGANIZES_TO_OWNER};
+
+const MAX_ALERTS_PER_ORGANIZE: usize = 1_000;  // 每个组织最多保留的告警数 超出时删除最早的
+const MAX_PAGE_SIZE: u64 = 100;  // 收件箱单次查询最多返回的告警数
+
+thread_local!{
+    static ALERTS:RefCell<BTreeMap<u64, AlertEvent>> = RefCell::default();  // 告警事件 id -> 事件
+    static NEXT_ALERT_ID:Cell<u64> = const { Cell::new(0) };
+    static READ_ALERTS:RefCell<BTreeSet<(Principal, u64)>> = RefCell::default();  // (成员, 告警 id) 已读记录
+}
+
+// 升级时保存的告警状态
+#[derive(CandidType, Deserialize, Default)]
+pub struct AlertsStable {
+    alerts: BTreeMap<u64, AlertEvent>,
+    next_alert_id: u64,
+    #[serde(default)]
+    read_alerts: BTreeSet<(Principal, u64)>,
+}
+
+pub fn snapshot() -> AlertsStable {
+    AlertsStable {
+        alerts: ALERTS.with(|alerts| alerts.borrow().clone()),
+        next_alert_id: NEXT_ALERT_ID.with(|id| id.get()),
+        read_alerts: READ_ALERTS.with(|read| read.borrow().clone()),
+    }
+}
+
+pub fn restore(stable: AlertsStable) {
+    ALERTS.with(|alerts| *alerts.borrow_mut() = stable.alerts);
+    NEXT_ALERT_ID.with(|id| id.set(stable.next_alert_id));
+    READ_ALERTS.with(|read| *read.borrow_mut() = stable.read_alerts);
+}
+
+// 告警严重程度
+fn severity_of(kind: &AlertKind) -> AlertSeverity {
+    match kind {
+        AlertKind::BurnRateAnomaly{..} => AlertSeverity::Warning,
+        AlertKind::CanisterStopped{..} => AlertSeverity::Critical,
+        AlertKind::ModuleHashChanged{..} => AlertSeverity::Info,
+        AlertKind::ControllersChanged{..} => AlertSeverity::Warning,
+        AlertKind::ThresholdCrossed{ action: ThresholdAction::PageAndTopUpToMax, .. } => AlertSeverity::Critical,
+        AlertKind::ThresholdCrossed{ level, .. } => match level {
+            ThresholdLevel::Info => AlertSeverity::Info,
+            ThresholdLevel::Warning => AlertSeverity::Warning,
+            ThresholdLevel::Critical => AlertSeverity::Critical,
+        },
+        AlertKind::PollFailed{..} => AlertSeverity::Warning,
+        AlertKind::TopUpSucceeded{..} => AlertSeverity::Info,
+        AlertKind::TopUpFailed{..} => AlertSeverity::Warning,
+        AlertKind::SubscriberUnsubscribed{..} => AlertSeverity::Warning,
+        AlertKind::MonitoringStale{..} => AlertSeverity::Critical,
+    }
+}
+
+// 只做记录的事件 创建时即为已解决
+fn is_informational(kind: &AlertKind) -> bool {
+    matches!(kind, AlertKind::ModuleHashChanged{..} | AlertKind::TopUpSucceeded{..})
+}
+
+// 为组织记录一条告警 返回告警 id
+pub fn raise_alert(organize_name: OrganizeName, canister_id: Principal, kind: AlertKind) -> u64 {
+    let id = NEXT_ALERT_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    let now = ic_cdk::api::time();
+    let informational = is_informational(&kind);
+    ALERTS.with(|alerts|{
+        let mut alerts = alerts.borrow_mut();
+        let alert = AlertEvent{
+            id,
+            organize_name: organize_name.clone(),
+            canister_id,
+            severity: severity_of(&kind),
+            kind,
+            state: if informational { AlertState::Resolved } else { AlertState::Open },
+            time: now,
+            acknowledged_by: None,
+            acknowledged_at: None,
+            resolved_at: if informational { Some(now) } else { None },
+        };
+        webhooks::enqueue_alert(&alert);
+        alerts.insert(id, alert);
+        // 超出保留数量时删除该组织最早的告警 [未确认的异常告警可能暂停着自动充值, 保留到被确认]
+        let organize_alerts = alerts.values().filter(|alert| alert.organize_name == organize_name).count();
+        if organize_alerts > MAX_ALERTS_PER_ORGANIZE {
+            let expired: BTreeSet<u64> = alerts.values()
+                .filter(|alert| alert.organize_name == organize_name && alert.id != id && !pauses_top_ups(alert))
+                .map(|alert| alert.id)
+                .take(organize_alerts - MAX_ALERTS_PER_ORGANIZE)
+                .collect();
+            for alert_id in expired.iter() {
+                alerts.remove(alert_id);
+            }
+            READ_ALERTS.with(|read| read.borrow_mut().retain(|(_, alert_id)| !expired.contains(alert_id)));
+        }
+    });
+    id
+}
+
+// 未确认的异常告警 [确认或解决前暂停着自动充值]
+fn pauses_top_ups(alert: &AlertEvent) -> bool {
+    alert.state == AlertState::Open && matches!(alert.kind, AlertKind::BurnRateAnomaly{..})
+}
+
+// 组织解散时清除告警和已读记录 并解除这些告警造成的充值暂停
+pub fn remove_organize(organize_name: &OrganizeName) {
+    let removed: BTreeSet<u64> = ALERTS.with(|alerts|{
+        let mut alerts = alerts.borrow_mut();
+        let removed: BTreeSet<u64> = alerts.values()
+            .filter(|alert| alert.organize_name == *organize_name)
+            .map(|alert| alert.id)
+            .collect();
+        alerts.retain(|alert_id, _| !removed.contains(alert_id));
+        removed
+    });
+    READ_ALERTS.with(|read| read.borrow_mut().retain(|(_, alert_id)| !removed.contains(alert_id)));
+    for alert_id in removed {
+        anomaly::release_top_up_pause(alert_id);
+    }
+}
+
+// 组织移除该罐时解决该组织下这个罐未解决的告警 并解除这些告警造成的充值暂停
+pub fn remove_from_organize(canister_id: &Principal, organize_name: &OrganizeName) {
+    let now = ic_cdk::api::time();
+    let resolved: Vec<u64> = ALERTS.with(|alerts|{
+        let mut resolved = Vec::new();
+        for alert in alerts.borrow_mut().values_mut() {
+            if alert.organize_name == *organize_name && alert.canister_id == *canister_id && alert.state != AlertState::Resolved {
+                alert.state = AlertState::Resolved;
+                alert.resolved_at = Some(now);
+                resolved.push(alert.id);
+            }
+        }
+        resolved
+    });
+    for alert_id in resolved {
+        anomaly::release_top_up_pause(alert_id);
+    }
+}
+
+// 为收录该罐的所有组织记录告警
+pub fn raise_canister_alert(canister_id: Principal, kind: AlertKind) -> Vec<u64> {
+    organizes_of(&canister_id).into_iter()
+        .map(|organize_name| raise_alert(organize_name, canister_id, kind.clone()))
+        .collect()
+}
+
+// 组织下该罐是否有满足条件的未解决告警
+pub fn has_unresolved(organize_name: &OrganizeName, canister_id: &Principal, matches: fn(&AlertKind) -> bool) -> bool {
+    ALERTS.with(|alerts|{
+        alerts.borrow().values().any(|alert| {
+            alert.organize_name == *organize_name
+                && alert.canister_id == *canister_id
+                && alert.state != AlertState::Resolved
+                && matches(&alert.kind)
+        })
+    })
+}
+
+// 问题消失后自动解决满足条件的告警 [organize_name 为 None 时为所有组织]
+pub fn resolve_matching(organize_name: Option<&OrganizeName>, canister_id: &Principal, matches: fn(&AlertKind) -> bool) {
+    let now = ic_cdk::api::time();
+    ALERTS.with(|alerts|{
+        for alert in alerts.borrow_mut().values_mut() {
+            if alert.canister_id == *canister_id
+                && organize_name.is_none_or(|organize_name| alert.organize_name == *organize_name)
+                && alert.state != AlertState::Resolved
+                && matches(&alert.kind) {
+                alert.state = AlertState::Resolved;
+                alert.resolved_at = Some(now);
+            }
+        }
+    })
+}
+
+fn organizes_of(canister_id: &Principal) -> Vec<OrganizeName> {
+    CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        match canisters_to_organizes.borrow().get(canister_id) {
+            Some(organizes) => organizes.iter().map(|(_, organize_name)| organize_name.clone()).collect(),
+            None => Vec::new(),
+        }
+    })
+}
+
+// 调用者在组织中的告警权限 所有者和管理员视为 Operator
+fn alert_role(caller: &Principal, organize_name: &OrganizeName) -> Option<MemberRole> {
+    if is_admin(caller) || is_owner(caller, organize_name) {
+        return Some(MemberRole::Operator);
+    }
+    ORGANIZES_TO_MEMBERS.with(|organizes_to_members|{
+        organizes_to_members.borrow().get(organize_name)
+            .and_then(|members| members.borrow().get(caller).map(|member| member.borrow().role.get()))
+    })
+}
+
+// Viewer 只能看到 Warning 及以上的告警
+fn visible_to(role: MemberRole, alert: &AlertEvent) -> bool {
+    role == MemberRole::Operator || alert.severity >= AlertSeverity::Warning
+}
+
+// 调用者作为所有者或成员所在的所有组织
+fn caller_organizes(caller: &Principal) -> BTreeMap<OrganizeName, MemberRole> {
+    let mut organizes = BTreeMap::new();
+    ORGANIZES_TO_MEMBERS.with(|organizes_to_members|{
+        for (organize_name, members) in organizes_to_members.borrow().iter() {
+            if let Some(member) = members.borrow().get(caller) {
+                organizes.insert(organize_name.clone(), member.borrow().role.get());
+            }
+        }
+    });
+    ORGANIZES_TO_OWNER.with(|organizes_to_owner|{
+        for (organize_name, owner) in organizes_to_owner.borrow().iter() {
+            if *owner.borrow() == *caller {
+                organizes.insert(organize_name.clone(), MemberRole::Operator);
+            }
+        }
+    });
+    organizes
+}
+
+// 查询组织的告警 [新的在前]
+#[query]
+pub fn organization_alerts(organize_name: OrganizeName) -> Result<Vec<AlertEvent>, String> {
+    let role = match alert_role(&ic_cdk::api::caller(), &organize_name) {
+        Some(role) => role,
+        None => return Err(String::from("No permission to view this organization")),  // 无权查看该组织
+    };
+    Ok(ALERTS.with(|alerts|{
+        alerts.borrow().values().rev()
+            .filter(|alert| alert.organize_name == organize_name && visible_to(role, alert))
+            .cloned()
+            .collect()
+    }))
+}
+
+// 我的告警收件箱 所有所在组织的告警 [新的在前, 分页]
+#[query]
+pub fn my_alert_inbox(offset: u64, limit: u64, unread_only: bool) -> AlertInboxPage {
+    let caller = ic_cdk::api::caller();
+    let organizes = caller_organizes(&caller);
+    let visible: Vec<AlertInboxItem> = ALERTS.with(|alerts|{
+        READ_ALERTS.with(|read|{
+            let read = read.borrow();
+            alerts.borrow().values().rev()
+                .filter(|alert| organizes.get(&alert.organize_name).is_some_and(|role| visible_to(*role, alert)))
+                .map(|alert| AlertInboxItem{
+                    read: read.contains(&(caller, alert.id)),
+                    alert: alert.clone(),
+                })
+                .collect()
+        })
+    });
+    let unread = visible.iter().filter(|item| !item.read).count() as u64;
+    let filtered: Vec<AlertInboxItem> = visible.into_iter().filter(|item| !unread_only || !item.read).collect();
+    AlertInboxPage{
+        total: filtered.len() as u64,
+        unread,
+        items: filtered.into_iter().skip(offset as usize).take(limit.min(MAX_PAGE_SIZE) as usize).collect(),
+    }
+}
+
+// 标记告警为已读
+#[update]
+pub fn mark_alerts_read(alert_ids: Vec<u64>) -> u64 {
+    let caller = ic_cdk::api::caller();
+    let organizes = caller_organizes(&caller);
+    let readable: Vec<u64> = ALERTS.with(|alerts|{
+        let alerts = alerts.borrow();
+        alert_ids.into_iter()
+            .filter(|alert_id| alerts.get(alert_id).is_some_and(|alert| organizes.contains_key(&alert.organize_name)))
+            .collect()
+    });
+    READ_ALERTS.with(|read|{
+        let mut read = read.borrow_mut();
+        readable.iter().filter(|alert_id| read.insert((caller, **alert_id))).count() as u64
+    })
+}
+
+// 标记所有可见告警为已读
+#[update]
+pub fn mark_all_alerts_read() -> u64 {
+    let caller = ic_cdk::api::caller();
+    let organizes = caller_organizes(&caller);
+    let visible: Vec<u64> = ALERTS.with(|alerts|{
+        alerts.borrow().values()
+            .filter(|alert| organizes.get(&alert.organize_name).is_some_and(|role| visible_to(*role, alert)))
+            .map(|alert| alert.id)
+            .collect()
+    });
+    READ_ALERTS.with(|read|{
+        let mut read = read.borrow_mut();
+        visible.into_iter().filter(|alert_id| read.insert((caller, *alert_id))).count() as u64
+    })
+}
+
+// 确认告警 确认后恢复因该告警暂停的自动充值 [Viewer 不可确认]
+#[update]
+pub fn acknowledge_alert(alert_id: u64) -> String {
+    let caller = ic_cdk::api::caller();
+    match update_alert_state(&caller, alert_id, AlertState::Acknowledged) {
+        Ok(()) => {
+            anomaly::release_top_up_pause(alert_id);
+            String::from("alert acknowledged")  // 告警已确认
+        },
+        Err(err) => err,
+    }
+}
+
+// 解决告警 [Viewer 不可解决]
+#[update]
+pub fn resolve_alert(alert_id: u64) -> String {
+    let caller = ic_cdk::api::caller();
+    match update_alert_state(&caller, alert_id, AlertState::Resolved) {
+        Ok(()) => {
+            anomaly::release_top_up_pause(alert_id);
+            String::from("alert resolved")  // 告警已解决
+        },
+        Err(err) => err,
+    }
+}
+
+fn update_alert_state(caller: &Principal, alert_id: u64, state: AlertState) -> Result<(), String> {
+    let organize_name = match ALERTS.with(|alerts| alerts.borrow().get(&alert_id).map(|alert| alert.organize_name.clone())) {
+        Some(organize_name) => organize_name,
+        None => return Err(String::from("alert does not exist")),  // 告警不存在
+    };
+    if alert_role(caller, &organize_name) != Some(MemberRole::Operator) {
+        return Err(String::from("No permission to change this alert"));  // 无权修改该告警
+    }
+    let now = ic_cdk::api::time();
+    ALERTS.with(|alerts|{
+        if let Some(alert) = alerts.borrow_mut().get_mut(&alert_id) {
+            if alert.acknowledged_by.is_none() {
+                alert.acknowledged_by = Some(*caller);
+                alert.acknowledged_at = Some(now);
+            }
+            if state == AlertState::Resolved {
+                alert.resolved_at = Some(now);
+            }
+            // 已解决的告警不再回到已确认
+            if alert.state != AlertState::Resolved {
+                alert.state = state;
+            }
+        }
+    });
+    Ok(())
+}
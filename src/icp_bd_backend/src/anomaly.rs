@@ -0,0 +1,226 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+
+use crate::alerts;
+use crate::common::types::{AlertKind, AnomalyConfig, CyclesSample, OrganizeName};
+use crate::history::{self, NANOS_PER_DAY};
+use crate::{is_admin, is_owner, is_owner_or_member, CANISTERS_TO_ORGANIZES};
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            baseline_window: 24,
+            min_baseline_samples: 6,
+            sensitivity: 10.0,
+            pause_top_ups: true,
+        }
+    }
+}
+
+thread_local!{
+    static ANOMALY_CONFIGS:RefCell<BTreeMap<OrganizeName, AnomalyConfig>> = RefCell::default();  // 组织的异常检测配置
+    static ACTIVE_ANOMALIES:RefCell<BTreeSet<(Principal, OrganizeName)>> = RefCell::default();  // 正在异常中的 (罐, 组织) 异常结束前不重复告警
+    static TOP_UP_PAUSES:RefCell<BTreeMap<Principal, BTreeSet<u64>>> = RefCell::default();  // 罐 -> 暂停自动充值的告警 id
+}
+
+// 升级时保存的异常检测状态
+#[derive(CandidType, Deserialize, Default)]
+pub struct AnomalyStable {
+    configs: BTreeMap<OrganizeName, AnomalyConfig>,
+    active: BTreeSet<(Principal, OrganizeName)>,
+    pauses: BTreeMap<Principal, BTreeSet<u64>>,
+}
+
+pub fn snapshot() -> AnomalyStable {
+    AnomalyStable {
+        configs: ANOMALY_CONFIGS.with(|m| m.borrow().clone()),
+        active: ACTIVE_ANOMALIES.with(|m| m.borrow().clone()),
+        pauses: TOP_UP_PAUSES.with(|m| m.borrow().clone()),
+    }
+}
+
+pub fn restore(stable: AnomalyStable) {
+    ANOMALY_CONFIGS.with(|m| *m.borrow_mut() = stable.configs);
+    ACTIVE_ANOMALIES.with(|m| *m.borrow_mut() = stable.active);
+    TOP_UP_PAUSES.with(|m| *m.borrow_mut() = stable.pauses);
+}
+
+pub fn anomaly_config(organize_name: &OrganizeName) -> AnomalyConfig {
+    ANOMALY_CONFIGS.with(|configs| configs.borrow().get(organize_name).cloned().unwrap_or_default())
+}
+
+// 相邻样本区间的消耗速率 cycles/天 [按时间先后], 余额上升的区间视为充值跳过
+fn interval_burn_rates(samples: &[CyclesSample]) -> Vec<u64> {
+    samples.windows(2)
+        .filter(|pair| pair[1].cycles <= pair[0].cycles && pair[1].timestamp > pair[0].timestamp)
+        .map(|pair| {
+            let burned = (pair[0].cycles - pair[1].cycles) as u128;
+            let elapsed = (pair[1].timestamp - pair[0].timestamp) as u128;
+            (burned * NANOS_PER_DAY as u128 / elapsed).min(u64::MAX as u128) as u64
+        })
+        .collect()
+}
+
+// 最近区间的消耗速率与之前区间的平均速率 (基线) 比较
+// 返回 Some((最近速率, 基线)) 表示异常
+fn detect(rates: &[u64], config: &AnomalyConfig) -> Option<(u64, u64)> {
+    let (latest, previous) = rates.split_last()?;
+    let baseline_rates: Vec<u64> = previous.iter().rev().take(config.baseline_window as usize).cloned().collect();
+    if (baseline_rates.len() as u64) < config.min_baseline_samples.max(1) {
+        return None;
+    }
+    let baseline = (baseline_rates.iter().map(|rate| *rate as u128).sum::<u128>() / baseline_rates.len() as u128) as u64;
+    if baseline > 0 && *latest as f64 > baseline as f64 * config.sensitivity {
+        Some((*latest, baseline))
+    } else {
+        None
+    }
+}
+
+// 轮训记录样本后 为收录该罐的每个组织按照各自的配置检测消耗异常
+pub fn check_canister(canister_id: Principal) {
+    let organizes: Vec<OrganizeName> = CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        match canisters_to_organizes.borrow().get(&canister_id) {
+            Some(organizes) => organizes.iter().map(|(_, organize_name)| organize_name.clone()).collect(),
+            None => Vec::new(),
+        }
+    });
+    for organize_name in organizes {
+        let config = anomaly_config(&organize_name);
+        let samples: Vec<CyclesSample> = history::with_history(&canister_id, |history|{
+            match history {
+                Some(history) => {
+                    let mut samples: Vec<CyclesSample> = history.iter().rev().take(config.baseline_window as usize + 2).cloned().collect();
+                    samples.reverse();
+                    samples
+                },
+                None => Vec::new(),
+            }
+        });
+        let key = (canister_id, organize_name.clone());
+        match detect(&interval_burn_rates(&samples), &config) {
+            Some((burn_rate_per_day, baseline_per_day)) => {
+                let is_new = ACTIVE_ANOMALIES.with(|active| active.borrow_mut().insert(key));
+                if !is_new {
+                    continue;
+                }
+                let alert_id = alerts::raise_alert(
+                    organize_name,
+                    canister_id,
+                    AlertKind::BurnRateAnomaly{ burn_rate_per_day, baseline_per_day },
+                );
+                if config.pause_top_ups {
+                    TOP_UP_PAUSES.with(|pauses| pauses.borrow_mut().entry(canister_id).or_default().insert(alert_id));
+                }
+            },
+            None => {
+                ACTIVE_ANOMALIES.with(|active| active.borrow_mut().remove(&key));
+            },
+        }
+    }
+}
+
+// 该罐的自动充值是否被未确认的异常告警暂停
+pub fn top_ups_paused(canister_id: &Principal) -> bool {
+    TOP_UP_PAUSES.with(|pauses| pauses.borrow().get(canister_id).is_some_and(|alert_ids| !alert_ids.is_empty()))
+}
+
+// 告警确认后解除该告警造成的暂停
+pub fn release_top_up_pause(alert_id: u64) {
+    TOP_UP_PAUSES.with(|pauses|{
+        let mut pauses = pauses.borrow_mut();
+        for alert_ids in pauses.values_mut() {
+            alert_ids.remove(&alert_id);
+        }
+        pauses.retain(|_, alert_ids| !alert_ids.is_empty());
+    })
+}
+
+// 罐不再被任何组织收录时清除状态
+pub fn remove_canister(canister_id: &Principal) {
+    ACTIVE_ANOMALIES.with(|active| active.borrow_mut().retain(|(id, _)| id != canister_id));
+    TOP_UP_PAUSES.with(|pauses| pauses.borrow_mut().remove(canister_id));
+}
+
+// 组织移除该罐时清除该组织的异常状态 [暂停由告警解决时解除]
+pub fn remove_from_organize(canister_id: &Principal, organize_name: &OrganizeName) {
+    ACTIVE_ANOMALIES.with(|active| active.borrow_mut().remove(&(*canister_id, organize_name.clone())));
+}
+
+// 组织解散时清除配置和异常状态 [暂停由告警清除时解除]
+pub fn remove_organize(organize_name: &OrganizeName) {
+    ANOMALY_CONFIGS.with(|configs| configs.borrow_mut().remove(organize_name));
+    ACTIVE_ANOMALIES.with(|active| active.borrow_mut().retain(|(_, name)| name != organize_name));
+}
+
+// 查询组织的异常检测配置
+#[query]
+pub fn get_anomaly_config(organize_name: OrganizeName) -> Result<AnomalyConfig, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_admin(&caller) && !is_owner_or_member(&caller, &organize_name) {
+        return Err(String::from("No permission to view this organization"));  // 无权查看该组织
+    }
+    Ok(anomaly_config(&organize_name))
+}
+
+// 组织所有人 修改异常检测配置
+#[update]
+pub fn set_anomaly_config(organize_name: OrganizeName, config: AnomalyConfig) -> String {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return String::from("Non-organization owners cannot modify anomaly detection");  // 非组织所有者不可修改异常检测配置
+    }
+    if config.sensitivity.is_nan() || config.sensitivity <= 1.0 {
+        return String::from("sensitivity must be greater than 1");  // 灵敏度必须大于 1
+    }
+    ANOMALY_CONFIGS.with(|configs| configs.borrow_mut().insert(organize_name, config));
+    String::from("anomaly detection updated successfully")  // 异常检测配置更新成功
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(baseline_window: u64, min_baseline_samples: u64, sensitivity: f64) -> AnomalyConfig {
+        AnomalyConfig{ baseline_window, min_baseline_samples, sensitivity, pause_top_ups: true }
+    }
+
+    #[test]
+    fn interval_rates_skip_top_ups() {
+        let samples: Vec<CyclesSample> = [(0, 1_000), (1, 900), (2, 5_000), (4, 4_800)].iter()
+            .map(|(day, cycles)| CyclesSample{ timestamp: day * NANOS_PER_DAY, cycles: *cycles, memory_size: 0 })
+            .collect();
+        assert_eq!(interval_burn_rates(&samples), vec![100, 100]);
+    }
+
+    #[test]
+    fn baseline_uses_only_the_window() {
+        // 窗口为 3 时只取最近的 3 个区间 [30, 30, 30], 更早的 1_000 不计入
+        let rates = [1_000, 30, 30, 30, 301];
+        assert_eq!(detect(&rates, &config(3, 1, 10.0)), Some((301, 30)));
+        // 窗口为 4 时基线被拉高 不再异常
+        assert_eq!(detect(&rates, &config(4, 1, 10.0)), None);
+    }
+
+    #[test]
+    fn baseline_needs_enough_samples() {
+        let rates = [10, 10, 10, 1_000];
+        assert_eq!(detect(&rates, &config(24, 4, 10.0)), None);
+        assert_eq!(detect(&rates, &config(24, 3, 10.0)), Some((1_000, 10)));
+        // 至少需要一个基线区间
+        assert_eq!(detect(&[1_000], &config(24, 0, 10.0)), None);
+        assert_eq!(detect(&[], &config(24, 0, 10.0)), None);
+    }
+
+    #[test]
+    fn latest_rate_must_exceed_the_sensitivity() {
+        let rates = [10, 10, 10];
+        assert_eq!(detect(&[10, 10, 100], &config(24, 2, 10.0)), None);
+        assert_eq!(detect(&[10, 10, 101], &config(24, 2, 10.0)), Some((101, 10)));
+        assert_eq!(detect(&rates, &config(24, 2, 0.5)), Some((10, 10)));
+        // 基线为 0 时不判断
+        assert_eq!(detect(&[0, 0, 1_000], &config(24, 2, 10.0)), None);
+    }
+}
@@ -0,0 +1,173 @@
+use async_trait::async_trait;
+use ic_cdk::api::call::CallResult;
+use ic_cdk::call;
+use ic_cdk::export::candid::{CandidType, Deserialize, Int, Nat, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IcrcAccount {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl IcrcAccount {
+    pub fn of(owner: Principal) -> Self {
+        IcrcAccount { owner, subaccount: None }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum IcrcMetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct IcrcTransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: IcrcAccount,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum IcrcTransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+pub type IcrcTransferResult = Result<Nat, IcrcTransferError>;
+
+#[derive(CandidType, Deserialize)]
+pub struct IcrcApproveArgs {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub spender: IcrcAccount,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum IcrcApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+pub type IcrcApproveResult = Result<Nat, IcrcApproveError>;
+
+#[derive(CandidType, Deserialize)]
+pub struct IcrcTransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: IcrcAccount,
+    pub to: IcrcAccount,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum IcrcTransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+pub type IcrcTransferFromResult = Result<Nat, IcrcTransferFromError>;
+
+#[derive(CandidType, Deserialize)]
+pub struct IcrcAllowanceArgs {
+    pub account: IcrcAccount,
+    pub spender: IcrcAccount,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct IcrcAllowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[async_trait]
+pub trait Icrc {
+    // -------------- ICRC-1 ----------------
+    async fn icrc1_name(&self) -> CallResult<(String,)>;
+    async fn icrc1_symbol(&self) -> CallResult<(String,)>;
+    async fn icrc1_decimals(&self) -> CallResult<(u8,)>;
+    async fn icrc1_fee(&self) -> CallResult<(Nat,)>;
+    async fn icrc1_metadata(&self) -> CallResult<(Vec<(String, IcrcMetadataValue)>,)>;
+    async fn icrc1_balance_of(&self, account: IcrcAccount) -> CallResult<(Nat,)>;
+    async fn icrc1_transfer(&self, arg: IcrcTransferArg) -> CallResult<(IcrcTransferResult,)>;
+
+    // -------------- ICRC-2 ----------------
+    async fn icrc2_approve(&self, args: IcrcApproveArgs) -> CallResult<(IcrcApproveResult,)>;
+    async fn icrc2_transfer_from(&self, args: IcrcTransferFromArgs) -> CallResult<(IcrcTransferFromResult,)>;
+    async fn icrc2_allowance(&self, args: IcrcAllowanceArgs) -> CallResult<(IcrcAllowance,)>;
+}
+
+#[async_trait]
+impl Icrc for Principal {
+    async fn icrc1_name(&self) -> CallResult<(String,)> {
+        call(*self, "icrc1_name", ()).await
+    }
+
+    async fn icrc1_symbol(&self) -> CallResult<(String,)> {
+        call(*self, "icrc1_symbol", ()).await
+    }
+
+    async fn icrc1_decimals(&self) -> CallResult<(u8,)> {
+        call(*self, "icrc1_decimals", ()).await
+    }
+
+    async fn icrc1_fee(&self) -> CallResult<(Nat,)> {
+        call(*self, "icrc1_fee", ()).await
+    }
+
+    async fn icrc1_metadata(&self) -> CallResult<(Vec<(String, IcrcMetadataValue)>,)> {
+        call(*self, "icrc1_metadata", ()).await
+    }
+
+    async fn icrc1_balance_of(&self, account: IcrcAccount) -> CallResult<(Nat,)> {
+        call(*self, "icrc1_balance_of", (account,)).await
+    }
+
+    async fn icrc1_transfer(&self, arg: IcrcTransferArg) -> CallResult<(IcrcTransferResult,)> {
+        call(*self, "icrc1_transfer", (arg,)).await
+    }
+
+    async fn icrc2_approve(&self, args: IcrcApproveArgs) -> CallResult<(IcrcApproveResult,)> {
+        call(*self, "icrc2_approve", (args,)).await
+    }
+
+    async fn icrc2_transfer_from(&self, args: IcrcTransferFromArgs) -> CallResult<(IcrcTransferFromResult,)> {
+        call(*self, "icrc2_transfer_from", (args,)).await
+    }
+
+    async fn icrc2_allowance(&self, args: IcrcAllowanceArgs) -> CallResult<(IcrcAllowance,)> {
+        call(*self, "icrc2_allowance", (args,)).await
+    }
+}
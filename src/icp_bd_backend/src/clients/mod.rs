@@ -1,4 +1,5 @@
 pub mod dip20;
+pub mod icrc;
 pub mod sonic;
 pub mod xtc;
 pub mod nns_cycles_minting;
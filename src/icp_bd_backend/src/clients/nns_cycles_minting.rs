@@ -22,9 +22,28 @@ pub struct IcpXdrConversionRate {
 }
 
 
+#[derive(CandidType, Deserialize)]
+pub struct NotifyTopUpArg {
+  pub block_index: u64,
+  pub canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum NotifyError {
+  Refunded { reason: String, block_index: Option<u64> },
+  InvalidTransaction(String),
+  TransactionTooOld(u64),
+  Processing,
+  Other { error_code: u64, error_message: String },
+}
+
+pub type NotifyTopUpResult = Result<Nat, NotifyError>;
+
 #[async_trait]
 pub trait NNS_Cycle_Minting {
     async fn get_icp_xdr_conversion_rate(&self) -> CallResult<(IcpXdrConversionRateCertifiedResponse,)>;
+    // 转到 cycles minting 罐的充值账户后通知 为 canister_id 充值 [同一区块重复通知返回相同结果]
+    async fn notify_top_up(&self, arg: NotifyTopUpArg) -> CallResult<(NotifyTopUpResult,)>;
 }
 
 #[async_trait]
@@ -32,4 +51,8 @@ impl NNS_Cycle_Minting for Principal {
     async fn get_icp_xdr_conversion_rate(&self,) -> CallResult<(IcpXdrConversionRateCertifiedResponse,)> {
         ic_cdk::call(*self, "get_icp_xdr_conversion_rate", ()).await
       }
+
+    async fn notify_top_up(&self, arg: NotifyTopUpArg) -> CallResult<(NotifyTopUpResult,)> {
+        ic_cdk::call(*self, "notify_top_up", (arg,)).await
+    }
 }
@@ -18,11 +18,28 @@ pub enum WICPError {
     AmountTooSmall,
 }
 
+impl WICPError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            WICPError::InsufficientAllowance => "insufficient WICP allowance",
+            WICPError::InsufficientBalance => "insufficient WICP balance",
+            WICPError::ErrorOperationStyle => "the ledger block is not a transfer to the WICP account",
+            WICPError::Unauthorized => "the ledger block was not sent by the caller",
+            WICPError::LedgerTrap => "the ICP ledger call failed",
+            WICPError::ErrorTo => "invalid destination account",
+            WICPError::Other => "WICP rejected the request",
+            WICPError::BlockUsed => "the ledger block has already been used to mint",
+            WICPError::AmountTooSmall => "amount is too small to cover the ICP fee",
+        }
+    }
+}
+
 pub type WICPResult = Result<Nat, WICPError>;
 
 #[async_trait]
 pub trait WICP {
     async fn mint(&self, to: Option<Subaccount>, block_index: u64) -> CallResult<(WICPResult,)>;
+    async fn withdraw(&self, value: u64, to: String) -> CallResult<(WICPResult,)>;
 }
 
 #[async_trait]
@@ -31,4 +48,9 @@ impl WICP for Principal {
         call(*self, "mint", (to,  block_index)).await
     }
 
+    // 销毁 WICP 并将 ICP [扣除转账手续费] 转到 to 账户 [十六进制账户 id]
+    async fn withdraw(&self, value: u64, to: String) -> CallResult<(WICPResult,)> {
+        call(*self, "withdraw", (value, to)).await
+    }
+
 }
@@ -1,10 +1,21 @@
-use crate::get_state;
+use crate::{is_admin, is_controller};
 use ic_cdk::caller;
 
-pub fn controller_guard() -> Result<(), String> {
-    // if caller() != get_state().controller {
-    //     return Err(String::from("Access denied"));
-    // }
+// 管理员守卫 只有管理员集合中的用户可以调用
+pub fn admin_guard() -> Result<(), String> {
+    if !is_admin(&caller()) {
+        return Err(String::from("Access denied"));
+    }
+
+    Ok(())
+}
+
+// 管理员或控制者守卫 用于维护管理员集合, 使控制者在管理员全部失效时仍能恢复
+pub fn admin_or_controller_guard() -> Result<(), String> {
+    let caller = caller();
+    if !is_admin(&caller) && !is_controller(&caller) {
+        return Err(String::from("Access denied"));
+    }
 
     Ok(())
 }
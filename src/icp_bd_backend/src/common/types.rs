@@ -15,6 +15,16 @@ pub type OrganizeOwner = Principal;
 pub struct MemberInfo {
     pub nickname: String,  // 别称
     pub instime: Cell<u64>,  // 插入时间 此为管理员插入
+    #[serde(default)]
+    pub role: Cell<MemberRole>,  // 成员角色
+}
+
+// 成员角色
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MemberRole {
+    Viewer,  // 只能查看 Warning 及以上的告警
+    #[default]
+    Operator,  // 可查看全部告警并确认/解决
 }
 
 // 罐信息
@@ -24,9 +34,91 @@ pub struct  CanisterInfo {
     pub instime:Cell<u64>,  // 罐插入时间
     pub updtime:Cell<u64>,  // 上次更新Cycles时间
     pub cycles_balance: Cell<u64>,  // 罐余额
-    pub time_interval: Cell<u64>,  // 轮训时间间隔
+    pub time_interval: Cell<u64>,  // 轮训时间间隔 秒
     pub cycles_minimum: Cell<u64>,  // 最低Cycles
     pub cycles_highest:Cell<u64>,  // 最高Cycles
+    #[serde(default)]
+    pub status: Option<CanisterStatusInfo>,  // 罐完整状态 [按罐统一存储, 查询时填充]
+    #[serde(default)]
+    pub minimum_days_above_freeze: Cell<Option<u64>>,  // 以 "高于冻结点 N 天" 表示的最低Cycles, 设置后每次轮训重新换算 cycles_minimum
+    #[serde(default)]
+    pub thresholds: Option<Vec<CyclesThreshold>>,  // 分级阈值 [None 时使用组织默认]
+    #[serde(default)]
+    pub degraded: Option<DegradedInfo>,  // 监控失效信息 [按罐统一存储, 查询时填充]
+}
+
+// 读取罐状态失败的原因
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PollFailureCause {
+    NotController,  // 黑洞不是该罐的控制者
+    CanisterDeleted,  // 罐已删除
+    BlackHoleUnavailable,  // 黑洞罐不可用
+    NoResponse,  // 读取一直没有返回
+    Other,
+}
+
+// 最近一次成功后的读取失败
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PollFailure {
+    pub cause: PollFailureCause,
+    pub error: String,
+    pub time: u64,  // 最近一次失败时间
+    pub consecutive_failures: u32,  // 连续失败次数
+}
+
+// 监控失效信息 [距上次成功读取超过 stale_after_intervals 个轮训间隔]
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DegradedInfo {
+    pub cause: PollFailureCause,
+    pub last_error: Option<String>,  // 最近一次失败原因
+    pub last_success: u64,  // 上次成功读取时间
+    pub since: u64,  // 标记为失效的时间
+}
+
+// 阈值级别 [由轻到重]
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThresholdLevel {
+    Info,
+    Warning,
+    Critical,
+}
+
+// 低于阈值时的动作
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ThresholdAction {
+    NotifyOnly,  // 只通知
+    NotifyAndTopUp,  // 通知并充值到该组织设置的最高Cycles [从本罐扣除, 需显式设置]
+    PageAndTopUpToMax,  // 紧急通知并充值到所有组织中最高的最高Cycles
+}
+
+// 分级阈值
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CyclesThreshold {
+    pub level: ThresholdLevel,
+    pub cycles: u64,  // 余额低于该值时触发
+    pub action: ThresholdAction,
+}
+
+// 罐运行状态
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum CanisterRunningStatus {
+    Running,
+    Stopping,
+    Stopped,
+}
+
+// 罐完整状态 [black hole canister_status]
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CanisterStatusInfo {
+    pub status: CanisterRunningStatus,  // 运行状态
+    pub memory_size: u64,  // 内存占用
+    pub cycles: u64,  // 余额
+    pub module_hash: Option<Vec<u8>>,  // 代码 hash
+    pub controllers: Vec<Principal>,  // 控制者 [已排序]
+    pub freezing_threshold: u64,  // 冻结阈值 秒
+    pub memory_allocation: u64,
+    pub compute_allocation: u64,
+    pub updtime: u64,  // 读取时间
 }
 
 // 公共罐信息
@@ -34,7 +126,7 @@ pub struct  CanisterInfo {
 pub struct  PubilcCanisterInfo {
     pub updtime:Cell<u64>,  // 公共上次更新Cycles时间
     pub cycles_balance: Cell<u64>,  // 罐余额
-    pub time_interval: Cell<u64>,  // 轮训时间间隔
+    pub time_interval: Cell<u64>,  // 轮训时间间隔 秒
     pub cycles_minimum: Cell<u64>,  // 公共最低Cycles
     pub cycles_highest:Cell<u64>,  // 公共最高Cycles
 }
@@ -46,6 +138,181 @@ pub struct UserRechargeICPRecordInfo {
     pub recharge_amount: u64,  // 充值金额
 }
 
+// 罐余额历史样本
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CyclesSample {
+    pub timestamp: u64,  // 采样时间
+    pub cycles: u64,  // 罐余额
+    pub memory_size: u64,  // 罐内存占用
+}
+
+// 罐余额历史分页
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CyclesHistoryPage {
+    pub samples: Vec<CyclesSample>,
+    pub total: u64,  // 时间范围内的样本总数
+}
+
+// 罐消耗预测
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CanisterForecast {
+    pub canister_id: Principal,
+    pub nickname: String,  // 罐别称
+    pub cycles_balance: u64,  // 最近一次读取的余额
+    pub burn_rate_per_day: Option<u64>,  // 消耗速率 cycles/天 [不含充值]
+    pub depletion_time: Option<u64>,  // 预计耗尽时间
+    pub minimum_crossing_time: Option<u64>,  // 预计低于最低Cycles的时间
+    pub freezing_point: Option<u64>,  // 冻结点 [空闲消耗 × 冻结阈值]
+    pub freeze_time: Option<u64>,  // 预计到达冻结点的时间
+    pub degraded: Option<DegradedInfo>,  // 监控失效时预测基于旧数据
+}
+
+// 告警类型
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AlertKind {
+    BurnRateAnomaly { burn_rate_per_day: u64, baseline_per_day: u64 },  // 消耗速率异常
+    CanisterStopped { status: CanisterRunningStatus },  // 罐停止运行
+    ModuleHashChanged { old_hash: Option<Vec<u8>>, new_hash: Option<Vec<u8>> },  // 罐代码变化 [升级]
+    ControllersChanged { old_controllers: Vec<Principal>, new_controllers: Vec<Principal> },  // 罐控制者变化
+    ThresholdCrossed { level: ThresholdLevel, cycles_threshold: u64, cycles_balance: u64, action: ThresholdAction },  // 余额低于分级阈值
+    PollFailed { error: String },  // 读取罐状态失败
+    TopUpSucceeded { amount: u64 },  // 自动充值成功
+    TopUpFailed { amount: u64, error: String },  // 自动充值失败
+    SubscriberUnsubscribed { callback_canister: Principal, callback_method: String, error: String },  // 通知连续被拒绝 已自动取消订阅 [已不再产生, 保留以解码历史告警]
+    MonitoringStale { cause: PollFailureCause, last_success: u64, last_error: Option<String> },  // 长时间没有成功读取 监控失效
+}
+
+// 告警严重程度 [由轻到重]
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum AlertSeverity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+// 告警状态
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum AlertState {
+    #[default]
+    Open,  // 未处理
+    Acknowledged,  // 已确认
+    Resolved,  // 已解决
+}
+
+// 告警事件
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AlertEvent {
+    pub id: u64,
+    pub organize_name: OrganizeName,
+    pub canister_id: Principal,
+    pub kind: AlertKind,
+    #[serde(default)]
+    pub severity: AlertSeverity,
+    #[serde(default)]
+    pub state: AlertState,
+    pub time: u64,  // 告警时间
+    #[serde(default)]
+    pub acknowledged_by: Option<Principal>,  // 确认人
+    pub acknowledged_at: Option<u64>,  // 确认时间
+    pub resolved_at: Option<u64>,  // 解决时间
+}
+
+// 收件箱中的告警
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AlertInboxItem {
+    pub alert: AlertEvent,
+    pub read: bool,  // 当前成员是否已读
+}
+
+// 收件箱分页
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AlertInboxPage {
+    pub items: Vec<AlertInboxItem>,
+    pub total: u64,  // 符合条件的告警总数
+    pub unread: u64,  // 未读告警数
+}
+
+// 阈值通知订阅 [罐低于阈值时调用回调罐]
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Subscription {
+    pub id: u64,
+    pub organize_name: OrganizeName,
+    pub canister_id: Principal,  // 监控的罐
+    pub callback_canister: Principal,  // 回调罐
+    pub callback_method: String,  // 回调方法 (ThresholdNotification) -> ()
+    pub total_failures: u64,  // 累计发出调用时同步失败的次数 [单向调用 无法得知回调罐是否拒绝]
+    pub last_error: Option<String>,  // 最近一次失败原因
+    pub last_delivered: Option<u64>,  // 最近一次发出通知的时间 [单向调用 不确认对方已处理]
+    pub instime: u64,
+}
+
+// 发送给回调罐的阈值通知
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ThresholdNotification {
+    pub organize_name: OrganizeName,
+    pub canister_id: Principal,
+    pub level: ThresholdLevel,
+    pub cycles_threshold: u64,
+    pub cycles_balance: u64,
+    pub action: ThresholdAction,
+    pub time: u64,
+}
+
+// webhook
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Webhook {
+    pub id: u64,
+    pub organize_name: OrganizeName,
+    pub url: String,
+    pub secret: String,  // 签名密钥 [HMAC-SHA256]
+    pub min_severity: AlertSeverity,  // 只投递该级别及以上的告警
+    pub instime: u64,
+}
+
+// webhook 查询结果 [不含密钥]
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WebhookInfo {
+    pub id: u64,
+    pub url: String,
+    pub min_severity: AlertSeverity,
+    pub instime: u64,
+}
+
+// webhook 投递状态
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookDeliveryStatus {
+    Pending,  // 等待投递或重试
+    Delivered,  // 已投递 [2xx]
+    Failed,  // 超过最多尝试次数或 webhook 已删除
+}
+
+// webhook 投递记录
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WebhookDelivery {
+    pub id: u64,
+    pub webhook_id: u64,
+    pub organize_name: OrganizeName,
+    pub alert_id: u64,
+    pub payload: String,  // 签名的 JSON 内容
+    pub status: WebhookDeliveryStatus,
+    pub attempts: u32,  // 已尝试次数
+    pub last_status_code: Option<u16>,  // 最近一次响应状态码
+    pub last_error: Option<String>,  // 最近一次失败原因
+    pub instime: u64,
+    pub next_attempt: u64,  // 下次尝试时间
+    pub updtime: u64,
+}
+
+// 消耗速率异常检测配置
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AnomalyConfig {
+    pub baseline_window: u64,  // 基线使用最近多少个轮训区间
+    pub min_baseline_samples: u64,  // 基线至少需要的区间数
+    pub sensitivity: f64,  // 最近区间的消耗速率超过基线的倍数视为异常
+    pub pause_top_ups: bool,  // 异常时暂停自动充值直到告警被确认
+}
+
 // 罐映射组织信息
 #[derive(CandidType, Deserialize, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct CanisterMappingOrganizationInfo {
@@ -56,12 +323,151 @@ pub struct CanisterMappingOrganizationInfo {
 
 
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub enum Order {
     Market(MarketOrder),
     Limit(LimitOrder),
 }
 
+// 订单状态
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderStatus {
+    Open,  // 等待达到目标价格
+    Executing,  // 执行中
+    Filled,  // 已成交
+    Cancelled,  // 已撤销
+    Expired,  // 已过期
+    Failed,  // 执行失败
+}
+
+// 订单记录
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrderRecord {
+    pub id: u64,
+    pub owner: Principal,
+    pub order: Order,
+    pub status: OrderStatus,
+    pub error: Option<String>,  // 执行失败原因
+    pub instime: u64,
+    pub expires_at: Option<u64>,  // 过期时间 [None 为不过期]
+    pub updtime: u64,
+    #[serde(default)]
+    pub slippage_bps: Option<u64>,  // 相对触发时价格允许的滑点 万分之 [None 为默认]
+    #[serde(default)]
+    pub execution_id: Option<u64>,  // 兑换执行记录
+}
+
+// 兑换执行步骤
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapStep {
+    TransferIn,  // 从用户转入 give 代币 [DIP20 transferFrom]
+    Approve,  // 授权 Sonic 转出
+    Deposit,  // 存入 Sonic
+    Swap,  // 兑换
+    Settle,  // 按余额差计算成交数量
+    WithdrawTake,  // 从 Sonic 取出兑换所得
+    TransferTake,  // 兑换所得转给用户
+    WithdrawGive,  // 从 Sonic 取出未用完的 give 代币
+    TransferGive,  // 未用完的 give 代币退还用户
+    Done,
+}
+
+// 步骤回执
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SwapStepReceipt {
+    pub step: SwapStep,
+    pub time: u64,
+    pub tx: Option<Nat>,  // 交易编号 [没有实际调用时为空]
+    pub error: Option<String>,  // 失败原因
+}
+
+// 兑换执行状态
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapExecutionStatus {
+    Running,
+    Completed,  // 已兑换并转给用户
+    Refunded,  // 未兑换 代币已退还
+    Failed,  // 停在某一步 可恢复
+}
+
+// 兑换执行记录 [记录每一步回执和各处持有的数量 失败后可从中断处恢复]
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SwapExecution {
+    pub id: u64,
+    pub order_id: u64,
+    pub owner: Principal,
+    pub market_order: MarketOrder,
+    #[serde(default)]
+    pub path: Vec<String>,  // 兑换路径 [Sonic 代币 id]
+    pub slippage_bps: u64,  // 允许的滑点 万分之
+    pub bound: Nat,  // GiveExact 时的最少得到数量, TakeExact 时的最多付出数量
+    pub deadline: u64,  // 兑换截止时间
+    pub amount_in: Nat,  // 从用户转入的数量
+    pub status: SwapExecutionStatus,
+    pub next_step: SwapStep,  // 下一步 [失败时为失败的步骤]
+    pub refunding: bool,  // 兑换前失败 正在退款
+    #[serde(default)]
+    pub wrap_id: Option<u64>,  // ICP 转入或转出当前使用的包装记录 [见 wrap]
+    #[serde(default)]
+    pub unconfirmed: bool,  // 授权、存入或兑换的调用没有得到答复 [可能已经执行, 恢复时按余额判断 授权按已收取手续费处理]
+    pub wallet_give: Nat,  // 本罐持有的 give 代币
+    pub sonic_give: Nat,  // 存在 Sonic 的 give 代币
+    pub sonic_take: Nat,  // 存在 Sonic 的 take 代币
+    #[serde(default)]
+    pub sonic_give_before: Nat,  // 兑换前本罐在 Sonic 的 give 余额
+    #[serde(default)]
+    pub sonic_take_before: Nat,  // 兑换前本罐在 Sonic 的 take 余额
+    pub wallet_take: Nat,  // 本罐持有的 take 代币
+    pub spent: Nat,  // 兑换付出
+    pub received: Nat,  // 兑换得到
+    pub amount_out: Nat,  // 转给用户的兑换所得
+    pub refunded: Nat,  // 退还用户的 give 代币
+    pub receipts: Vec<SwapStepReceipt>,
+    pub error: Option<String>,
+    pub instime: u64,
+    pub updtime: u64,
+}
+
+// 兑换价格 [每个 take 需要的 give, 按两种代币的精度换算为整币]
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SwapPrice {
+    pub price: f64,
+    pub numerator: Nat,  // 精确价格 numerator / denominator [已约分]
+    pub denominator: Nat,
+    pub path: Vec<String>,  // 计算价格使用的路径 [Sonic 代币 id]
+}
+
+// 兑换报价
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SwapQuote {
+    pub path: Vec<String>,  // 兑换路径 [Sonic 代币 id, 首个为 give 最后为 take]
+    pub amount_in: Nat,  // 付出数量
+    pub amount_out: Nat,  // 得到数量
+}
+
+// 按付出数量的报价 [价格为原始单位下每个 take 需要的 give 的十进制文本]
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PriceImpactQuote {
+    pub path: Vec<String>,
+    pub amount_in: Nat,
+    pub expected_out: Nat,  // 预计得到数量 [已扣每跳手续费]
+    pub minimum_received: Nat,  // 滑点下的最少得到数量
+    pub spot_price: String,  // 当前边际价格
+    pub effective_price: String,  // 成交均价
+    pub price_impact_percent: String,  // 价格影响百分比 [不含手续费]
+}
+
+// 成交记录
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrderFill {
+    pub order_id: u64,
+    pub owner: Principal,
+    pub price: f64,  // 成交均价 [原始单位 give/take]
+    pub amount_in: Nat,  // 实际付出
+    pub amount_out: Nat,  // 实际得到
+    pub time: u64,
+}
+
 #[derive(CandidType)]
 pub enum DepositErr {
     BalanceLow,
@@ -93,7 +499,7 @@ pub enum OrderDirective {
     TakeExact(Nat),
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum Currency {
     ICP,
     XTC,
@@ -101,6 +507,132 @@ pub enum Currency {
     SONIC,
     NnsCyclesMinting,
     BlackHole,
+    Token(Principal),  // 已登记的代币 [见 tokens 登记表]
+}
+
+// 代币标准
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TokenStandard {
+    DIP20,
+    ICRC,  // ICRC-1 [从用户转入需要 ICRC-2]
+}
+
+// ICP 与 WICP 的兑换方向
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WrapKind {
+    Wrap,  // ICP -> WICP
+    Unwrap,  // WICP -> ICP
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WrapStatus {
+    Pending,
+    Completed,
+    Failed,
+}
+
+// ICP 与 WICP 的兑换记录
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WrapRecord {
+    pub id: u64,
+    pub kind: WrapKind,
+    pub amount: u64,  // e8s
+    pub to: String,  // Wrap 时为 WICP 罐账户, Unwrap 时为收款账户 [十六进制账户 id]
+    #[serde(default)]
+    pub from: Option<Principal>,  // Wrap 时从该用户在本罐的子账户转出 [None 为本罐默认账户]
+    pub block_index: Option<u64>,  // Wrap 转出 ICP 的区块高度
+    pub tx: Option<Nat>,  // WICP 的交易 id
+    pub status: WrapStatus,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub unconfirmed: bool,  // 铸造或 Unwrap 调用没有得到 WICP 的答复 可能已经执行 [Unwrap 不能直接重试]
+    pub instime: u64,  // 创建时间 [也是 Wrap 转账的 created_at_time, 重试时账本据此去重]
+    pub updtime: u64,
+}
+
+// 缓存的代币元数据
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+    pub fee: Nat,
+    pub fetched_at: u64,  // 读取时间
+}
+
+// 已登记的代币 [元数据登记时从代币罐读取, 随元数据缓存刷新]
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TokenInfo {
+    pub canister_id: Principal,
+    pub standard: TokenStandard,
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+    pub fee: Nat,  // 每笔转账的手续费
+    pub enabled: bool,  // 停用的代币不可用于新的报价和订单
+    pub instime: u64,
+    pub updtime: u64,
+}
+
+
+// 外部依赖罐 [对应 State 中的每一项]
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ExternalCanister {
+    IcpLedger,
+    XTC,
+    WICP,
+    SonicSwap,
+    NnsCyclesMinting,
+    BlackHole,
+}
+
+
+// 部署环境
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Profile {
+    Local,  // 本地 replica, 使用本地替身罐和模拟余额 [XTC / WICP / SonicSwap / BlackHole 必须在参数中指定]
+    Testnet,  // 测试网络, 使用主网服务罐, 轮训更频繁
+    Mainnet,  // 主网
+}
+
+// 罐余额来源
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum BalanceSource {
+    BlackHole,  // 通过 black hole 读取真实余额 [需要将 black hole 设为控制者]
+    Mock,  // 模拟余额 [本地测试]
+}
+
+// 轮训配置
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct PollingConfig {
+    pub tick_interval: u64,  // 轮训任务执行间隔 秒
+    pub default_time_interval: u64,  // 罐未设置轮训时间间隔时的默认值 秒
+    #[serde(default = "default_stale_after_intervals")]
+    pub stale_after_intervals: u64,  // 距上次成功读取超过多少个轮训间隔视为监控失效 [0 为不检测]
+}
+
+fn default_stale_after_intervals() -> u64 {
+    3
+}
+
+// 安装/升级参数 未设置的项使用 profile 的默认值 [升级时未设置的项保持不变]
+#[derive(CandidType, Deserialize, Clone)]
+pub struct InitArgs {
+    pub profile: Option<Profile>,
+    pub admins: Option<Vec<Principal>>,
+    pub external_canisters: Option<Vec<(ExternalCanister, Principal)>>,
+    pub polling: Option<PollingConfig>,
+    pub balance_source: Option<BalanceSource>,
+}
+
+// 定时任务
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CronTaskKind {
+    PollCanisters,  // 轮训罐余额
+    DeliverWebhooks,  // 投递 webhook
+    EvaluateLimitOrders,  // 检查限价单
+    RecordPrices,  // 记录交易对累计价格
+    RefreshTokenMetadata,  // 刷新代币元数据缓存
 }
 
 
@@ -109,4 +641,20 @@ pub enum Opts {
     ADD,
     UPDATE,
     DELETE,
-}
\ No newline at end of file
+}
+
+
+// 状态不变量违规 [五个映射结构之间的一致性]
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StateViolation {
+    MembersWithoutOwner(OrganizeName),  // 组员映射中的组织没有所有者
+    CanistersWithoutOwner(OrganizeName),  // 罐映射中的组织没有所有者
+    MissingCanisterMapping { organize_name: OrganizeName, canister_id: Principal },  // 组织下的罐没有记录在罐映射组织中
+    DuplicateCanisterMapping { organize_name: OrganizeName, canister_id: Principal },  // 罐映射组织中同一组织出现多次
+    StaleCanisterMapping { organize_name: OrganizeName, canister_id: Principal },  // 罐映射组织指向不存在的组织罐
+    MinCyclesMismatch { organize_name: OrganizeName, canister_id: Principal, expected: u64, found: u64 },  // 罐映射组织中的最低Cycles与组织罐不一致
+    EmptyCanisterMapping(Principal),  // 罐映射组织为空
+    MissingPublicCanister(Principal),  // 被组织收录的罐不在公共罐中
+    StalePublicCanister(Principal),  // 公共罐没有被任何组织收录
+    PublicAggregateMismatch(Principal),  // 公共罐的 轮训时间间隔/最低Cycles/最高Cycles 与重新聚合的结果不一致
+}
@@ -0,0 +1,271 @@
+use ic_cdk::export::candid::Principal;
+use ic_cdk_macros::query;
+
+use crate::common::types::{CanisterForecast, CanisterInfo, CanisterStatusInfo, CyclesSample, OrganizeName};
+use crate::history::{self, CyclesHistory, NANOS_PER_DAY};
+use crate::{staleness, status};
+use crate::{is_admin, is_owner_or_member, ORGANIZES_TO_CANISTERS};
+
+const BURN_RATE_WINDOW: u64 = 7 * NANOS_PER_DAY;  // 使用最近七天的样本估算消耗速率
+const SECONDS_PER_DAY: u128 = 86_400;
+const GIB: u128 = 1 << 30;
+const GIB_STORAGE_PER_SECOND_FEE: u128 = 127_000;  // 每 GiB 内存每秒的存储费用
+const COMPUTE_PERCENT_ALLOCATED_PER_SECOND_FEE: u128 = 10_000_000;  // 每 1% 计算分配每秒的费用
+
+// 空闲消耗 cycles/天 [内存取 内存分配 与 实际占用 中较大的, 加上计算分配]
+pub fn idle_burn_per_day(status: &CanisterStatusInfo) -> u64 {
+    let memory = status.memory_allocation.max(status.memory_size) as u128;
+    let per_second = memory * GIB_STORAGE_PER_SECOND_FEE / GIB
+        + status.compute_allocation as u128 * COMPUTE_PERCENT_ALLOCATED_PER_SECOND_FEE;
+    (per_second * SECONDS_PER_DAY).min(u64::MAX as u128) as u64
+}
+
+// 冻结点 余额低于该值时罐被冻结 [空闲消耗 × 冻结阈值]
+pub fn freezing_point(status: &CanisterStatusInfo) -> u64 {
+    let per_day = idle_burn_per_day(status) as u128;
+    (per_day * status.freezing_threshold as u128 / SECONDS_PER_DAY).min(u64::MAX as u128) as u64
+}
+
+// "高于冻结点 N 天" 换算成 cycles [冻结点 + N 天的消耗, 消耗取观测速率, 没有历史时取空闲消耗]
+pub fn minimum_for_days_above_freeze(canister_id: &Principal, days: u64) -> Option<u64> {
+    let status = status::canister_status(canister_id)?;
+    let observed = history::with_history(canister_id, |history| history.and_then(burn_rate_per_day));
+    let per_day = observed.unwrap_or(0).max(idle_burn_per_day(&status));
+    Some(freezing_point(&status).saturating_add(per_day.saturating_mul(days)))
+}
+
+// 最近窗口内的平均消耗速率 cycles/天
+// 余额上升的区间视为充值, 该区间的消耗无法得知, 不计入消耗也不计入时长
+pub fn burn_rate_per_day(history: &CyclesHistory) -> Option<u64> {
+    let latest = history.iter().next_back()?.timestamp;
+    let since = latest.saturating_sub(BURN_RATE_WINDOW);
+    let mut burned: u128 = 0;
+    let mut elapsed: u128 = 0;
+    let mut prev: Option<&CyclesSample> = None;
+    for sample in history.iter().filter(|s| s.timestamp >= since) {
+        if let Some(prev) = prev {
+            if sample.cycles <= prev.cycles {
+                burned += (prev.cycles - sample.cycles) as u128;
+                elapsed += (sample.timestamp - prev.timestamp) as u128;
+            }
+        }
+        prev = Some(sample);
+    }
+    if elapsed == 0 {
+        return None;
+    }
+    Some((burned * NANOS_PER_DAY as u128 / elapsed).min(u64::MAX as u128) as u64)
+}
+
+// 余额从 balance 按 rate_per_day 消耗到 floor 需要的时长 纳秒, 不消耗时为 None
+pub fn nanos_until(balance: u64, floor: u64, rate_per_day: u64) -> Option<u64> {
+    if balance <= floor {
+        return Some(0);
+    }
+    if rate_per_day == 0 {
+        return None;
+    }
+    let nanos = (balance - floor) as u128 * NANOS_PER_DAY as u128 / rate_per_day as u128;
+    Some(nanos.min(u64::MAX as u128) as u64)
+}
+
+// 组织下一个罐的消耗预测
+pub fn canister_forecast(canister_id: Principal, canister_info: &CanisterInfo) -> CanisterForecast {
+    let (burn_rate_per_day, latest) = history::with_history(&canister_id, |history|{
+        match history {
+            Some(history) => (burn_rate_per_day(history), history.iter().next_back().map(|s| (s.timestamp, s.cycles))),
+            None => (None, None),
+        }
+    });
+    // 没有历史样本时使用组织罐中记录的余额
+    let (updtime, cycles_balance) = latest.unwrap_or((canister_info.updtime.get(), canister_info.cycles_balance.get()));
+    let freezing_point = status::canister_status(&canister_id).map(|status| freezing_point(&status));
+    let project = |floor: u64| {
+        burn_rate_per_day
+            .and_then(|rate| nanos_until(cycles_balance, floor, rate))
+            .map(|nanos| updtime.saturating_add(nanos))
+    };
+    CanisterForecast{
+        canister_id,
+        nickname: canister_info.nickname.clone(),
+        cycles_balance,
+        burn_rate_per_day,
+        depletion_time: project(0),
+        minimum_crossing_time: project(canister_info.cycles_minimum.get()),
+        freezing_point,
+        freeze_time: project(freezing_point.unwrap_or(0)),
+        degraded: staleness::degraded(&canister_id),
+    }
+}
+
+// 组织下所有罐按照预计冻结时间由近到远排序 [无法预测的排在最后]
+#[query]
+pub fn organization_canisters_by_time_to_freeze(organize_name: OrganizeName) -> Result<Vec<CanisterForecast>, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_admin(&caller) && !is_owner_or_member(&caller, &organize_name) {
+        return Err(String::from("No permission to view this organization"));  // 无权查看该组织
+    }
+    let mut forecasts: Vec<CanisterForecast> = ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        match organizes_to_canisters.borrow().get(&organize_name) {
+            Some(canisters) => canisters.borrow().iter()
+                .map(|(canister_id, canister_info)| canister_forecast(*canister_id, &canister_info.borrow()))
+                .collect(),
+            None => Vec::new(),
+        }
+    });
+    forecasts.sort_by_key(|forecast| (forecast.freeze_time.is_none(), forecast.freeze_time));
+    Ok(forecasts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::common::types::CanisterRunningStatus;
+
+    const T: u64 = 1_000_000_000_000;
+
+    fn canister_info(cycles_balance: u64, cycles_minimum: u64) -> CanisterInfo {
+        CanisterInfo{
+            nickname: String::from("jar"),
+            instime: Cell::new(0),
+            updtime: Cell::new(7),
+            cycles_balance: Cell::new(cycles_balance),
+            time_interval: Cell::new(60),
+            cycles_minimum: Cell::new(cycles_minimum),
+            cycles_highest: Cell::new(0),
+            status: None,
+            minimum_days_above_freeze: Cell::new(None),
+            thresholds: None,
+            degraded: None,
+        }
+    }
+
+    // 1 GiB 内存 冻结阈值 30 天 冻结点为 127_000 × 2_592_000 cycles
+    fn status_with_one_gib() -> CanisterStatusInfo {
+        CanisterStatusInfo{
+            status: CanisterRunningStatus::Running,
+            memory_size: 1 << 30,
+            cycles: 0,
+            module_hash: None,
+            controllers: Vec::new(),
+            freezing_threshold: 30 * 86_400,
+            memory_allocation: 0,
+            compute_allocation: 0,
+            updtime: 0,
+        }
+    }
+
+    #[test]
+    fn top_ups_are_excluded_from_the_burn_rate() {
+        let canister_id = Principal::from_slice(&[1]);
+        for (day, cycles) in [(0, 1_000), (1, 900), (2, 2_000), (3, 1_900)] {
+            history::record_sample(canister_id, day * NANOS_PER_DAY, cycles, 0);
+        }
+        // 第 1 到 2 天为充值区间 不计入消耗和时长
+        let rate = history::with_history(&canister_id, |history| history.and_then(burn_rate_per_day));
+        assert_eq!(rate, Some(100));
+    }
+
+    #[test]
+    fn burn_rate_uses_the_recent_window() {
+        let canister_id = Principal::from_slice(&[2]);
+        history::record_sample(canister_id, 0, 100_000, 0);
+        for day in 1..=8 {
+            history::record_sample(canister_id, day * NANOS_PER_DAY, 10_000 - day * 10, 0);
+        }
+        // 第 0 天的样本在七天窗口之外
+        let rate = history::with_history(&canister_id, |history| history.and_then(burn_rate_per_day));
+        assert_eq!(rate, Some(10));
+    }
+
+    #[test]
+    fn burn_rate_needs_two_samples() {
+        let canister_id = Principal::from_slice(&[3]);
+        assert_eq!(history::with_history(&canister_id, |history| history.and_then(burn_rate_per_day)), None);
+        history::record_sample(canister_id, 0, 1_000, 0);
+        assert_eq!(history::with_history(&canister_id, |history| history.and_then(burn_rate_per_day)), None);
+    }
+
+    #[test]
+    fn forecast_projects_depletion_minimum_and_freeze() {
+        let canister_id = Principal::from_slice(&[4]);
+        history::record_sample(canister_id, 0, 2 * T, 0);
+        history::record_sample(canister_id, NANOS_PER_DAY, 19 * T / 10, 0);
+        status::restore(BTreeMap::from([(canister_id, status_with_one_gib())]));
+
+        let forecast = canister_forecast(canister_id, &canister_info(0, T));
+        let freezing_point = 127_000 * 2_592_000;
+        assert_eq!(forecast.cycles_balance, 19 * T / 10);
+        assert_eq!(forecast.burn_rate_per_day, Some(T / 10));
+        assert_eq!(forecast.freezing_point, Some(freezing_point));
+        // 1.9T 每天消耗 0.1T: 19 天耗尽 9 天低于最低Cycles
+        assert_eq!(forecast.depletion_time, Some(20 * NANOS_PER_DAY));
+        assert_eq!(forecast.minimum_crossing_time, Some(10 * NANOS_PER_DAY));
+        let nanos_to_freeze = (19 * T / 10 - freezing_point) as u128 * NANOS_PER_DAY as u128 / (T / 10) as u128;
+        assert_eq!(forecast.freeze_time, Some(NANOS_PER_DAY + nanos_to_freeze as u64));
+    }
+
+    #[test]
+    fn forecast_below_the_floor_is_now() {
+        let canister_id = Principal::from_slice(&[5]);
+        history::record_sample(canister_id, 0, 2 * T, 0);
+        history::record_sample(canister_id, NANOS_PER_DAY, T, 0);
+        let forecast = canister_forecast(canister_id, &canister_info(0, 3 * T));
+        assert_eq!(forecast.minimum_crossing_time, Some(NANOS_PER_DAY));
+        // 没有状态时冻结点未知 按 0 计算
+        assert_eq!(forecast.freezing_point, None);
+        assert_eq!(forecast.freeze_time, forecast.depletion_time);
+    }
+
+    #[test]
+    fn forecast_without_samples_uses_the_recorded_balance() {
+        let canister_id = Principal::from_slice(&[6]);
+        let forecast = canister_forecast(canister_id, &canister_info(5 * T, T));
+        assert_eq!(forecast.cycles_balance, 5 * T);
+        assert_eq!(forecast.burn_rate_per_day, None);
+        assert_eq!(forecast.depletion_time, None);
+        assert_eq!(forecast.minimum_crossing_time, None);
+        assert_eq!(forecast.freeze_time, None);
+    }
+
+    #[test]
+    fn freezing_point_covers_the_idle_burn_for_the_threshold() {
+        let mut status = status_with_one_gib();
+        assert_eq!(idle_burn_per_day(&status), 127_000 * 86_400);
+        assert_eq!(freezing_point(&status), 127_000 * 2_592_000);
+        // 内存分配大于实际占用时按分配计算 计算分配另计
+        status.memory_allocation = 2 << 30;
+        status.compute_allocation = 1;
+        assert_eq!(idle_burn_per_day(&status), (2 * 127_000 + 10_000_000) * 86_400);
+        assert_eq!(freezing_point(&status), (2 * 127_000 + 10_000_000) * 2_592_000);
+        status.freezing_threshold = 0;
+        assert_eq!(freezing_point(&status), 0);
+    }
+
+    #[test]
+    fn minimum_above_freeze_uses_the_faster_burn() {
+        let canister_id = Principal::from_slice(&[7]);
+        // 没有状态时无法换算
+        assert_eq!(minimum_for_days_above_freeze(&canister_id, 3), None);
+        status::restore(BTreeMap::from([(canister_id, status_with_one_gib())]));
+        let freezing_point = 127_000 * 2_592_000;
+        let idle = 127_000 * 86_400;
+        // 没有历史时按空闲消耗
+        assert_eq!(minimum_for_days_above_freeze(&canister_id, 3), Some(freezing_point + 3 * idle));
+        // 观测速率更快时按观测速率
+        history::record_sample(canister_id, 0, 2 * T, 0);
+        history::record_sample(canister_id, NANOS_PER_DAY, 19 * T / 10, 0);
+        assert_eq!(minimum_for_days_above_freeze(&canister_id, 3), Some(freezing_point + 3 * T / 10));
+        assert_eq!(minimum_for_days_above_freeze(&canister_id, 0), Some(freezing_point));
+    }
+
+    #[test]
+    fn no_burn_never_crosses() {
+        assert_eq!(nanos_until(10, 5, 0), None);
+        assert_eq!(nanos_until(5, 5, 0), Some(0));
+        assert_eq!(nanos_until(3, 5, 7), Some(0));
+    }
+}
@@ -0,0 +1,193 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::query;
+
+use crate::can_view_canister;
+use crate::common::types::{CyclesHistoryPage, CyclesSample};
+
+pub const NANOS_PER_HOUR: u64 = 3_600 * 1_000_000_000;
+pub const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+const RAW_RETENTION: u64 = NANOS_PER_DAY;  // 原始样本保留一天
+const HOURLY_RETENTION: u64 = 30 * NANOS_PER_DAY;  // 小时样本保留一个月
+const MAX_DAILY_SAMPLES: usize = 2 * 365;  // 日样本最多保留两年
+const MAX_PAGE_SIZE: u64 = 1_000;  // 单次查询最多返回的样本数
+
+// 罐余额历史 三个精度的样本按时间先后排列 daily < hourly < raw
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CyclesHistory {
+    raw: VecDeque<CyclesSample>,
+    hourly: VecDeque<CyclesSample>,
+    daily: VecDeque<CyclesSample>,
+}
+
+impl CyclesHistory {
+    // 新增样本 并将过期的样本降采样到更低的精度 [每个时间桶保留最后一个样本]
+    fn push(&mut self, sample: CyclesSample) {
+        let now = sample.timestamp;
+        if let Some(last) = self.raw.back() {
+            // 乱序样本丢弃
+            if last.timestamp >= now {
+                return;
+            }
+        }
+        self.raw.push_back(sample);
+
+        while self.raw.front().is_some_and(|s| now.saturating_sub(s.timestamp) > RAW_RETENTION) {
+            let sample = self.raw.pop_front().unwrap();
+            push_bucketed(&mut self.hourly, sample, NANOS_PER_HOUR);
+        }
+        while self.hourly.front().is_some_and(|s| now.saturating_sub(s.timestamp) > HOURLY_RETENTION) {
+            let sample = self.hourly.pop_front().unwrap();
+            push_bucketed(&mut self.daily, sample, NANOS_PER_DAY);
+        }
+        while self.daily.len() > MAX_DAILY_SAMPLES {
+            self.daily.pop_front();
+        }
+    }
+
+    // 按时间先后遍历所有样本
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &CyclesSample> {
+        self.daily.iter().chain(self.hourly.iter()).chain(self.raw.iter())
+    }
+}
+
+// 同一个时间桶内只保留最后一个样本
+fn push_bucketed(samples: &mut VecDeque<CyclesSample>, sample: CyclesSample, bucket: u64) {
+    match samples.back_mut() {
+        Some(last) if last.timestamp / bucket == sample.timestamp / bucket => *last = sample,
+        _ => samples.push_back(sample),
+    }
+}
+
+thread_local!{
+    static CYCLES_HISTORY:RefCell<BTreeMap<Principal, CyclesHistory>> = RefCell::default();  // 罐余额历史
+}
+
+// 记录一次轮训结果
+pub fn record_sample(canister_id: Principal, timestamp: u64, cycles: u64, memory_size: u64) {
+    CYCLES_HISTORY.with(|cycles_history|{
+        cycles_history.borrow_mut().entry(canister_id).or_default().push(CyclesSample{
+            timestamp,
+            cycles,
+            memory_size,
+        });
+    })
+}
+
+// 罐不再被任何组织收录时删除历史
+pub fn remove_history(canister_id: &Principal) {
+    CYCLES_HISTORY.with(|cycles_history|{
+        cycles_history.borrow_mut().remove(canister_id);
+    })
+}
+
+// 在罐的历史上执行只读操作
+pub fn with_history<R>(canister_id: &Principal, f: impl FnOnce(Option<&CyclesHistory>) -> R) -> R {
+    CYCLES_HISTORY.with(|cycles_history| f(cycles_history.borrow().get(canister_id)))
+}
+
+pub fn snapshot() -> BTreeMap<Principal, CyclesHistory> {
+    CYCLES_HISTORY.with(|cycles_history| cycles_history.borrow().clone())
+}
+
+pub fn restore(history: BTreeMap<Principal, CyclesHistory>) {
+    CYCLES_HISTORY.with(|cycles_history| *cycles_history.borrow_mut() = history)
+}
+
+// 查询罐在 [from, to] 时间范围内的余额历史 按时间先后分页
+#[query]
+pub fn canister_cycles_history(canister_id: Principal, from: u64, to: u64, offset: u64, limit: u64) -> Result<CyclesHistoryPage, String> {
+    if !can_view_canister(&ic_cdk::api::caller(), &canister_id) {
+        return Err(String::from("No permission to view this canister"));  // 无权查看该罐
+    }
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    with_history(&canister_id, |history|{
+        let in_range: Vec<&CyclesSample> = match history {
+            Some(history) => history.iter().filter(|s| s.timestamp >= from && s.timestamp <= to).collect(),
+            None => Vec::new(),
+        };
+        Ok(CyclesHistoryPage{
+            total: in_range.len() as u64,
+            samples: in_range.into_iter().skip(offset as usize).take(limit).cloned().collect(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+    fn sample(timestamp: u64, cycles: u64) -> CyclesSample {
+        CyclesSample{ timestamp, cycles, memory_size: 0 }
+    }
+
+    #[test]
+    fn bucket_keeps_the_last_sample() {
+        let mut samples = VecDeque::new();
+        push_bucketed(&mut samples, sample(0, 1), NANOS_PER_HOUR);
+        push_bucketed(&mut samples, sample(NANOS_PER_HOUR - 1, 2), NANOS_PER_HOUR);
+        // 整点属于下一个桶
+        push_bucketed(&mut samples, sample(NANOS_PER_HOUR, 3), NANOS_PER_HOUR);
+        let cycles: Vec<u64> = samples.iter().map(|s| s.cycles).collect();
+        assert_eq!(cycles, vec![2, 3]);
+    }
+
+    #[test]
+    fn raw_samples_older_than_a_day_are_downsampled_hourly() {
+        let mut history = CyclesHistory::default();
+        // 每 10 分钟一个样本 共两天
+        for i in 0..=(2 * 24 * 6) {
+            history.push(sample(i * 10 * NANOS_PER_MINUTE, i));
+        }
+        let now = 2 * NANOS_PER_DAY;
+        assert!(history.raw.iter().all(|s| now - s.timestamp <= RAW_RETENTION));
+        assert_eq!(history.raw.front().map(|s| s.timestamp), Some(NANOS_PER_DAY));
+        // 第一天的每个小时保留最后一个样本 [xx:50]
+        assert_eq!(history.hourly.len(), 24);
+        assert!(history.hourly.iter().all(|s| s.timestamp % NANOS_PER_HOUR == 50 * NANOS_PER_MINUTE));
+        assert!(history.daily.is_empty());
+        // 遍历按时间先后
+        assert!(history.iter().zip(history.iter().skip(1)).all(|(a, b)| a.timestamp < b.timestamp));
+    }
+
+    #[test]
+    fn hourly_samples_older_than_a_month_are_downsampled_daily() {
+        let mut history = CyclesHistory::default();
+        // 每小时一个样本 共 40 天
+        for i in 0..=(40 * 24) {
+            history.push(sample(i * NANOS_PER_HOUR, i));
+        }
+        let now = 40 * NANOS_PER_DAY;
+        assert!(history.hourly.iter().all(|s| now - s.timestamp <= HOURLY_RETENTION));
+        // 超过 30 天的前 10 天 每天保留最后一个小时样本 [23:00]
+        assert_eq!(history.daily.len(), 10);
+        assert!(history.daily.iter().all(|s| s.timestamp % NANOS_PER_DAY == 23 * NANOS_PER_HOUR));
+        assert_eq!(history.daily.back().map(|s| s.timestamp), Some(10 * NANOS_PER_DAY - NANOS_PER_HOUR));
+    }
+
+    #[test]
+    fn daily_samples_are_capped() {
+        let mut history = CyclesHistory::default();
+        let days = MAX_DAILY_SAMPLES as u64 + 40;
+        for i in 0..=days {
+            history.push(sample(i * NANOS_PER_DAY, i));
+        }
+        assert_eq!(history.daily.len(), MAX_DAILY_SAMPLES);
+        // 保留最近的日样本
+        assert_eq!(history.daily.front().map(|s| s.cycles), Some(days - 31 - MAX_DAILY_SAMPLES as u64 + 1));
+    }
+
+    #[test]
+    fn out_of_order_samples_are_dropped() {
+        let mut history = CyclesHistory::default();
+        history.push(sample(10, 1));
+        history.push(sample(10, 2));
+        history.push(sample(5, 3));
+        let cycles: Vec<u64> = history.iter().map(|s| s.cycles).collect();
+        assert_eq!(cycles, vec![1]);
+    }
+}
@@ -0,0 +1,74 @@
+use ic_cdk::export::candid::Principal;
+use ic_cdk_macros::update;
+use ic_ledger_types::{AccountIdentifier, Memo, Subaccount, Timestamp, Tokens, TransferArgs, DEFAULT_FEE, DEFAULT_SUBACCOUNT};
+
+use crate::clients::nns_cycles_minting::{NotifyTopUpArg, NNS_Cycle_Minting};
+use crate::common::guards::admin_guard;
+use crate::{get_state, wrap};
+
+const MEMO_TOP_UP_CANISTER: u64 = 0x5055_5054;  // cycles minting 罐识别充值转账的 memo [TPUP]
+
+// 用本罐的 ICP 通过 cycles minting 罐为 canister_id 充值约 cycles, 返回实际充值的 cycles
+// 本罐 ICP 不足时先把 WICP 解包到本罐账户
+pub async fn top_up_with_icp(canister_id: Principal, cycles: u64) -> Result<u64, String> {
+    let state = get_state();
+    let (rate,) = NNS_Cycle_Minting::get_icp_xdr_conversion_rate(&state.nns_cycles_minting_canister)
+        .await
+        .map_err(|(code, msg)| format!("conversion rate query failed: {:?}: {}", code, msg))?;
+    let e8s = icp_for_cycles(cycles, rate.data.xdr_permyriad_per_icp)?;
+    let fee = DEFAULT_FEE.e8s();
+    let balance = wrap::icp_balance(None).await?;
+    if balance < e8s + fee {
+        // 解包到账时 WICP 扣除一次转账手续费
+        let shortfall = e8s + fee - balance + fee;
+        wrap::unwrap_wicp(shortfall, AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT)).await?;
+    }
+    let block_index = ic_ledger_types::transfer(state.icp_canister, TransferArgs {
+        memo: Memo(MEMO_TOP_UP_CANISTER),
+        amount: Tokens::from_e8s(e8s),
+        fee: DEFAULT_FEE,
+        from_subaccount: None,
+        to: AccountIdentifier::new(&state.nns_cycles_minting_canister, &Subaccount::from(canister_id)),
+        created_at_time: Some(Timestamp{ timestamp_nanos: ic_cdk::api::time() }),
+    })
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+        .map_err(|err| format!("ICP transfer failed: {}", err))?;
+    notify(block_index, canister_id).await
+}
+
+// 每 e8s ICP 兑换 xdr_permyriad_per_icp cycles [1 XDR = 10^12 cycles] 向上取整
+fn icp_for_cycles(cycles: u64, xdr_permyriad_per_icp: u64) -> Result<u64, String> {
+    if xdr_permyriad_per_icp == 0 {
+        return Err(String::from("invalid conversion rate"));  // 汇率无效
+    }
+    Ok(cycles.div_ceil(xdr_permyriad_per_icp))
+}
+
+// 通知 cycles minting 罐完成充值 失败时 ICP 已在其账户中, 可用 admin_notify_top_up 重试
+async fn notify(block_index: u64, canister_id: Principal) -> Result<u64, String> {
+    let (notified,) = NNS_Cycle_Minting::notify_top_up(&get_state().nns_cycles_minting_canister, NotifyTopUpArg{ block_index, canister_id })
+        .await
+        .map_err(|(code, msg)| format!("notify for block {} failed: {:?}: {}", block_index, code, msg))?;
+    let cycles = notified.map_err(|err| format!("notify for block {} failed: {:?}", block_index, err))?;
+    Ok(u64::try_from(cycles.0).unwrap_or(u64::MAX))
+}
+
+// 管理员 重试充值通知 [ICP 已转到 cycles minting 罐但通知失败时]
+#[update(guard = "admin_guard")]
+pub async fn admin_notify_top_up(block_index: u64, canister_id: Principal) -> Result<u64, String> {
+    notify(block_index, canister_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::icp_for_cycles;
+
+    #[test]
+    fn icp_for_cycles_rounds_up() {
+        // 1 ICP = 5 XDR 时每 e8s 兑换 50_000 cycles
+        assert_eq!(icp_for_cycles(5_000_000_000_000, 50_000), Ok(100_000_000));
+        assert_eq!(icp_for_cycles(50_001, 50_000), Ok(2));
+        assert!(icp_for_cycles(1, 0).is_err());
+    }
+}
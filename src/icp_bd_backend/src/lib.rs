@@ -1,24 +1,39 @@
+mod alerts;
+mod anomaly;
 mod clients;
 mod common;
+mod forecast;
+mod history;
+mod icp_top_up;
+mod oracle;
+mod orders;
+mod router;
+mod staleness;
+mod status;
+mod subscriptions;
+mod swap;
+mod thresholds;
+mod tokens;
+mod webhooks;
+mod wrap;
 
 // use rand::Rng;
 use std::borrow::BorrowMut;
 use std::cell::{RefCell, Cell};
 use std::ops::IndexMut;
-use crate::clients::dip20::Dip20;
-use crate::clients::sonic::Sonic;
 use crate::clients::xtc::{XTCBurnPayload, XTC};
 use crate::clients::nns_cycles_minting::{NNS_Cycle_Minting, IcpXdrConversionRateCertifiedResponse, IcpXdrConversionRate};
-use crate::clients::black_hole::{BlackHole, CanisterStatusArg0};
-use crate::common::guards::controller_guard;
-use crate::common::types::{Currency, LimitOrder, MarketOrder, Order, OrderDirective, TargetPrice, OrganizeName, OrganizeOwner, MemberInfo, CanisterInfo, PubilcCanisterInfo, CanisterMappingOrganizationInfo, Opts, UserRechargeICPRecordInfo};
+use crate::clients::black_hole::{BlackHole, CanisterStatusArg0, CanisterStatus, canister_status_status};
+use crate::common::guards::{admin_guard, admin_or_controller_guard};
+use crate::common::types::{Currency, LimitOrder, MarketOrder, Order, OrderDirective, TargetPrice, OrganizeName, OrganizeOwner, MemberInfo, CanisterInfo, PubilcCanisterInfo, CanisterMappingOrganizationInfo, Opts, UserRechargeICPRecordInfo, StateViolation, ExternalCanister, Profile, SwapPrice, BalanceSource, PollingConfig, InitArgs, CronTaskKind, CanisterRunningStatus, CanisterStatusInfo, AlertKind, MemberRole, PollFailureCause};
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
-use bigdecimal::num_bigint::{BigInt, ToBigInt};
-use bigdecimal::num_traits::Pow;
+use bigdecimal::num_bigint::{BigInt, BigUint};
+use bigdecimal::num_traits::pow;
 use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
 use ic_cdk::api::{canister_balance, time};
+use ic_cdk::api::management_canister::main::{deposit_cycles, CanisterIdRecord};
 use ic_cdk::export::candid::{export_service, CandidType, Deserialize, Int, Nat, Principal};
 use ic_cdk::id;
 use ic_cdk::storage::{stable_restore, stable_save};
@@ -39,8 +54,8 @@ type OrganizesToOwner = BTreeMap<OrganizeName, RefCell<OrganizeOwner>>;  // 组
 // 公共罐结构 所有组织下的罐都映射到这个 BT 中, 此中只记录 罐余额， 轮训时间间隔取 所有组织罐中最低的 最低Cycles取最低的，最高Cycles取最高的
 type PublicCanisters = BTreeMap<Principal, PubilcCanisterInfo>;
 // 记录这个罐都被那个组织添加了，以备在罐余额不足时直接命中组织进而找到成员，组织排序方式按照最低Cycles进行排序,可以找到最低设置Cycle用户
-// Vec 有排序方法 CanistersInfo.sort_by(|op, m| m.min_cycles.cmp(&op.min_cycles));
-type CanistersToOrganizes = BTreeMap<Principal, RefCell<Vec<CanisterMappingOrganizationInfo>>>;
+// 有序索引 罐 -> (最低Cycles, 组织名), first() 为最低Cycles最低的组织, last() 为最高的组织, 查询无需重新排序
+type CanistersToOrganizes = BTreeMap<Principal, BTreeSet<(u64, OrganizeName)>>;
 // 存储用户充值记录结构  只能查到余额 使用了怎么办
 type UserRechargeRecordStructure = BTreeMap<Principal, RefCell<Vec<UserRechargeICPRecordInfo>>>;
 
@@ -59,6 +74,11 @@ thread_local!{
     static ORGANIZES_TO_OWNER:RefCell<OrganizesToOwner> = RefCell::default();
     static PUBLIC_CANISTERS:RefCell<PublicCanisters> = RefCell::default();
     static CANISTERS_TO_ORGANIZES:RefCell<CanistersToOrganizes> = RefCell::default();
+    static ADMINS:RefCell<BTreeSet<Principal>> = RefCell::default();  // 管理员集合
+    static CONTROLLERS:RefCell<BTreeSet<Principal>> = RefCell::default();  // 安装或最近一次升级本罐的控制者
+    static CONFIG:RefCell<Option<Config>> = const { RefCell::new(None) };  // 运行配置
+    static POLLING_IN_FLIGHT:RefCell<BTreeSet<Principal>> = RefCell::default();  // 正在轮训的罐
+    static POLL_TASK:Cell<Option<TaskId>> = const { Cell::new(None) };  // 轮训定时任务
 }
 
 // 创建组织
@@ -142,7 +162,8 @@ pub async fn organization_owner_add_members_to_organization(member_id: Principal
                         RefCell::new(
                             MemberInfo{
                                 nickname: member_name,
-                                instime: Cell::new(ic_cdk::api::time())
+                                instime: Cell::new(ic_cdk::api::time()),
+                                role: Cell::new(MemberRole::default()),
                             }
                         ));
                     String::from("added successfully")  // 新增成功
@@ -156,7 +177,8 @@ pub async fn organization_owner_add_members_to_organization(member_id: Principal
                     member_id, 
                     RefCell::new(MemberInfo{
                         nickname: member_name,
-                        instime: Cell::new(ic_cdk::api::time())
+                        instime: Cell::new(ic_cdk::api::time()),
+                        role: Cell::new(MemberRole::default()),
                     })
                 );
                 // 插入 组织
@@ -204,6 +226,20 @@ pub async fn organization_owner_minus_organization_members(member_id: Principal,
     })
 }
 
+// 组织所有人 设置成员角色 [Viewer 只能查看 Warning 及以上的告警, Operator 可确认/解决告警]
+#[update]
+pub fn organization_owner_set_member_role(member_id: Principal, organize_name: String, role: MemberRole) -> String {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return String::from("Non-organization owners cannot modify members");  // 非组织所有者不可修改成员
+    }
+    ORGANIZES_TO_MEMBERS.with(|organizes_to_members|{
+        match organizes_to_members.borrow().get(&organize_name).and_then(|members| members.borrow().get(&member_id).map(|member| member.borrow().role.set(role))) {
+            Some(()) => String::from("Member role updated successfully"),  // 成员角色修改成功
+            None => String::from("The member does not exist in the organization"),  // 该成员不存在于组织中
+        }
+    })
+}
+
 
 // 组织所有者查询自己名下组织及组织下的用户
 #[query]
@@ -250,10 +286,13 @@ pub async fn the_organization_owner_queries_the_organization_under_his_own_name_
 #[update]
 pub async fn the_organization_owner_adds_a_new_jar_to_the_organization(organize_name:String, canister_name: String, canister_id: Principal, time_interval: u64, cycles_minimum: u64, cycles_highest: u64) -> String {
     let requester_id = ic_cdk::api::caller();
-    // 正式环境使用 测试没有问题
-    // let cycle_balance = use_black_hole_cycles_balance(canister_id).await.0.to_u64().unwrap();
-    // 测试环境使用直接赋值
-    let cycle_balance = generate_random_numbers().await;
+    // 余额来源由配置决定 [正式环境 black hole, 测试环境 模拟余额]
+    let cycle_balance = match fetch_cycles_balance(canister_id).await {
+        Ok(cycle_balance) => cycle_balance,
+        Err(err) => return format!("Unable to read canister cycles: {}", err),  // 无法读取罐余额
+    };
+    // 未设置轮训时间间隔时使用默认值
+    let time_interval = if time_interval == 0 { get_config().polling.default_time_interval } else { time_interval };
     ORGANIZES_TO_OWNER.with(|organizes_to_owner|{
         // 组织必须存在
         if !organizes_to_owner.borrow().contains_key(&organize_name){
@@ -283,23 +322,20 @@ pub async fn the_organization_owner_adds_a_new_jar_to_the_organization(organize_
                                 time_interval: Cell::new(time_interval),
                                 cycles_minimum: Cell::new(cycles_minimum),
                                 cycles_highest: Cell::new(cycles_highest),
+                                status: None,
+                                minimum_days_above_freeze: Cell::new(None),
+                                thresholds: None,
+                                degraded: None,
                             }
                         ));
-                    // 为公共罐结构增加罐
-                    add_or_update_public_canisters(
-                        canister_id, 
-                        ic_cdk::api::time(), 
-                        cycle_balance, 
-                        time_interval, 
-                        cycles_minimum, 
-                        cycles_highest
-                    );
                     // 记录该罐被那些组织收录逻辑
                     canister_mapping_organization_deal_with(
                         Opts::ADD, 
                         canister_id, 
                         organize_name, 
                         cycles_minimum);
+                    // 为公共罐结构重新聚合
+                    recompute_public_canisters(canister_id, Some((ic_cdk::api::time(), cycle_balance)));
                     String::from("added successfully")  // 新增成功
                 }
             } else {
@@ -317,6 +353,10 @@ pub async fn the_organization_owner_adds_a_new_jar_to_the_organization(organize_
                         time_interval: Cell::new(time_interval),
                         cycles_minimum: Cell::new(cycles_minimum),
                         cycles_highest: Cell::new(cycles_highest),
+                        status: None,
+                        minimum_days_above_freeze: Cell::new(None),
+                        thresholds: None,
+                        degraded: None,
                     })
                 );
                 // 插入 组织
@@ -324,21 +364,14 @@ pub async fn the_organization_owner_adds_a_new_jar_to_the_organization(organize_
                     organize_name.clone(),
                     canisters
                 );
-                // 为公共罐结构增加罐
-                add_or_update_public_canisters(
-                    canister_id, 
-                    ic_cdk::api::time(), 
-                    cycle_balance, 
-                    time_interval, 
-                    cycles_minimum, 
-                    cycles_highest
-                );
                 // 记录该罐被那些组织收录逻辑
                 canister_mapping_organization_deal_with(
                     Opts::ADD, 
                     canister_id, 
                     organize_name.clone(), 
                     cycles_minimum);
+                // 为公共罐结构重新聚合
+                recompute_public_canisters(canister_id, Some((ic_cdk::api::time(), cycle_balance)));
                 String::from("Organization canister added successfully")  // 组织罐新增成功
             }
         });
@@ -369,8 +402,11 @@ pub async fn organization_owner_delete_jar(organize_name:String, canister_id: Pr
                     canister_mapping_organization_deal_with(
                         Opts::DELETE, 
                         canister_id, 
-                        organize_name, 
+                        organize_name.clone(), 
                         0u64);
+                    remove_canister_from_organize(&canister_id, &organize_name);
+                    // 公共罐结构重新聚合, 不再被任何组织收录时移除
+                    recompute_public_canisters(canister_id, None);
                     String::from("The canister has been removed from the organization")  // 该罐已在组织中删除
                 } else {
                     String::from("The canister does not exist in the organization")  // 该罐不存在于组织中
@@ -388,8 +424,11 @@ pub async fn organization_owner_delete_jar(organize_name:String, canister_id: Pr
 #[update]
 pub async fn organization_owner_modify_jar(organize_name: String, canister_id:Principal, time_interval:u64, cycles_minimum:u64, cycles_highest:u64) -> String {
     let requester_id = ic_cdk::api::caller();
-    // let cycle_balance = use_black_hole_cycles_balance(canister_id).await.0.to_u64().unwrap();
-    let cycle_balance = generate_random_numbers().await;
+    let cycle_balance = match fetch_cycles_balance(canister_id).await {
+        Ok(cycle_balance) => cycle_balance,
+        Err(err) => return format!("Unable to read canister cycles: {}", err),  // 无法读取罐余额
+    };
+    let time_interval = if time_interval == 0 { get_config().polling.default_time_interval } else { time_interval };
     ORGANIZES_TO_OWNER.with(|organizes_to_owner|{
         // 组织必须存在
         if !organizes_to_owner.borrow().contains_key(&organize_name){
@@ -410,21 +449,17 @@ pub async fn organization_owner_modify_jar(organize_name: String, canister_id:Pr
                     organizes_to_canisters.borrow().get(&organize_name).unwrap().borrow().get(&canister_id).unwrap().borrow_mut().cycles_minimum.set(cycles_minimum);
                     organizes_to_canisters.borrow().get(&organize_name).unwrap().borrow().get(&canister_id).unwrap().borrow_mut().cycles_highest.set(cycles_highest);
                     organizes_to_canisters.borrow().get(&organize_name).unwrap().borrow().get(&canister_id).unwrap().borrow_mut().cycles_balance.set(cycle_balance);
-                     // 为公共罐结构增加罐
-                     add_or_update_public_canisters(
-                        canister_id, 
-                        ic_cdk::api::time(), 
-                        cycle_balance, 
-                        time_interval, 
-                        cycles_minimum, 
-                        cycles_highest
-                    );
+                    organizes_to_canisters.borrow().get(&organize_name).unwrap().borrow().get(&canister_id).unwrap().borrow_mut().updtime.set(ic_cdk::api::time());
+                    // 设置了绝对的最低Cycles 不再按冻结点换算
+                    organizes_to_canisters.borrow().get(&organize_name).unwrap().borrow().get(&canister_id).unwrap().borrow_mut().minimum_days_above_freeze.set(None);
                     // 记录该罐被那些组织修改逻辑
                     canister_mapping_organization_deal_with(
                         Opts::UPDATE, 
                         canister_id, 
                         organize_name, 
                         cycles_minimum);
+                    // 为公共罐结构重新聚合
+                    recompute_public_canisters(canister_id, Some((ic_cdk::api::time(), cycle_balance)));
                     String::from("Canister details updated successfully")  // canister 详情更新成功
 
                 } else {
@@ -440,6 +475,31 @@ pub async fn organization_owner_modify_jar(organize_name: String, canister_id:Pr
 }
 
 
+// 组织所有人 以 "高于冻结点 N 天" 设置罐的最低Cycles [days 为 None 时恢复为当前的绝对值]
+#[update]
+pub fn organization_owner_set_jar_minimum_days_above_freeze(organize_name: String, canister_id: Principal, days: Option<u64>) -> String {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return String::from("Non-organization owners cannot modify canister");  // 非组织所有者不可修改罐
+    }
+    let exists = ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        match organizes_to_canisters.borrow().get(&organize_name) {
+            Some(canisters) => match canisters.borrow().get(&canister_id) {
+                Some(canister_info) => {
+                    canister_info.borrow().minimum_days_above_freeze.set(days);
+                    true
+                },
+                None => false,
+            },
+            None => false,
+        }
+    });
+    if !exists {
+        return String::from("The canister does not exist under this organization");  // 罐不存在
+    }
+    apply_dynamic_minimums(canister_id);
+    String::from("Canister details updated successfully")  // canister 详情更新成功
+}
+
 // 组织所有人 查询自己名下组织及组织下的罐
 #[query]
 pub async fn organization_owner_query_the_organization_under_his_name_and_the_tanks_under_the_organization() -> OrganizationOwnerCanisterOutput {
@@ -464,7 +524,13 @@ pub async fn organization_owner_query_the_organization_under_his_name_and_the_ta
                 match organizes_to_canisters.borrow().get(&organize_name) {
                     Some(canister_info) => {
                         let mut o_t_m = OrganizesToCanisters::new();
-                        o_t_m.insert(organize_name, canister_info.clone());
+                        let canister_info = canister_info.clone();
+                        // 填充罐完整状态
+                        for (canister_id, info) in canister_info.borrow().iter() {
+                            info.borrow_mut().status = status::canister_status(canister_id);
+                            info.borrow_mut().degraded = staleness::degraded(canister_id);
+                        }
+                        o_t_m.insert(organize_name, canister_info);
                         organization_owner_canister_output.push(o_t_m);
                     },
                     None => {
@@ -483,19 +549,26 @@ pub async fn organization_owner_query_the_organization_under_his_name_and_the_ta
 
 // 测试期间方法  
 // 查询公共映射罐结构
-#[query]
+#[query(guard = "admin_guard")]
 pub async fn query_the_structure_of_the_public_rotation_training_tank() -> PublicCanisters {
     PUBLIC_CANISTERS.with(|public_canisters|{
         public_canisters.borrow_mut().clone()
     })
 }
 
-// 按照 cycles 排序 组织
-#[query]
+// 按照 cycles 排序 组织 [由低到高, 索引本身有序]
+#[query(guard = "admin_guard")]
 pub async fn organize_according_to_cycles_sorting(canister_id: Principal) -> Vec<CanisterMappingOrganizationInfo> {
     CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
-        canisters_to_organizes.borrow_mut().get(&canister_id).unwrap().borrow_mut().sort_by(|op, m| m.min_cycles.cmp(&op.min_cycles));
-        canisters_to_organizes.borrow_mut().get(&canister_id).unwrap().borrow_mut().clone()
+        match canisters_to_organizes.borrow().get(&canister_id) {
+            Some(organizes) => organizes.iter().map(|(min_cycles, organize_name)| {
+                CanisterMappingOrganizationInfo{
+                    organize_name: organize_name.clone(),
+                    min_cycles: Cell::new(*min_cycles),
+                }
+            }).collect(),
+            None => Vec::new(),
+        }
     })
 }
 
@@ -508,135 +581,311 @@ pub async fn generate_random_numbers () -> u64 {
 }
 
 
+// 检查五个映射结构之间的不变量 返回所有违规项
+#[query(guard = "admin_guard")]
+pub fn check_state_invariants() -> Vec<StateViolation> {
+    collect_state_violations()
+}
+
+// 按照确定的顺序修复所有不变量违规 返回修复前检查到的违规项 [升级后可执行]
+// 1. 删除没有所有者的组织的 成员/罐
+// 2. 以 ORGANIZES_TO_CANISTERS 为准重建 CANISTERS_TO_ORGANIZES
+// 3. 重新聚合 PUBLIC_CANISTERS, 保留已读取的余额
+#[update(guard = "admin_guard")]
+pub fn repair_state_invariants() -> Vec<StateViolation> {
+    let violations = collect_state_violations();
+    if violations.is_empty() {
+        return violations;
+    }
+
+    let owned: BTreeSet<OrganizeName> = ORGANIZES_TO_OWNER.with(|organizes_to_owner|{
+        organizes_to_owner.borrow().keys().cloned().collect()
+    });
+    ORGANIZES_TO_MEMBERS.with(|organizes_to_members|{
+        organizes_to_members.borrow_mut().retain(|organize_name, _| owned.contains(organize_name));
+    });
+    ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        organizes_to_canisters.borrow_mut().retain(|organize_name, _| owned.contains(organize_name));
+    });
+
+    // 重建罐映射组织
+    let rebuilt: CanistersToOrganizes = ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        let mut rebuilt = CanistersToOrganizes::new();
+        for (organize_name, canisters) in organizes_to_canisters.borrow().iter() {
+            for (canister_id, canister_info) in canisters.borrow().iter() {
+                rebuilt.entry(*canister_id).or_default().insert(
+                    (canister_info.borrow().cycles_minimum.get(), organize_name.clone())
+                );
+            }
+        }
+        rebuilt
+    });
+    let mut canister_ids: BTreeSet<Principal> = rebuilt.keys().cloned().collect();
+    CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        canister_ids.extend(canisters_to_organizes.borrow().keys().cloned());
+        *canisters_to_organizes.borrow_mut() = rebuilt;
+    });
+
+    // 重新聚合公共罐
+    PUBLIC_CANISTERS.with(|public_canisters|{
+        canister_ids.extend(public_canisters.borrow().keys().cloned());
+    });
+    for canister_id in canister_ids {
+        recompute_public_canisters(canister_id, None);
+    }
+
+    violations
+}
+
+
 // 私有方法 
 // 同步删除
 fn delete_synchronously (organize_name:&String) {
     // 删除组织的同时删除组织成员
     ORGANIZES_TO_MEMBERS.with(|organizes_to_members|{
-        match organizes_to_members.borrow().get(organize_name) {
-            Some(member_info) => {
-                organizes_to_members.borrow_mut().remove(organize_name);
-            },
-            None => (),
-        }
+        organizes_to_members.borrow_mut().remove(organize_name);
     });
     // 删除组织的同时删除组织罐
-    ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
-        match organizes_to_canisters.borrow().get(organize_name) {
-            Some(canister_info) => {
-                organizes_to_canisters.borrow_mut().remove(organize_name);
-            },
-            None => (),
+    let canisters = ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        organizes_to_canisters.borrow_mut().remove(organize_name)
+    });
+    // 删除组织罐的同时删除罐映射组织 并重新聚合公共罐
+    if let Some(canisters) = canisters {
+        for canister_id in canisters.borrow().keys() {
+            canister_mapping_organization_deal_with(
+                Opts::DELETE,
+                *canister_id,
+                organize_name.clone(),
+                0u64);
+            remove_canister_from_organize(canister_id, organize_name);
+            recompute_public_canisters(*canister_id, None);
         }
-    })
+    }
+    // 删除组织的告警 阈值 异常检测 webhook 和订阅 [同名组织重新创建时不会继承]
+    alerts::remove_organize(organize_name);
+    thresholds::remove_organize(organize_name);
+    anomaly::remove_organize(organize_name);
+    webhooks::remove_organize(organize_name);
+    subscriptions::remove_organize(organize_name);
 }
 
-// 公共罐映射 添加和修改
-fn add_or_update_public_canisters (canister_id: Principal,updtime: u64, cycles_balance: u64, time_interval: u64, cycles_minimum: u64, cycles_highest: u64) {
-    // 公共罐映射 添加和修改
-    PUBLIC_CANISTERS.with(|public_canisters|{
-        // 这个罐存在
-        if public_canisters.borrow().get(&canister_id).is_some(){
-            // 获取公共罐的 time_interval cycles_minimum cycles_highest
-            if public_canisters.borrow().get(&canister_id).unwrap().time_interval > Cell::new(time_interval) {
-                // 找最小的轮训时间间隔
-                public_canisters.borrow().get(&canister_id).unwrap().time_interval.set(time_interval)
+// 组织移除罐时清除该 (罐, 组织) 的状态 [其他组织仍收录时公共罐和其余组织的状态保留]
+fn remove_canister_from_organize(canister_id: &Principal, organize_name: &OrganizeName) {
+    thresholds::remove_from_organize(canister_id, organize_name);
+    anomaly::remove_from_organize(canister_id, organize_name);
+    subscriptions::remove_from_organize(canister_id, organize_name);
+    alerts::remove_from_organize(canister_id, organize_name);
+}
+
+// 收集所有不变量违规项
+fn collect_state_violations() -> Vec<StateViolation> {
+    let mut violations = Vec::new();
+    let owned: BTreeSet<OrganizeName> = ORGANIZES_TO_OWNER.with(|organizes_to_owner|{
+        organizes_to_owner.borrow().keys().cloned().collect()
+    });
+
+    // 成员映射和罐映射中的组织都必须有所有者
+    ORGANIZES_TO_MEMBERS.with(|organizes_to_members|{
+        for organize_name in organizes_to_members.borrow().keys() {
+            if !owned.contains(organize_name) {
+                violations.push(StateViolation::MembersWithoutOwner(organize_name.clone()));
             }
-            if public_canisters.borrow().get(&canister_id).unwrap().cycles_minimum > Cell::new(cycles_minimum) {
-                // 找最低限度的 Cycles
-                public_canisters.borrow().get(&canister_id).unwrap().cycles_minimum.set(cycles_minimum)
+        }
+    });
+    // (罐, 组织) -> 组织罐中的最低Cycles
+    let mut expected: BTreeMap<(Principal, OrganizeName), u64> = BTreeMap::new();
+    ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        for (organize_name, canisters) in organizes_to_canisters.borrow().iter() {
+            if !owned.contains(organize_name) {
+                violations.push(StateViolation::CanistersWithoutOwner(organize_name.clone()));
             }
-            if public_canisters.borrow().get(&canister_id).unwrap().cycles_highest < Cell::new(cycles_highest) {
-                // 找最高限度的 Cycles
-                public_canisters.borrow().get(&canister_id).unwrap().cycles_highest.set(cycles_highest)
+            for (canister_id, canister_info) in canisters.borrow().iter() {
+                expected.insert((*canister_id, organize_name.clone()), canister_info.borrow().cycles_minimum.get());
             }
-            // 因为每触发一次此函数都会重新读取一次罐的 Cycles
-            // 故同步更新 公共 updtime  cycles_balance
-            public_canisters.borrow().get(&canister_id).unwrap().updtime.set(updtime);
-            public_canisters.borrow().get(&canister_id).unwrap().cycles_balance.set(cycles_balance);
-        } else {
-            // 这个罐不存在 新增罐
-            public_canisters.borrow_mut().insert(
-                canister_id,
-                PubilcCanisterInfo{
-                    updtime:Cell::new(updtime),
-                    cycles_balance:Cell::new(cycles_balance),
-                    time_interval:Cell::new(time_interval),
-                    cycles_minimum:Cell::new(cycles_minimum),
-                    cycles_highest:Cell::new(cycles_highest),
+        }
+    });
+
+    // 罐映射组织必须与组织罐一一对应
+    let mut mapped: BTreeSet<(Principal, OrganizeName)> = BTreeSet::new();
+    let mapped_canisters: BTreeSet<Principal> = CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        for (canister_id, organizes) in canisters_to_organizes.borrow().iter() {
+            if organizes.is_empty() {
+                violations.push(StateViolation::EmptyCanisterMapping(*canister_id));
+            }
+            for (min_cycles, organize_name) in organizes.iter() {
+                let key = (*canister_id, organize_name.clone());
+                if !mapped.insert(key.clone()) {
+                    violations.push(StateViolation::DuplicateCanisterMapping{ organize_name: key.1, canister_id: key.0 });
+                    continue;
                 }
-            );
+                match expected.get(&key) {
+                    Some(cycles_minimum) if cycles_minimum != min_cycles => {
+                        violations.push(StateViolation::MinCyclesMismatch{
+                            organize_name: key.1,
+                            canister_id: key.0,
+                            expected: *cycles_minimum,
+                            found: *min_cycles,
+                        });
+                    },
+                    Some(_) => (),
+                    None => violations.push(StateViolation::StaleCanisterMapping{ organize_name: key.1, canister_id: key.0 }),
+                }
+            }
+        }
+        canisters_to_organizes.borrow().keys().cloned().collect()
+    });
+    for (canister_id, organize_name) in expected.keys() {
+        if !mapped.contains(&(*canister_id, organize_name.clone())) {
+            violations.push(StateViolation::MissingCanisterMapping{ organize_name: organize_name.clone(), canister_id: *canister_id });
+        }
+    }
+
+    // 公共罐必须与被收录的罐一一对应 且聚合值正确
+    let expected_canisters: BTreeSet<Principal> = expected.keys().map(|(canister_id, _)| *canister_id).collect();
+    PUBLIC_CANISTERS.with(|public_canisters|{
+        let public_canisters = public_canisters.borrow();
+        for canister_id in expected_canisters.union(&mapped_canisters) {
+            if !public_canisters.contains_key(canister_id) {
+                violations.push(StateViolation::MissingPublicCanister(*canister_id));
+            }
+        }
+        for (canister_id, public_canister) in public_canisters.iter() {
+            match public_aggregate(*canister_id) {
+                Some((time_interval, cycles_minimum, cycles_highest)) => {
+                    if public_canister.time_interval.get() != time_interval
+                        || public_canister.cycles_minimum.get() != cycles_minimum
+                        || public_canister.cycles_highest.get() != cycles_highest {
+                        violations.push(StateViolation::PublicAggregateMismatch(*canister_id));
+                    }
+                },
+                None => violations.push(StateViolation::StalePublicCanister(*canister_id)),
+            }
+        }
+    });
+
+    violations
+}
+
+// 公共罐映射 重新聚合
+// 轮训时间间隔取所有收录该罐组织中最小的, 最低Cycles取最低的, 最高Cycles取最高的
+// 每次 新增/修改/删除 都从 CANISTERS_TO_ORGANIZES 剩余的组织重新计算, 没有组织收录时移除该罐
+// balance 为本次读取到的 (updtime, cycles_balance), 为 None 时沿用公共罐中已有的值
+fn recompute_public_canisters(canister_id: Principal, balance: Option<(u64, u64)>) {
+    let aggregate = public_aggregate(canister_id);
+    PUBLIC_CANISTERS.with(|public_canisters|{
+        let mut public_canisters = public_canisters.borrow_mut();
+        match aggregate {
+            // 没有任何组织收录这个罐 移除
+            None => {
+                public_canisters.remove(&canister_id);
+                history::remove_history(&canister_id);
+                status::remove_status(&canister_id);
+                anomaly::remove_canister(&canister_id);
+                thresholds::remove_canister(&canister_id);
+                subscriptions::remove_canister(&canister_id);
+                staleness::remove_canister(&canister_id);
+            },
+            Some((time_interval, cycles_minimum, cycles_highest)) => {
+                let (updtime, cycles_balance) = match (balance, public_canisters.get(&canister_id)) {
+                    (Some(balance), _) => balance,
+                    (None, Some(public_canister)) => (public_canister.updtime.get(), public_canister.cycles_balance.get()),
+                    (None, None) => (0u64, 0u64),
+                };
+                public_canisters.insert(
+                    canister_id,
+                    PubilcCanisterInfo{
+                        updtime:Cell::new(updtime),
+                        cycles_balance:Cell::new(cycles_balance),
+                        time_interval:Cell::new(time_interval),
+                        cycles_minimum:Cell::new(cycles_minimum),
+                        cycles_highest:Cell::new(cycles_highest),
+                    }
+                );
+            },
         }
     })
 }
 
+// 从 CANISTERS_TO_ORGANIZES 剩余的组织聚合出公共罐的 (time_interval, cycles_minimum, cycles_highest)
+fn public_aggregate(canister_id: Principal) -> Option<(u64, u64, u64)> {
+    // 收录该罐的所有组织
+    let organizes: Vec<OrganizeName> = CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        match canisters_to_organizes.borrow().get(&canister_id) {
+            Some(organizes) => organizes.iter().map(|(_, organize_name)| organize_name.clone()).collect(),
+            None => Vec::new(),
+        }
+    });
+
+    // (time_interval, cycles_minimum, cycles_highest)
+    let mut aggregate: Option<(u64, u64, u64)> = None;
+    ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        for organize_name in organizes.iter() {
+            if let Some(canisters) = organizes_to_canisters.borrow().get(organize_name) {
+                if let Some(canister_info) = canisters.borrow().get(&canister_id) {
+                    let canister_info = canister_info.borrow();
+                    let time_interval = canister_info.time_interval.get();
+                    let cycles_minimum = canister_info.cycles_minimum.get();
+                    let cycles_highest = canister_info.cycles_highest.get();
+                    aggregate = Some(match aggregate {
+                        Some((t, min, max)) => (t.min(time_interval), min.min(cycles_minimum), max.max(cycles_highest)),
+                        None => (time_interval, cycles_minimum, cycles_highest),
+                    });
+                }
+            }
+        }
+    });
+    aggregate
+}
+
 
 // 罐映射组织排序 操作 [新增/修改/删除]
 fn canister_mapping_organization_deal_with(opt: Opts, canister_id:Principal, organize_name: String, min_cycles: u64) {
     // 罐组织映射结构操作有三种
-    
+    // 索引键包含 min_cycles, 修改和删除都先移除该组织原有的索引项
     CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
-        // 当这个罐 组织 映射不存在时 对 且 opt 为 ADD时 对 Vec 进行初始化新增
-        if opt == Opts::ADD {
-            // 罐组织不存在是进行初始化新增
-            if canisters_to_organizes.borrow_mut().get(&canister_id).is_none(){
-                let mut v_c_m_z_i = Vec::new();
-                v_c_m_z_i.push(
-                    CanisterMappingOrganizationInfo{
-                        organize_name: organize_name,
-                        min_cycles:Cell::new(min_cycles),
-                    }
-                );
-                canisters_to_organizes.borrow_mut().insert(
-                    canister_id, 
-                    RefCell::new(v_c_m_z_i),
-                );
-            } else {
-                // 罐组织存在 进行 push 新增
-                canisters_to_organizes.borrow_mut().get(&canister_id).unwrap().borrow_mut().push(
-                    CanisterMappingOrganizationInfo {
-                        organize_name: organize_name,
-                        min_cycles: Cell::new(min_cycles),
-                    }
-                );
-            }
-        } else if opt == Opts::UPDATE {
-            // 罐组织存在修改 min_cycles
-            canisters_to_organizes.borrow_mut().get(&canister_id).unwrap().borrow_mut().iter_mut().find(|cmoi|{cmoi.organize_name == organize_name}).unwrap().borrow_mut().min_cycles.set(min_cycles);
-            // target.borrow_mut().min_cycles.set(min_cycles);
-
-        } else {
-            // 罐组织存在 进行删除
-            // if let Some(target)= canisters_to_organizes.borrow_mut().get(&canister_id).unwrap().borrow_mut().iter_mut().position(|cmoi|{cmoi.organize_name == organize_name}) {
-            //     // target.borrow_mut().organize_name.remove(index);
-            //     canisters_to_organizes.borrow_mut().get(&canister_id).unwrap().borrow_mut().remove(target);
-            // } else {
-            //     ();
-            // }
-            let mut target= canisters_to_organizes.borrow_mut().get(&canister_id).unwrap().borrow_mut().iter().position(|cmoi|{cmoi.organize_name == organize_name}).unwrap();
-            canisters_to_organizes.borrow_mut().get(&canister_id).unwrap().borrow_mut().remove(target);
-            
+        let mut canisters_to_organizes = canisters_to_organizes.borrow_mut();
+        let organizes = canisters_to_organizes.entry(canister_id).or_default();
+        organizes.retain(|(_, name)| name != &organize_name);
+        if opt != Opts::DELETE {
+            organizes.insert((min_cycles, organize_name));
+        }
+        // 没有组织再收录这个罐 移除映射
+        if organizes.is_empty() {
+            canisters_to_organizes.remove(&canister_id);
         }
     })
 }
 
+// 该罐最低Cycles设置最低的组织 [O(log n)]
+fn lowest_threshold_organization(canister_id: &Principal) -> Option<(u64, OrganizeName)> {
+    CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        canisters_to_organizes.borrow().get(canister_id).and_then(|organizes| organizes.iter().next().cloned())
+    })
+}
 
+// 该罐最低Cycles设置最高的组织 [O(log n)]
+fn highest_threshold_organization(canister_id: &Principal) -> Option<(u64, OrganizeName)> {
+    CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        canisters_to_organizes.borrow().get(canister_id).and_then(|organizes| organizes.iter().next_back().cloned())
+    })
+}
 
-async fn get_swap_price_internal(give_currency: Currency, take_currency: Currency) -> BigDecimal {
-    let state = get_state();
-    let give_token = token_id_by_currency(give_currency);
-    let take_token = token_id_by_currency(take_currency);
-
-    let (pair_opt,) = Sonic::get_pair(&state.sonic_swap_canister, give_token, take_token)
-        .await
-        .expect("Unable to fetch pair at Sonic");
-
-    let pair = pair_opt.unwrap();
 
-    let give_reserve = BigDecimal::from(pair.reserve0.0.to_bigint().unwrap());
-    let take_reserve = BigDecimal::from(pair.reserve1.0.to_bigint().unwrap());
 
-    give_reserve / take_reserve
+// 原始单位下每个 take 需要的 give 的精确比值 [没有直接交易对时按多跳路径计算]
+// 路径按配置窗口的 TWAP 选择 避免被单笔交易操纵, 价格记录不足时按边际价格选择
+async fn get_swap_price_internal(give_token: Principal, take_token: Principal) -> Result<(Vec<String>, BigUint, BigUint), String> {
+    let graph = router::load_graph().await?;
+    let (from, to) = (give_token.to_text(), take_token.to_text());
+    let path = match oracle::best_twap_path(&graph, &from, &to, get_config().twap_window) {
+        Some(path) => path,
+        None => graph.best_spot_path(&from, &to)
+            .map(|(path, _)| path)
+            .ok_or_else(|| String::from("no route between these currencies"))?,
+    };
+    let (numerator, denominator) = graph.path_spot_ratio(&path)
+        .ok_or_else(|| String::from("no route between these currencies"))?;
+    Ok((path, numerator, denominator))
 }
 
 
@@ -646,8 +895,11 @@ pub fn my_cycles_balance() -> u64 {
 }
 
 #[query]
-pub fn my_canister_config(token: Currency) -> Principal {
-    token_id_by_currency(token.clone())
+pub fn my_canister_config(token: Currency) -> Result<Principal, String> {
+    match token {
+        Currency::ICP => Ok(get_state().icp_canister),
+        token => token_id_by_currency(token),
+    }
 }
 
 #[query]
@@ -699,39 +951,492 @@ pub async fn use_black_hole_cycles_balance(canister_id:Principal) -> Nat {
 
 
 
+// 查询兑换价格 [每个 take 需要的 give, 整币单位] 同时返回精确的分数
 #[update]
-pub async fn get_swap_price(give_currency: Currency, take_currency: Currency) -> f64 {
-    let give_token = token_id_by_currency(give_currency.clone());
-    let take_token = token_id_by_currency(take_currency.clone());
+pub async fn get_swap_price(give_currency: Currency, take_currency: Currency) -> Result<SwapPrice, String> {
+    let give_token = token_id_by_currency(give_currency)?;
+    let take_token = token_id_by_currency(take_currency)?;
+
+    let (path, mut numerator, mut denominator) = get_swap_price_internal(give_token, take_token).await?;
+
+    // 整币价格 = 原始价格 × 10^(take 精度 - give 精度)
+    let give_decimals = token_decimals(&give_token).await?;
+    let take_decimals = token_decimals(&take_token).await?;
+    if take_decimals >= give_decimals {
+        numerator *= pow(BigUint::from(10u32), (take_decimals - give_decimals) as usize);
+    } else {
+        denominator *= pow(BigUint::from(10u32), (give_decimals - take_decimals) as usize);
+    }
+    let divisor = gcd(numerator.clone(), denominator.clone());
+    numerator /= &divisor;
+    denominator /= &divisor;
+
+    let price = BigDecimal::from(BigInt::from(numerator.clone())) / BigDecimal::from(BigInt::from(denominator.clone()));
+    Ok(SwapPrice {
+        price: price.to_f64().unwrap_or(0f64),
+        numerator: Nat(numerator),
+        denominator: Nat(denominator),
+        path,
+    })
+}
 
-    let price_bd = get_swap_price_internal(give_currency, take_currency).await;
+// 原始单位的价格按两种代币的精度换算为整币价格
+async fn to_display_price(give_token: Principal, take_token: Principal, price_bd: BigDecimal) -> Result<f64, String> {
+    let give_decimals = token_decimals(&give_token).await?;
+    let take_decimals = token_decimals(&take_token).await?;
+    // BigDecimal::new(1, scale) = 10^-scale
+    let decimals_modifier = BigDecimal::new(BigInt::from(1), give_decimals as i64 - take_decimals as i64);
+    Ok((price_bd * decimals_modifier).to_f64().unwrap_or(0f64))
+}
 
-    let (give_token_decimals,) = Dip20::decimals(&give_token)
-        .await
-        .expect("Unable to fetch give_token decimals");
+// 代币精度 [使用元数据缓存]
+async fn token_decimals(token: &Principal) -> Result<u8, String> {
+    tokens::metadata(token).await.map(|metadata| metadata.decimals)
+}
 
-    let (take_token_decimals,) = Dip20::decimals(&take_token)
-        .await
-        .expect("Unable to fetch take_token decimals");
+fn gcd(mut a: BigUint, mut b: BigUint) -> BigUint {
+    let zero = BigUint::from(0u32);
+    while b != zero {
+        let r = &a % &b;
+        a = b;
+        b = r;
+    }
+    a
+}
 
-    let decimals_dif =
-        give_token_decimals.to_i32().unwrap() - take_token_decimals.to_i32().unwrap();
+// 代币对应的罐 [服务罐不是代币 返回错误]
+// ICP 账本不是 Sonic 上的代币 按 1:1 包装的 WICP 报价和兑换, 转入转出时由 wrap 包装和解包
+fn token_id_by_currency(currency: Currency) -> Result<Principal, String> {
+    let state = get_state();
 
-    let decimals_modifier = 10f64.pow(decimals_dif);
+    match currency {
+        Currency::XTC => Ok(state.xtc_canister),
+        Currency::WICP | Currency::ICP => Ok(state.wicp_canister),
+        Currency::Token(canister_id) => tokens::enabled(canister_id),
+        Currency::SONIC | Currency::NnsCyclesMinting | Currency::BlackHole => Err(format!("{:?} is not a token", currency)),  // 不是代币
+    }
+}
+
+// -------------------- ADMIN ---------------------
 
-    price_bd.to_f64().unwrap() * decimals_modifier
+pub fn is_admin(principal: &Principal) -> bool {
+    ADMINS.with(|admins| admins.borrow().contains(principal))
 }
 
-fn token_id_by_currency(currency: Currency) -> Principal {
-    let state = get_state();
+// ic-cdk 0.6 无法查询控制者列表, 以安装或升级本罐的调用者为准 [只有控制者能安装和升级]
+// 每次升级只保留本次升级的调用者, 已被移除的控制者不会继续有效
+pub fn is_controller(principal: &Principal) -> bool {
+    CONTROLLERS.with(|controllers| controllers.borrow().contains(principal))
+}
 
-    match currency {
-        Currency::XTC => state.xtc_canister,
-        Currency::WICP => state.wicp_canister,
-        Currency::ICP => state.icp_canister,
-        Currency::SONIC => state.sonic_swap_canister,
-        Currency::NnsCyclesMinting => state.nns_cycles_minting_canister,
-        Currency::BlackHole => state.black_hole_canister,
+fn record_controller() {
+    CONTROLLERS.with(|controllers| *controllers.borrow_mut() = BTreeSet::from([ic_cdk::api::caller()]));
+}
+
+// 查询管理员集合
+#[query(guard = "admin_guard")]
+pub fn get_admins() -> Vec<Principal> {
+    ADMINS.with(|admins| admins.borrow().iter().cloned().collect())
+}
+
+// 添加管理员 [管理员或控制者]
+#[update(guard = "admin_or_controller_guard")]
+pub fn add_admin(admin: Principal) -> String {
+    ADMINS.with(|admins|{
+        if admins.borrow_mut().insert(admin) {
+            String::from("admin added successfully")  // 管理员添加成功
+        } else {
+            String::from("admin already exists")  // 管理员已存在
+        }
+    })
+}
+
+// 移除管理员 [管理员或控制者 至少保留一个管理员]
+#[update(guard = "admin_or_controller_guard")]
+pub fn remove_admin(admin: Principal) -> String {
+    ADMINS.with(|admins|{
+        let mut admins = admins.borrow_mut();
+        if !admins.contains(&admin) {
+            String::from("admin does not exist")  // 管理员不存在
+        } else if admins.len() == 1 {
+            String::from("cannot remove the last admin")  // 不能移除最后一个管理员
+        } else {
+            admins.remove(&admin);
+            String::from("admin removed successfully")  // 管理员移除成功
+        }
+    })
+}
+
+// 查询运行配置
+#[query]
+pub fn get_config() -> Config {
+    CONFIG.with(|config| config.borrow().clone().expect("Config is not initialized"))
+}
+
+// 修改外部依赖罐 [本地测试时指向本地替身罐]
+#[update(guard = "admin_guard")]
+pub fn set_external_canister(target: ExternalCanister, canister_id: Principal) -> State {
+    CONFIG.with(|config|{
+        let mut config = config.borrow_mut();
+        let config = config.as_mut().expect("Config is not initialized");
+        apply_external_canister(&mut config.state, target, canister_id);
+        config.state
+    })
+}
+
+// 修改轮训配置
+#[update(guard = "admin_guard")]
+pub fn set_polling_config(polling: PollingConfig) -> PollingConfig {
+    CONFIG.with(|config|{
+        config.borrow_mut().as_mut().expect("Config is not initialized").polling = polling;
+    });
+    schedule_polling();
+    polling
+}
+
+// 修改余额来源
+#[update(guard = "admin_guard")]
+pub fn set_balance_source(balance_source: BalanceSource) -> BalanceSource {
+    CONFIG.with(|config|{
+        config.borrow_mut().as_mut().expect("Config is not initialized").balance_source = balance_source;
+    });
+    balance_source
+}
+
+// 修改允许的最大价格影响 万分之 [0 为不限制]
+#[update(guard = "admin_guard")]
+pub fn set_max_price_impact(max_price_impact_bps: u64) -> u64 {
+    CONFIG.with(|config|{
+        config.borrow_mut().as_mut().expect("Config is not initialized").max_price_impact_bps = max_price_impact_bps;
+    });
+    max_price_impact_bps
+}
+
+// 修改限价单使用的 TWAP 窗口 秒
+#[update(guard = "admin_guard")]
+pub fn set_twap_window(twap_window: u64) -> u64 {
+    let twap_window = twap_window.clamp(1, oracle::MAX_TWAP_WINDOW);
+    CONFIG.with(|config|{
+        config.borrow_mut().as_mut().expect("Config is not initialized").twap_window = twap_window;
+    });
+    twap_window
+}
+
+// 修改是否在本罐 cycles 不足时用 ICP 充值
+#[update(guard = "admin_guard")]
+pub fn set_top_up_from_icp(top_up_from_icp: bool) -> bool {
+    CONFIG.with(|config|{
+        config.borrow_mut().as_mut().expect("Config is not initialized").top_up_from_icp = top_up_from_icp;
+    });
+    top_up_from_icp
+}
+
+fn apply_external_canister(state: &mut State, target: ExternalCanister, canister_id: Principal) {
+    match target {
+        ExternalCanister::IcpLedger => state.icp_canister = canister_id,
+        ExternalCanister::XTC => state.xtc_canister = canister_id,
+        ExternalCanister::WICP => state.wicp_canister = canister_id,
+        ExternalCanister::SonicSwap => state.sonic_swap_canister = canister_id,
+        ExternalCanister::NnsCyclesMinting => state.nns_cycles_minting_canister = canister_id,
+        ExternalCanister::BlackHole => state.black_hole_canister = canister_id,
+    }
+}
+
+// -------------------- POLLING ---------------------
+
+// 读取罐余额
+async fn fetch_cycles_balance(canister_id: Principal) -> Result<u64, String> {
+    fetch_canister_status(canister_id).await.map(|status| status.cycles).map_err(|(_, err)| err)
+}
+
+// 读取罐的完整状态 [按照配置的余额来源] 失败时返回原因分类和错误信息
+async fn fetch_canister_status(canister_id: Principal) -> Result<CanisterStatusInfo, (PollFailureCause, String)> {
+    match get_config().balance_source {
+        BalanceSource::BlackHole => {
+            let (status,) = BlackHole::canister_status(&get_state().black_hole_canister, CanisterStatusArg0{canister_id: canister_id})
+                .await
+                .map_err(|(code, msg)| (staleness::classify(code, &msg), format!("{:?}: {}", code, msg)))?;
+            canister_status_info(status).map_err(|err| (PollFailureCause::Other, err))
+        },
+        // 模拟状态 只有余额变化
+        BalanceSource::Mock => Ok(CanisterStatusInfo{
+            status: CanisterRunningStatus::Running,
+            memory_size: 0u64,
+            cycles: generate_random_numbers().await,
+            module_hash: None,
+            controllers: Vec::new(),
+            freezing_threshold: 2_592_000u64,
+            memory_allocation: 0u64,
+            compute_allocation: 0u64,
+            updtime: time(),
+        }),
+    }
+}
+
+fn canister_status_info(status: CanisterStatus) -> Result<CanisterStatusInfo, String> {
+    let mut controllers = status.settings.controllers;
+    controllers.sort();
+    Ok(CanisterStatusInfo{
+        status: match status.status {
+            canister_status_status::running => CanisterRunningStatus::Running,
+            canister_status_status::stopping => CanisterRunningStatus::Stopping,
+            canister_status_status::stopped => CanisterRunningStatus::Stopped,
+        },
+        memory_size: status.memory_size.0.to_u64().unwrap_or(u64::MAX),
+        cycles: status.cycles.0.to_u64().ok_or_else(|| String::from("cycles balance overflow"))?,
+        module_hash: status.module_hash,
+        controllers,
+        freezing_threshold: status.settings.freezing_threshold.0.to_u64().unwrap_or(u64::MAX),
+        memory_allocation: status.settings.memory_allocation.0.to_u64().unwrap_or(u64::MAX),
+        compute_allocation: status.settings.compute_allocation.0.to_u64().unwrap_or(u64::MAX),
+        updtime: time(),
+    })
+}
+
+// 调用者是否可以查看该罐 [管理员, 或收录该罐的组织的所有者/成员]
+pub fn can_view_canister(caller: &Principal, canister_id: &Principal) -> bool {
+    if is_admin(caller) {
+        return true;
+    }
+    let organizes: Vec<OrganizeName> = CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        match canisters_to_organizes.borrow().get(canister_id) {
+            Some(organizes) => organizes.iter().map(|(_, organize_name)| organize_name.clone()).collect(),
+            None => Vec::new(),
+        }
+    });
+    organizes.iter().any(|organize_name| is_owner_or_member(caller, organize_name))
+}
+
+// 调用者是否是组织的所有者
+pub fn is_owner(caller: &Principal, organize_name: &OrganizeName) -> bool {
+    ORGANIZES_TO_OWNER.with(|organizes_to_owner|{
+        organizes_to_owner.borrow().get(organize_name).is_some_and(|owner| *owner.borrow() == *caller)
+    })
+}
+
+// 调用者是否是组织的所有者或成员
+pub fn is_owner_or_member(caller: &Principal, organize_name: &OrganizeName) -> bool {
+    is_owner(caller, organize_name) || ORGANIZES_TO_MEMBERS.with(|organizes_to_members|{
+        organizes_to_members.borrow().get(organize_name).is_some_and(|members| members.borrow().contains_key(caller))
+    })
+}
+
+// 记录轮训结果 同步更新公共罐及所有收录该罐的组织罐, 并写入余额历史
+fn record_canister_balance(canister_id: Principal, updtime: u64, cycles_balance: u64, memory_size: u64) {
+    history::record_sample(canister_id, updtime, cycles_balance, memory_size);
+    PUBLIC_CANISTERS.with(|public_canisters|{
+        if let Some(public_canister) = public_canisters.borrow().get(&canister_id) {
+            public_canister.updtime.set(updtime);
+            public_canister.cycles_balance.set(cycles_balance);
+        }
+    });
+    let organizes: Vec<OrganizeName> = CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        match canisters_to_organizes.borrow().get(&canister_id) {
+            Some(organizes) => organizes.iter().map(|(_, organize_name)| organize_name.clone()).collect(),
+            None => Vec::new(),
+        }
+    });
+    ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        for organize_name in organizes.iter() {
+            if let Some(canisters) = organizes_to_canisters.borrow().get(organize_name) {
+                if let Some(canister_info) = canisters.borrow().get(&canister_id) {
+                    canister_info.borrow().updtime.set(updtime);
+                    canister_info.borrow().cycles_balance.set(cycles_balance);
+                }
+            }
+        }
+    });
+}
+
+// 正在轮训的标记 离开作用域时释放 [回调 trap 时由 cleanup 释放, 不会一直占用]
+struct PollingGuard(Principal);
+
+impl PollingGuard {
+    fn acquire(canister_id: Principal) -> Option<PollingGuard> {
+        POLLING_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(canister_id))
+            .then_some(PollingGuard(canister_id))
+    }
+}
+
+impl Drop for PollingGuard {
+    fn drop(&mut self) {
+        POLLING_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.0));
+    }
+}
+
+// 轮训一个罐 同一个罐同时只有一个轮训
+async fn poll_canister(canister_id: Principal) {
+    let _guard = match PollingGuard::acquire(canister_id) {
+        Some(guard) => guard,
+        None => return,
+    };
+    match fetch_canister_status(canister_id).await {
+        Ok(status) => {
+            let cycles_balance = status.cycles;
+            record_canister_balance(canister_id, status.updtime, cycles_balance, status.memory_size);
+            status::record_status(canister_id, status);
+            apply_dynamic_minimums(canister_id);
+            staleness::record_success(canister_id);
+            alerts::resolve_matching(None, &canister_id, |kind| matches!(kind, AlertKind::PollFailed{..}));
+            anomaly::check_canister(canister_id);
+            if let Some(target) = thresholds::evaluate(canister_id, cycles_balance) {
+                top_up_to(canister_id, cycles_balance, target).await;
+            }
+        },
+        Err((cause, err)) => {
+            ic_cdk::println!("poll {} failed: {}", canister_id, err);
+            staleness::record_failure(canister_id, cause, err.clone());
+            raise_poll_failed(canister_id, err);
+        },
+    }
+}
+
+// 读取失败告警 同一组织下未解决的读取失败告警只保留一条
+fn raise_poll_failed(canister_id: Principal, error: String) {
+    let organizes: Vec<OrganizeName> = CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        canisters_to_organizes.borrow().get(&canister_id)
+            .map_or(Vec::new(), |organizes| organizes.iter().map(|(_, organize_name)| organize_name.clone()).collect())
+    });
+    for organize_name in organizes {
+        if !alerts::has_unresolved(&organize_name, &canister_id, |kind| matches!(kind, AlertKind::PollFailed{..})) {
+            alerts::raise_alert(organize_name, canister_id, AlertKind::PollFailed{ error: error.clone() });
+        }
+    }
+}
+
+// 按照最新的状态 将以 "高于冻结点 N 天" 表示的最低Cycles 重新换算成 cycles, 并同步索引和公共罐
+fn apply_dynamic_minimums(canister_id: Principal) {
+    let mut changed: Vec<(OrganizeName, u64)> = Vec::new();
+    ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        for (organize_name, canisters) in organizes_to_canisters.borrow().iter() {
+            if let Some(canister_info) = canisters.borrow().get(&canister_id) {
+                let canister_info = canister_info.borrow();
+                let days = match canister_info.minimum_days_above_freeze.get() {
+                    Some(days) => days,
+                    None => continue,
+                };
+                if let Some(cycles_minimum) = forecast::minimum_for_days_above_freeze(&canister_id, days) {
+                    if cycles_minimum != canister_info.cycles_minimum.get() {
+                        canister_info.cycles_minimum.set(cycles_minimum);
+                        changed.push((organize_name.clone(), cycles_minimum));
+                    }
+                }
+            }
+        }
+    });
+    if changed.is_empty() {
+        return;
+    }
+    for (organize_name, cycles_minimum) in changed {
+        canister_mapping_organization_deal_with(Opts::UPDATE, canister_id, organize_name, cycles_minimum);
+    }
+    recompute_public_canisters(canister_id, None);
+}
+
+const TOP_UP_RESERVE: u64 = 1_000_000_000_000;  // 自动充值后本罐至少保留的 cycles
+
+// 从本罐充值到目标余额 [被未确认的异常告警暂停时跳过]
+// 目标至少为各组织最低Cycles中最高的一个, 避免充值后仍低于某个组织的最低Cycles
+// 本罐 cycles 不够时 若开启 top_up_from_icp 则剩余部分用 ICP 充值
+async fn top_up_to(canister_id: Principal, cycles_balance: u64, target: u64) {
+    let target = target.max(highest_threshold_organization(&canister_id).map_or(0, |(cycles_minimum, _)| cycles_minimum));
+    let shortfall = target.saturating_sub(cycles_balance);
+    if anomaly::top_ups_paused(&canister_id) {
+        // 余额已低于所有组织的最低Cycles 暂停充值需要告知
+        if lowest_threshold_organization(&canister_id).is_some_and(|(cycles_minimum, _)| cycles_balance < cycles_minimum) {
+            raise_top_up_blocked(canister_id, shortfall, String::from("top-ups are paused until the anomaly alert is acknowledged"));
+        }
+        return;
+    }
+    let amount = shortfall.min(canister_balance().saturating_sub(TOP_UP_RESERVE));
+    if amount > 0 {
+        match deposit_cycles(CanisterIdRecord{canister_id}, amount as u128).await {
+            Ok(()) => {
+                ic_cdk::println!("topped up {} with {} cycles", canister_id, amount);
+                alerts::raise_canister_alert(canister_id, AlertKind::TopUpSucceeded{ amount });
+            },
+            Err((code, msg)) => {
+                ic_cdk::println!("top up {} failed: {:?}: {}", canister_id, code, msg);
+                alerts::raise_canister_alert(canister_id, AlertKind::TopUpFailed{ amount, error: format!("{:?}: {}", code, msg) });
+                return;
+            },
+        }
+    }
+    let remainder = shortfall - amount;
+    let from_icp = CONFIG.with(|config| config.borrow().as_ref().is_some_and(|config| config.top_up_from_icp));
+    if remainder == 0 || !from_icp {
+        return;
+    }
+    match icp_top_up::top_up_with_icp(canister_id, remainder).await {
+        Ok(amount) => {
+            ic_cdk::println!("topped up {} with {} cycles from ICP", canister_id, amount);
+            alerts::raise_canister_alert(canister_id, AlertKind::TopUpSucceeded{ amount });
+        },
+        Err(error) => {
+            ic_cdk::println!("top up {} from ICP failed: {}", canister_id, error);
+            alerts::raise_canister_alert(canister_id, AlertKind::TopUpFailed{ amount: remainder, error });
+        },
+    }
+}
+
+// 无法充值的告警 同一组织下未解决的充值失败告警只保留一条
+fn raise_top_up_blocked(canister_id: Principal, amount: u64, error: String) {
+    let organizes: Vec<OrganizeName> = CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        canisters_to_organizes.borrow().get(&canister_id)
+            .map_or(Vec::new(), |organizes| organizes.iter().map(|(_, organize_name)| organize_name.clone()).collect())
+    });
+    for organize_name in organizes {
+        if !alerts::has_unresolved(&organize_name, &canister_id, |kind| matches!(kind, AlertKind::TopUpFailed{..})) {
+            alerts::raise_alert(organize_name, canister_id, AlertKind::TopUpFailed{ amount, error: error.clone() });
+        }
+    }
+}
+
+// 轮训所有到期的公共罐 [距上次更新超过 time_interval 秒]
+fn poll_due_canisters() {
+    staleness::check_stale();
+    let now = time();
+    let due: Vec<Principal> = PUBLIC_CANISTERS.with(|public_canisters|{
+        public_canisters.borrow().iter()
+            .filter(|(_, public_canister)| {
+                now.saturating_sub(public_canister.updtime.get()) >= public_canister.time_interval.get().saturating_mul(1_000_000_000)
+            })
+            .map(|(canister_id, _)| *canister_id)
+            .collect()
+    });
+    for canister_id in due {
+        ic_cdk::spawn(poll_canister(canister_id));
+    }
+}
+
+// 按照轮训配置 (重新) 注册轮训定时任务
+fn schedule_polling() {
+    if let Some(task_id) = POLL_TASK.with(|task| task.take()) {
+        cron_dequeue(task_id);
+    }
+    let interval_nano = get_config().polling.tick_interval.max(1).saturating_mul(1_000_000_000);
+    let task_id = cron_enqueue(
+        CronTaskKind::PollCanisters,
+        SchedulingOptions {
+            delay_nano: interval_nano,
+            interval_nano,
+            iterations: Iterations::Infinite,
+        },
+    ).expect("Unable to schedule polling");
+    POLL_TASK.with(|task| task.set(Some(task_id)));
+}
+
+#[heartbeat]
+pub fn tick() {
+    for task in cron_ready_tasks() {
+        let kind = task.get_payload::<CronTaskKind>().expect("Unable to decode cron task");
+        match kind {
+            CronTaskKind::PollCanisters => poll_due_canisters(),
+            CronTaskKind::DeliverWebhooks => webhooks::deliver_due(),
+            CronTaskKind::EvaluateLimitOrders => orders::evaluate_open_orders(),
+            CronTaskKind::RecordPrices => oracle::record_observations(),
+            CronTaskKind::RefreshTokenMetadata => tokens::refresh_stale_metadata(),
+        }
     }
 }
 
@@ -747,24 +1452,551 @@ pub struct State {
     pub black_hole_canister: Principal,
 }
 
-pub static mut STATE: Option<State> = None;
+// 运行配置
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Config {
+    pub profile: Profile,
+    pub state: State,
+    pub polling: PollingConfig,
+    pub balance_source: BalanceSource,
+    #[serde(default = "default_max_price_impact_bps")]
+    pub max_price_impact_bps: u64,  // 允许的最大价格影响 万分之 [0 为不限制]
+    #[serde(default = "default_twap_window")]
+    pub twap_window: u64,  // 限价单使用的 TWAP 窗口 秒
+    #[serde(default)]
+    pub top_up_from_icp: bool,  // 本罐 cycles 不足时 用 ICP [不足时解包 WICP] 通过 cycles minting 罐充值剩余部分
+}
+
+fn default_max_price_impact_bps() -> u64 {
+    500
+}
+
+fn default_twap_window() -> u64 {
+    1_800
+}
+
+pub fn get_state() -> State {
+    CONFIG.with(|config| config.borrow().as_ref().expect("Config is not initialized").state)
+}
+
+// 主网服务罐 [本地 replica 的 ledger 与 cycles minting 罐与主网同 ID, 其余由参数中的替身罐覆盖]
+fn mainnet_state() -> State {
+    State {
+        icp_canister: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+        xtc_canister: Principal::from_text("aanaa-xaaaa-aaaah-aaeiq-cai").unwrap(),
+        wicp_canister: Principal::from_text("utozz-siaaa-aaaam-qaaxq-cai").unwrap(),
+        sonic_swap_canister: Principal::from_text("3xwpq-ziaaa-aaaah-qcn4a-cai").unwrap(),
+        nns_cycles_minting_canister: Principal::from_text("rkp4c-7iaaa-aaaaa-aaaca-cai").unwrap(),
+        black_hole_canister: Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap(),
+    }
+}
 
-pub fn get_state() -> &'static State {
-    unsafe { STATE.as_ref().unwrap() }
+// 各部署环境的默认配置
+fn profile_config(profile: Profile) -> Config {
+    match profile {
+        Profile::Local => Config {
+            profile,
+            state: mainnet_state(),
+            polling: PollingConfig { tick_interval: 10, default_time_interval: 60, stale_after_intervals: 3 },
+            balance_source: BalanceSource::Mock,
+            max_price_impact_bps: 5_000,
+            twap_window: 300,
+            top_up_from_icp: false,
+        },
+        Profile::Testnet => Config {
+            profile,
+            state: mainnet_state(),
+            polling: PollingConfig { tick_interval: 30, default_time_interval: 600, stale_after_intervals: 3 },
+            balance_source: BalanceSource::BlackHole,
+            max_price_impact_bps: 1_000,
+            twap_window: 900,
+            top_up_from_icp: false,
+        },
+        Profile::Mainnet => Config {
+            profile,
+            state: mainnet_state(),
+            polling: PollingConfig { tick_interval: 60, default_time_interval: 3600, stale_after_intervals: 3 },
+            balance_source: BalanceSource::BlackHole,
+            max_price_impact_bps: 500,
+            twap_window: 1_800,
+            top_up_from_icp: false,
+        },
+    }
 }
 
+// 本地 replica 上没有的服务罐 Local profile 必须在参数中指定本地替身罐
+const LOCAL_STAND_INS: [ExternalCanister; 4] = [ExternalCanister::XTC, ExternalCanister::WICP, ExternalCanister::SonicSwap, ExternalCanister::BlackHole];
+
+// 按 profile 生成默认配置 [Local 缺少替身罐时中止, 避免指向主网罐]
+fn profile_config_for(profile: Profile, args: &InitArgs) -> Config {
+    if profile == Profile::Local {
+        if let Err(err) = check_local_stand_ins(args) {
+            ic_cdk::trap(&err);
+        }
+    }
+    profile_config(profile)
+}
+
+// Local profile 的参数必须为每个本地 replica 上没有的服务罐指定替身罐
+fn check_local_stand_ins(args: &InitArgs) -> Result<(), String> {
+    let given: Vec<ExternalCanister> = args.external_canisters.iter().flatten().map(|(target, _)| *target).collect();
+    let missing: Vec<String> = LOCAL_STAND_INS.iter()
+        .filter(|target| !given.contains(target))
+        .map(|target| format!("ExternalCanister::{:?}", target))
+        .collect();
+    match missing.as_slice() {
+        [] => Ok(()),
+        [target] => Err(format!("Local profile is missing a stand-in canister for {}; pass it in external_canisters", target)),
+        _ => Err(format!("Local profile is missing stand-in canisters for {}; pass them in external_canisters", missing.join(", "))),
+    }
+}
+
+// 将参数应用到配置上 未设置的项保持不变
+fn apply_init_args(config: &mut Config, args: InitArgs) {
+    for (target, canister_id) in args.external_canisters.unwrap_or_default() {
+        apply_external_canister(&mut config.state, target, canister_id);
+    }
+    if let Some(polling) = args.polling {
+        config.polling = polling;
+    }
+    if let Some(balance_source) = args.balance_source {
+        config.balance_source = balance_source;
+    }
+    ADMINS.with(|admins| admins.borrow_mut().extend(args.admins.unwrap_or_default()));
+}
+
+// 安装者 [罐的控制者] 默认为管理员, 未指定 profile 时为主网
 #[init]
-pub fn init() {
-    unsafe {
-        STATE = Some(State {
-            icp_canister: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
-            xtc_canister: Principal::from_text("aanaa-xaaaa-aaaah-aaeiq-cai").unwrap(),
-            wicp_canister: Principal::from_text("utozz-siaaa-aaaam-qaaxq-cai").unwrap(),
-            sonic_swap_canister: Principal::from_text("3xwpq-ziaaa-aaaah-qcn4a-cai").unwrap(),
-            nns_cycles_minting_canister: Principal::from_text("rkp4c-7iaaa-aaaaa-aaaca-cai").unwrap(),
-            black_hole_canister: Principal::from_text("e3mmv-5qaaa-aaaah-aadma-cai").unwrap(),
-        })
+pub fn init(args: Option<InitArgs>) {
+    record_controller();
+    ADMINS.with(|admins| admins.borrow_mut().insert(ic_cdk::api::caller()));
+    let args = args.unwrap_or(InitArgs {
+        profile: None,
+        admins: None,
+        external_canisters: None,
+        polling: None,
+        balance_source: None,
+    });
+    let mut config = profile_config_for(args.profile.unwrap_or(Profile::Mainnet), &args);
+    apply_init_args(&mut config, args);
+    CONFIG.with(|c| *c.borrow_mut() = Some(config));
+    schedule_polling();
+    webhooks::schedule_delivery();
+    orders::schedule_evaluation();
+    oracle::schedule_observations();
+    tokens::schedule_metadata_refresh();
+}
+
+// -------------------- UPGRADE ---------------------
+
+// 升级时保存到稳定内存的状态
+#[derive(CandidType, Deserialize)]
+struct StableState {
+    config: Config,
+    admins: BTreeSet<Principal>,
+    organizes_to_owner: OrganizesToOwner,
+    organizes_to_members: OrganizesToMembers,
+    organizes_to_canisters: OrganizesToCanisters,
+    public_canisters: PublicCanisters,
+    canisters_to_organizes: CanistersToOrganizes,
+    #[serde(default)]
+    cycles_history: BTreeMap<Principal, history::CyclesHistory>,
+    #[serde(default)]
+    alerts: alerts::AlertsStable,
+    #[serde(default)]
+    anomaly: anomaly::AnomalyStable,
+    #[serde(default)]
+    canister_statuses: BTreeMap<Principal, CanisterStatusInfo>,
+    #[serde(default)]
+    thresholds: thresholds::ThresholdsStable,
+    #[serde(default)]
+    webhooks: webhooks::WebhooksStable,
+    #[serde(default)]
+    subscriptions: subscriptions::SubscriptionsStable,
+    #[serde(default)]
+    staleness: staleness::StalenessStable,
+    #[serde(default)]
+    orders: orders::OrdersStable,
+    #[serde(default)]
+    swap: swap::SwapStable,
+    #[serde(default)]
+    oracle: oracle::OracleStable,
+    #[serde(default)]
+    tokens: tokens::TokensStable,
+    #[serde(default)]
+    wrap: wrap::WrapStable,
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let stable_state = StableState {
+        config: get_config(),
+        admins: ADMINS.with(|admins| admins.borrow().clone()),
+        organizes_to_owner: ORGANIZES_TO_OWNER.with(|m| m.borrow().clone()),
+        organizes_to_members: ORGANIZES_TO_MEMBERS.with(|m| m.borrow().clone()),
+        organizes_to_canisters: ORGANIZES_TO_CANISTERS.with(|m| m.borrow().clone()),
+        public_canisters: PUBLIC_CANISTERS.with(|m| m.borrow().clone()),
+        canisters_to_organizes: CANISTERS_TO_ORGANIZES.with(|m| m.borrow().clone()),
+        cycles_history: history::snapshot(),
+        alerts: alerts::snapshot(),
+        anomaly: anomaly::snapshot(),
+        canister_statuses: status::snapshot(),
+        thresholds: thresholds::snapshot(),
+        webhooks: webhooks::snapshot(),
+        subscriptions: subscriptions::snapshot(),
+        staleness: staleness::snapshot(),
+        orders: orders::snapshot(),
+        swap: swap::snapshot(),
+        oracle: oracle::snapshot(),
+        tokens: tokens::snapshot(),
+        wrap: wrap::snapshot(),
+    };
+    stable_save((stable_state,)).expect("Unable to save state to stable memory");
+}
+
+// 升级参数中设置的项覆盖升级前的配置, 指定 profile 时先重置为该 profile 的默认配置
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    record_controller();
+    let mut config = match stable_restore::<(StableState,)>() {
+        Ok((stable_state,)) => {
+            ADMINS.with(|m| *m.borrow_mut() = stable_state.admins);
+            ORGANIZES_TO_OWNER.with(|m| *m.borrow_mut() = stable_state.organizes_to_owner);
+            ORGANIZES_TO_MEMBERS.with(|m| *m.borrow_mut() = stable_state.organizes_to_members);
+            ORGANIZES_TO_CANISTERS.with(|m| *m.borrow_mut() = stable_state.organizes_to_canisters);
+            PUBLIC_CANISTERS.with(|m| *m.borrow_mut() = stable_state.public_canisters);
+            CANISTERS_TO_ORGANIZES.with(|m| *m.borrow_mut() = stable_state.canisters_to_organizes);
+            history::restore(stable_state.cycles_history);
+            alerts::restore(stable_state.alerts);
+            anomaly::restore(stable_state.anomaly);
+            status::restore(stable_state.canister_statuses);
+            thresholds::restore(stable_state.thresholds);
+            webhooks::restore(stable_state.webhooks);
+            subscriptions::restore(stable_state.subscriptions);
+            staleness::restore(stable_state.staleness);
+            orders::restore(stable_state.orders);
+            swap::restore(stable_state.swap);
+            oracle::restore(stable_state.oracle);
+            tokens::restore(stable_state.tokens);
+            wrap::restore(stable_state.wrap);
+            stable_state.config
+        },
+        // 升级前的版本没有保存状态 按照安装处理
+        Err(_) if ic_cdk::api::stable::stable_size() == 0 => {
+            ADMINS.with(|admins| admins.borrow_mut().insert(ic_cdk::api::caller()));
+            profile_config(Profile::Mainnet)
+        },
+        // 已保存的状态无法解码时中止升级, 避免清空数据
+        Err(err) => ic_cdk::trap(&format!("Unable to restore state from stable memory: {}", err)),
+    };
+    if let Some(args) = args {
+        if let Some(profile) = args.profile {
+            config = profile_config_for(profile, &args);
+        }
+        apply_init_args(&mut config, args);
     }
+    CONFIG.with(|c| *c.borrow_mut() = Some(config));
+    schedule_polling();
+    webhooks::schedule_delivery();
+    orders::schedule_evaluation();
+    oracle::schedule_observations();
+    tokens::schedule_metadata_refresh();
 }
 
 implement_cron!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canister(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn add_owner(organize_name: &str) {
+        ORGANIZES_TO_OWNER.with(|m| m.borrow_mut().insert(String::from(organize_name), RefCell::new(Principal::anonymous())));
+    }
+
+    // 只写入组织罐 不维护其他映射
+    fn add_canister(organize_name: &str, canister_id: Principal, time_interval: u64, cycles_minimum: u64, cycles_highest: u64) {
+        ORGANIZES_TO_CANISTERS.with(|m|{
+            m.borrow_mut().entry(String::from(organize_name)).or_default().get_mut().insert(canister_id, RefCell::new(CanisterInfo{
+                nickname: String::new(),
+                instime: Cell::new(0),
+                updtime: Cell::new(0),
+                cycles_balance: Cell::new(0),
+                time_interval: Cell::new(time_interval),
+                cycles_minimum: Cell::new(cycles_minimum),
+                cycles_highest: Cell::new(cycles_highest),
+                status: None,
+                minimum_days_above_freeze: Cell::new(None),
+                thresholds: None,
+                degraded: None,
+            }));
+        });
+    }
+
+    // 一致的状态: 组织 a 和 b 都收录罐 1, 只有 a 收录罐 2
+    fn consistent_state() {
+        add_owner("a");
+        add_owner("b");
+        for (organize_name, canister_id, time_interval, cycles_minimum, cycles_highest) in [("a", canister(1), 60, 100, 1_000), ("b", canister(1), 30, 200, 500), ("a", canister(2), 60, 300, 900)] {
+            add_canister(organize_name, canister_id, time_interval, cycles_minimum, cycles_highest);
+            canister_mapping_organization_deal_with(Opts::ADD, canister_id, String::from(organize_name), cycles_minimum);
+            recompute_public_canisters(canister_id, Some((1, 5_000)));
+        }
+    }
+
+    // 破坏状态后 检查能发现该违规, 修复后不再有违规
+    fn assert_repaired(corrupt: fn(), detected: fn(&StateViolation) -> bool) {
+        consistent_state();
+        assert!(check_state_invariants().is_empty());
+        corrupt();
+        let violations = check_state_invariants();
+        assert!(violations.iter().any(detected), "{:?}", violations);
+        assert_eq!(repair_state_invariants().len(), violations.len());
+        assert!(check_state_invariants().is_empty(), "{:?}", check_state_invariants());
+        assert!(repair_state_invariants().is_empty());
+    }
+
+    #[test]
+    fn members_without_owner_are_removed() {
+        assert_repaired(
+            || { ORGANIZES_TO_MEMBERS.with(|m| m.borrow_mut().insert(String::from("ghost"), RefCell::default())); },
+            |violation| matches!(violation, StateViolation::MembersWithoutOwner(name) if name == "ghost"),
+        );
+        assert!(ORGANIZES_TO_MEMBERS.with(|m| m.borrow().is_empty()));
+    }
+
+    #[test]
+    fn canisters_without_owner_are_removed() {
+        assert_repaired(
+            || add_canister("ghost", canister(3), 60, 1, 2),
+            |violation| matches!(violation, StateViolation::CanistersWithoutOwner(name) if name == "ghost"),
+        );
+        assert!(!ORGANIZES_TO_CANISTERS.with(|m| m.borrow().contains_key("ghost")));
+        assert!(!PUBLIC_CANISTERS.with(|m| m.borrow().contains_key(&canister(3))));
+    }
+
+    #[test]
+    fn missing_canister_mapping_is_rebuilt() {
+        assert_repaired(
+            || add_canister("b", canister(2), 60, 50, 900),
+            |violation| matches!(violation, StateViolation::MissingCanisterMapping{ organize_name, .. } if organize_name == "b"),
+        );
+        assert_eq!(lowest_threshold_organization(&canister(2)), Some((50, String::from("b"))));
+    }
+
+    #[test]
+    fn duplicate_canister_mapping_is_rebuilt() {
+        assert_repaired(
+            || { CANISTERS_TO_ORGANIZES.with(|m| m.borrow_mut().get_mut(&canister(1)).unwrap().insert((999, String::from("a")))); },
+            |violation| matches!(violation, StateViolation::DuplicateCanisterMapping{ organize_name, .. } if organize_name == "a"),
+        );
+        assert_eq!(highest_threshold_organization(&canister(1)), Some((200, String::from("b"))));
+    }
+
+    #[test]
+    fn stale_canister_mapping_is_removed() {
+        assert_repaired(
+            || { CANISTERS_TO_ORGANIZES.with(|m| m.borrow_mut().get_mut(&canister(2)).unwrap().insert((5, String::from("b")))); },
+            |violation| matches!(violation, StateViolation::StaleCanisterMapping{ organize_name, .. } if organize_name == "b"),
+        );
+        assert_eq!(lowest_threshold_organization(&canister(2)), Some((300, String::from("a"))));
+    }
+
+    #[test]
+    fn min_cycles_mismatch_is_rebuilt() {
+        assert_repaired(
+            || CANISTERS_TO_ORGANIZES.with(|m| *m.borrow_mut().get_mut(&canister(2)).unwrap() = BTreeSet::from([(1, String::from("a"))])),
+            |violation| matches!(violation, StateViolation::MinCyclesMismatch{ expected: 300, found: 1, .. }),
+        );
+        assert_eq!(lowest_threshold_organization(&canister(2)), Some((300, String::from("a"))));
+    }
+
+    #[test]
+    fn empty_canister_mapping_is_removed() {
+        assert_repaired(
+            || { CANISTERS_TO_ORGANIZES.with(|m| m.borrow_mut().insert(canister(9), BTreeSet::new())); },
+            |violation| matches!(violation, StateViolation::EmptyCanisterMapping(id) if *id == canister(9)),
+        );
+        assert!(!CANISTERS_TO_ORGANIZES.with(|m| m.borrow().contains_key(&canister(9))));
+    }
+
+    #[test]
+    fn missing_public_canister_is_recomputed() {
+        assert_repaired(
+            || { PUBLIC_CANISTERS.with(|m| m.borrow_mut().remove(&canister(2))); },
+            |violation| matches!(violation, StateViolation::MissingPublicCanister(id) if *id == canister(2)),
+        );
+        let cycles_minimum = PUBLIC_CANISTERS.with(|m| m.borrow().get(&canister(2)).map(|public_canister| public_canister.cycles_minimum.get()));
+        assert_eq!(cycles_minimum, Some(300));
+    }
+
+    #[test]
+    fn stale_public_canister_is_removed() {
+        assert_repaired(
+            || { PUBLIC_CANISTERS.with(|m| m.borrow_mut().insert(canister(9), PubilcCanisterInfo{
+                updtime: Cell::new(0),
+                cycles_balance: Cell::new(0),
+                time_interval: Cell::new(1),
+                cycles_minimum: Cell::new(1),
+                cycles_highest: Cell::new(1),
+            })); },
+            |violation| matches!(violation, StateViolation::StalePublicCanister(id) if *id == canister(9)),
+        );
+        assert!(!PUBLIC_CANISTERS.with(|m| m.borrow().contains_key(&canister(9))));
+    }
+
+    #[test]
+    fn public_aggregate_mismatch_is_recomputed_keeping_the_balance() {
+        assert_repaired(
+            || PUBLIC_CANISTERS.with(|m| m.borrow().get(&canister(1)).unwrap().cycles_highest.set(1)),
+            |violation| matches!(violation, StateViolation::PublicAggregateMismatch(id) if *id == canister(1)),
+        );
+        // 轮训间隔取最小 最低Cycles取最低 最高Cycles取最高 已读取的余额保留
+        let public = PUBLIC_CANISTERS.with(|m|{
+            m.borrow().get(&canister(1)).map(|public_canister| (
+                public_canister.time_interval.get(),
+                public_canister.cycles_minimum.get(),
+                public_canister.cycles_highest.get(),
+                public_canister.cycles_balance.get(),
+            ))
+        });
+        assert_eq!(public, Some((30, 100, 1_000, 5_000)));
+    }
+
+    #[test]
+    fn dynamic_minimums_follow_the_freezing_point() {
+        consistent_state();
+        let canister_id = canister(1);
+        let status = CanisterStatusInfo{
+            status: CanisterRunningStatus::Running,
+            memory_size: 1 << 30,
+            cycles: 0,
+            module_hash: None,
+            controllers: Vec::new(),
+            freezing_threshold: 30 * 86_400,
+            memory_allocation: 0,
+            compute_allocation: 0,
+            updtime: 0,
+        };
+        status::restore(BTreeMap::from([(canister_id, status)]));
+        // 只有组织 b 以 "高于冻结点 2 天" 设置最低Cycles
+        ORGANIZES_TO_CANISTERS.with(|m| m.borrow().get("b").unwrap().borrow().get(&canister_id).unwrap().borrow().minimum_days_above_freeze.set(Some(2)));
+        apply_dynamic_minimums(canister_id);
+
+        let expected = 127_000 * 2_592_000 + 2 * 127_000 * 86_400;
+        let cycles_minimum = |organize_name: &str| ORGANIZES_TO_CANISTERS.with(|m| m.borrow().get(organize_name).unwrap().borrow().get(&canister_id).unwrap().borrow().cycles_minimum.get());
+        assert_eq!(cycles_minimum("b"), expected);
+        assert_eq!(cycles_minimum("a"), 100);
+        // 索引和公共罐同步更新
+        assert_eq!(highest_threshold_organization(&canister_id), Some((expected, String::from("b"))));
+        assert!(check_state_invariants().is_empty());
+
+        // 余额消耗加快后重新换算
+        history::record_sample(canister_id, 0, 20 * expected, 0);
+        history::record_sample(canister_id, history::NANOS_PER_DAY, 19 * expected, 0);
+        apply_dynamic_minimums(canister_id);
+        assert_eq!(cycles_minimum("b"), 127_000 * 2_592_000 + 2 * expected);
+        assert!(check_state_invariants().is_empty());
+    }
+
+    fn init_args(profile: Profile, external_canisters: Option<Vec<(ExternalCanister, Principal)>>) -> InitArgs {
+        InitArgs{ profile: Some(profile), admins: None, external_canisters, polling: None, balance_source: None }
+    }
+
+    #[test]
+    fn local_profile_names_the_missing_stand_in() {
+        let stand_ins = vec![(ExternalCanister::XTC, canister(1)), (ExternalCanister::WICP, canister(2)), (ExternalCanister::SonicSwap, canister(3))];
+        assert_eq!(
+            check_local_stand_ins(&init_args(Profile::Local, Some(stand_ins.clone()))),
+            Err(String::from("Local profile is missing a stand-in canister for ExternalCanister::BlackHole; pass it in external_canisters")),
+        );
+        assert_eq!(
+            check_local_stand_ins(&init_args(Profile::Local, Some(stand_ins[..1].to_vec()))),
+            Err(String::from("Local profile is missing stand-in canisters for ExternalCanister::WICP, ExternalCanister::SonicSwap, ExternalCanister::BlackHole; pass them in external_canisters")),
+        );
+        let mut complete = stand_ins;
+        complete.push((ExternalCanister::BlackHole, canister(4)));
+        assert_eq!(check_local_stand_ins(&init_args(Profile::Local, Some(complete))), Ok(()));
+    }
+
+    #[test]
+    fn init_args_override_profile_defaults() {
+        let mut config = profile_config(Profile::Local);
+        let mut args = init_args(Profile::Local, Some(vec![
+            (ExternalCanister::XTC, canister(1)),
+            (ExternalCanister::WICP, canister(2)),
+            (ExternalCanister::SonicSwap, canister(3)),
+            (ExternalCanister::BlackHole, canister(4)),
+        ]));
+        args.admins = Some(vec![canister(5)]);
+        args.balance_source = Some(BalanceSource::BlackHole);
+        apply_init_args(&mut config, args);
+
+        // 指定的服务罐被替换 其余保持主网罐
+        let mainnet = mainnet_state();
+        assert_eq!(config.state.xtc_canister, canister(1));
+        assert_eq!(config.state.wicp_canister, canister(2));
+        assert_eq!(config.state.sonic_swap_canister, canister(3));
+        assert_eq!(config.state.black_hole_canister, canister(4));
+        assert_eq!(config.state.icp_canister, mainnet.icp_canister);
+        assert_eq!(config.state.nns_cycles_minting_canister, mainnet.nns_cycles_minting_canister);
+        // 指定的项覆盖默认值 未指定的保持 profile 默认值
+        assert_eq!(config.balance_source, BalanceSource::BlackHole);
+        assert_eq!(config.polling.tick_interval, 10);
+        assert_eq!(config.polling.default_time_interval, 60);
+        assert_eq!(config.max_price_impact_bps, 5_000);
+        assert_eq!(config.twap_window, 300);
+        assert!(!config.top_up_from_icp);
+        assert!(is_admin(&canister(5)));
+
+        // 升级参数只覆盖指定的项
+        let mut args = init_args(Profile::Mainnet, None);
+        args.profile = None;
+        args.polling = Some(PollingConfig{ tick_interval: 5, default_time_interval: 50, stale_after_intervals: 0 });
+        apply_init_args(&mut config, args);
+        assert_eq!(config.polling.tick_interval, 5);
+        assert_eq!(config.polling.stale_after_intervals, 0);
+        assert_eq!(config.balance_source, BalanceSource::BlackHole);
+        assert_eq!(config.state.xtc_canister, canister(1));
+    }
+
+    #[test]
+    fn threshold_organizations_follow_the_index() {
+        let canister_id = canister(1);
+        // 没有组织收录时为空
+        assert_eq!(lowest_threshold_organization(&canister_id), None);
+        assert_eq!(highest_threshold_organization(&canister_id), None);
+
+        canister_mapping_organization_deal_with(Opts::ADD, canister_id, String::from("b"), 300);
+        canister_mapping_organization_deal_with(Opts::ADD, canister_id, String::from("a"), 100);
+        canister_mapping_organization_deal_with(Opts::ADD, canister_id, String::from("c"), 200);
+        canister_mapping_organization_deal_with(Opts::ADD, canister(2), String::from("d"), 1);
+        assert_eq!(lowest_threshold_organization(&canister_id), Some((100, String::from("a"))));
+        assert_eq!(highest_threshold_organization(&canister_id), Some((300, String::from("b"))));
+
+        // 修改后重新排序
+        canister_mapping_organization_deal_with(Opts::UPDATE, canister_id, String::from("a"), 400);
+        assert_eq!(lowest_threshold_organization(&canister_id), Some((200, String::from("c"))));
+        assert_eq!(highest_threshold_organization(&canister_id), Some((400, String::from("a"))));
+
+        // 删除后由剩余的组织决定 全部删除后移除映射
+        canister_mapping_organization_deal_with(Opts::DELETE, canister_id, String::from("a"), 0);
+        assert_eq!(highest_threshold_organization(&canister_id), Some((300, String::from("b"))));
+        canister_mapping_organization_deal_with(Opts::DELETE, canister_id, String::from("b"), 0);
+        canister_mapping_organization_deal_with(Opts::DELETE, canister_id, String::from("c"), 0);
+        assert_eq!(lowest_threshold_organization(&canister_id), None);
+        assert_eq!(highest_threshold_organization(&canister(2)), Some((1, String::from("d"))));
+    }
+
+    #[test]
+    fn equal_minimums_are_ordered_by_organization() {
+        let canister_id = canister(3);
+        canister_mapping_organization_deal_with(Opts::ADD, canister_id, String::from("y"), 100);
+        canister_mapping_organization_deal_with(Opts::ADD, canister_id, String::from("x"), 100);
+        assert_eq!(lowest_threshold_organization(&canister_id), Some((100, String::from("x"))));
+        assert_eq!(highest_threshold_organization(&canister_id), Some((100, String::from("y"))));
+    }
+}
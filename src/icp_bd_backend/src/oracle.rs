@@ -0,0 +1,283 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+use bigdecimal::num_bigint::BigInt;
+use bigdecimal::BigDecimal;
+use ic_cdk::export::candid::{CandidType, Deserialize, Int, Nat, Principal};
+use ic_cdk_macros::update;
+use ic_cron::types::{Iterations, SchedulingOptions};
+
+use crate::clients::sonic::{Sonic, SonicPairInfo};
+use crate::common::types::{CronTaskKind, Currency};
+use crate::router::{to_decimal, TokenGraph};
+use crate::tokens;
+use crate::{cron_enqueue, get_config, get_state, to_display_price, token_id_by_currency};
+
+const ORACLE_TICK: u64 = 300;  // 记录累计价格的间隔 秒
+pub const MAX_TWAP_WINDOW: u64 = 24 * 3_600;  // 最长 TWAP 窗口 秒
+const PAIR_TIME_UNIT: u64 = 1;  // Sonic 的 blockTimestampLast 为 Time.now() 纳秒
+
+// 交易对的一次观测
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PairObservation {
+    pub time: u64,  // 本罐记录时间 纳秒
+    pub reserve0: Nat,
+    pub reserve1: Nat,
+    pub price0_cumulative_last: Nat,  // 累计 reserve1/reserve0 × 时间
+    pub price1_cumulative_last: Nat,  // 累计 reserve0/reserve1 × 时间
+    pub block_timestamp_last: Int,  // 交易对上次更新累计价格的时间
+}
+
+impl PairObservation {
+    // 外推前检查 储备不能为 0, blockTimestampLast 与本罐时间同为纳秒 [PAIR_TIME_UNIT 的假设]
+    // 秒或毫秒的时间戳比本罐时间小几个数量级, 外推出的时长会错误
+    fn check(&self) -> Result<(), String> {
+        if self.reserve0 == 0u64 || self.reserve1 == 0u64 {
+            return Err(String::from("the pair has no liquidity"));  // 交易对没有流动性
+        }
+        if self.block_timestamp_last.0 <= BigInt::from(self.time / PAIR_TIME_UNIT / 1_000) {
+            return Err(String::from("pair timestamps are not in nanoseconds"));  // 交易对时间戳不是纳秒
+        }
+        Ok(())
+    }
+
+    // 外推到观测时间的累计价格 [交易对上次更新后价格保持不变]
+    // token0_in 为 true 时累计的是每个 token1 需要的 token0
+    fn cumulative(&self, token0_in: bool) -> BigDecimal {
+        let (cumulative_last, reserve_in, reserve_out) = if token0_in {
+            (&self.price1_cumulative_last, &self.reserve0, &self.reserve1)
+        } else {
+            (&self.price0_cumulative_last, &self.reserve1, &self.reserve0)
+        };
+        let now = BigInt::from(self.time / PAIR_TIME_UNIT);
+        let elapsed = now - self.block_timestamp_last.0.clone();
+        let elapsed = if elapsed > BigInt::from(0) { BigDecimal::from(elapsed) } else { BigDecimal::from(0) };
+        to_decimal(&cumulative_last.0) + to_decimal(&reserve_in.0) / to_decimal(&reserve_out.0) * elapsed
+    }
+}
+
+thread_local!{
+    static OBSERVATIONS:RefCell<BTreeMap<(String, String), VecDeque<PairObservation>>> = RefCell::default();  // (token0, token1) -> 按时间先后的观测
+}
+
+// 升级时保存的价格观测
+#[derive(CandidType, Deserialize, Default)]
+pub struct OracleStable {
+    observations: BTreeMap<(String, String), VecDeque<PairObservation>>,
+}
+
+pub fn snapshot() -> OracleStable {
+    OracleStable {
+        observations: OBSERVATIONS.with(|observations| observations.borrow().clone()),
+    }
+}
+
+pub fn restore(stable: OracleStable) {
+    OBSERVATIONS.with(|observations| *observations.borrow_mut() = stable.observations);
+}
+
+// 注册价格观测任务 [安装和升级后调用]
+pub fn schedule_observations() {
+    let interval_nano = ORACLE_TICK * 1_000_000_000;
+    cron_enqueue(
+        CronTaskKind::RecordPrices,
+        SchedulingOptions {
+            delay_nano: interval_nano,
+            interval_nano,
+            iterations: Iterations::Infinite,
+        },
+    ).expect("Unable to schedule price observations");
+}
+
+pub fn record_observations() {
+    ic_cdk::spawn(fetch_and_record());
+}
+
+// 记录包含本服务代币或已登记代币的交易对 [其余交易对只在多跳路径中间出现 不记录]
+async fn fetch_and_record() {
+    let state = get_state();
+    let pairs: Vec<SonicPairInfo> = match Sonic::get_all_pairs(&state.sonic_swap_canister).await {
+        Ok((pairs,)) => pairs,
+        Err((code, msg)) => {
+            ic_cdk::println!("price observation failed: {:?}: {}", code, msg);
+            return;
+        },
+    };
+    let mut tracked = vec![state.xtc_canister.to_text(), state.wicp_canister.to_text()];
+    tracked.extend(tokens::enabled_ids().iter().map(|token| token.to_text()));
+    let now = ic_cdk::api::time();
+    let retention = (MAX_TWAP_WINDOW + 2 * ORACLE_TICK) * 1_000_000_000;
+    OBSERVATIONS.with(|observations|{
+        let mut observations = observations.borrow_mut();
+        for pair in pairs {
+            if !tracked.contains(&pair.token0) && !tracked.contains(&pair.token1) {
+                continue;
+            }
+            observations.entry((pair.token0, pair.token1)).or_default().push_back(PairObservation{
+                time: now,
+                reserve0: pair.reserve0,
+                reserve1: pair.reserve1,
+                price0_cumulative_last: pair.price0CumulativeLast,
+                price1_cumulative_last: pair.price1CumulativeLast,
+                block_timestamp_last: pair.blockTimestampLast,
+            });
+        }
+        // 超过最长窗口的观测不再需要 [保留窗口起点之前的一个]
+        for history in observations.values_mut() {
+            while history.front().is_some_and(|observation| now.saturating_sub(observation.time) > retention) {
+                history.pop_front();
+            }
+        }
+        observations.retain(|_, history| !history.is_empty());
+    });
+}
+
+// 单个交易对在窗口内的平均价格 [每个 to 需要的 from]
+fn pair_twap(from: &str, to: &str, window: u64) -> Result<BigDecimal, String> {
+    OBSERVATIONS.with(|observations|{
+        let observations = observations.borrow();
+        let (history, token0_in) = match observations.get(&(from.to_string(), to.to_string())) {
+            Some(history) => (history, true),
+            None => match observations.get(&(to.to_string(), from.to_string())) {
+                Some(history) => (history, false),
+                None => return Err(String::from("no price history for this pair")),  // 没有该交易对的价格记录
+            },
+        };
+        let end = history.back().ok_or_else(|| String::from("no price history for this pair"))?;
+        let start_before = end.time.saturating_sub(window.saturating_mul(1_000_000_000));
+        let start = history.iter().rev()
+            .find(|observation| observation.time <= start_before && observation.time < end.time)
+            .ok_or_else(|| String::from("not enough price history for this window"))?;  // 价格记录不足以覆盖窗口
+        start.check()?;
+        end.check()?;
+        let elapsed = BigDecimal::from((end.time - start.time) / PAIR_TIME_UNIT);
+        Ok((end.cumulative(token0_in) - start.cumulative(token0_in)) / elapsed)
+    })
+}
+
+// 窗口内的时间加权平均价格 [原始单位下每个 take 需要的 give]
+// 路径按最近一次观测的储备选择边际价格最低的, 多跳时为各跳 TWAP 之积
+pub fn twap_price(give_token: Principal, take_token: Principal, window: u64) -> Result<BigDecimal, String> {
+    if window == 0 || window > MAX_TWAP_WINDOW {
+        return Err(format!("window must be between 1 and {} seconds", MAX_TWAP_WINDOW));
+    }
+    let mut graph = TokenGraph::new();
+    OBSERVATIONS.with(|observations|{
+        for ((token0, token1), history) in observations.borrow().iter() {
+            if let Some(latest) = history.back() {
+                graph.add_pair(token0.clone(), token1.clone(), latest.reserve0.0.clone(), latest.reserve1.0.clone());
+            }
+        }
+    });
+    let (path, _) = graph.best_spot_path(&give_token.to_text(), &take_token.to_text())
+        .ok_or_else(|| String::from("no route between these currencies"))?;
+    path_twap(&path, window)
+}
+
+// 路径在窗口内的平均价格 [各跳 TWAP 之积]
+fn path_twap(path: &[String], window: u64) -> Result<BigDecimal, String> {
+    let mut price = BigDecimal::from(1u64);
+    for hop in path.windows(2) {
+        price *= pair_twap(&hop[0], &hop[1], window)?;
+    }
+    Ok(price)
+}
+
+// 按窗口内的平均价格选择路径 [候选路径来自当前储备, 只考虑价格记录覆盖窗口的路径]
+// 没有可用记录时返回 None, 由调用方按边际价格选择
+pub fn best_twap_path(graph: &TokenGraph, from: &str, to: &str, window: u64) -> Option<Vec<String>> {
+    graph.paths(from, to).into_iter()
+        .filter_map(|path| path_twap(&path, window).ok().map(|price| (path, price)))
+        .min_by(|a, b| a.1.cmp(&b.1).then(a.0.len().cmp(&b.0.len())))
+        .map(|(path, _)| path)
+}
+
+// 查询时间加权平均价格 window 为秒 [0 使用配置的窗口, 限价单按配置的窗口检查]
+#[update]
+pub async fn get_twap_price(give_currency: Currency, take_currency: Currency, window: u64) -> Result<f64, String> {
+    let window = if window == 0 { get_config().twap_window } else { window };
+    let give_token = token_id_by_currency(give_currency)?;
+    let take_token = token_id_by_currency(take_currency)?;
+    let price = twap_price(give_token, take_token, window)?;
+    to_display_price(give_token, take_token, price).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+    const T0: u64 = 1_700_000_000 * SECOND;
+
+    fn observation(time: u64, reserves: (u64, u64), cumulative: (u64, u64), block_timestamp_last: u64) -> PairObservation {
+        PairObservation{
+            time,
+            reserve0: Nat::from(reserves.0),
+            reserve1: Nat::from(reserves.1),
+            price0_cumulative_last: Nat::from(cumulative.0),
+            price1_cumulative_last: Nat::from(cumulative.1),
+            block_timestamp_last: Int::from(block_timestamp_last as i64),
+        }
+    }
+
+    // 交易对 (a, b) 在 T0 - 10 秒 到 T0 + 300 秒 价格为 2 b/a, 之后储备变化 价格为 4 b/a
+    fn record_pair() {
+        let start = observation(T0, (100, 200), (0, 0), T0 - 10 * SECOND);
+        let end = observation(T0 + 600 * SECOND, (100, 400), (2 * 310 * SECOND, 310 * SECOND / 2), T0 + 300 * SECOND);
+        OBSERVATIONS.with(|observations| observations.borrow_mut().insert((String::from("a"), String::from("b")), VecDeque::from([start, end])));
+    }
+
+    #[test]
+    fn cumulative_extrapolates_from_the_last_pair_update() {
+        let start = observation(T0, (100, 200), (0, 0), T0 - 10 * SECOND);
+        // 上次更新后经过 10 秒 价格保持不变
+        assert_eq!(start.cumulative(false), BigDecimal::from(2 * 10 * SECOND));
+        assert_eq!(start.cumulative(true), BigDecimal::from(10 * SECOND / 2));
+        // 交易对时间晚于观测时间时不外推
+        let ahead = observation(T0, (100, 200), (7, 9), T0 + SECOND);
+        assert_eq!(ahead.cumulative(false), BigDecimal::from(7));
+        assert_eq!(ahead.cumulative(true), BigDecimal::from(9));
+    }
+
+    #[test]
+    fn twap_weights_prices_by_time() {
+        record_pair();
+        // 300 秒价格为 2, 300 秒价格为 4
+        assert_eq!(pair_twap("b", "a", 600).unwrap(), BigDecimal::from(3));
+        assert_eq!(pair_twap("a", "b", 600).unwrap(), BigDecimal::from_str("0.375").unwrap());
+        // 较短的窗口使用窗口起点之前最近的观测
+        assert_eq!(pair_twap("b", "a", 300).unwrap(), BigDecimal::from(3));
+        assert!(pair_twap("b", "a", 601).is_err());
+        assert!(pair_twap("a", "c", 600).is_err());
+    }
+
+    #[test]
+    fn path_twap_multiplies_the_hops() {
+        record_pair();
+        let start = observation(T0, (50, 100), (0, 0), T0 - SECOND);
+        let end = observation(T0 + 600 * SECOND, (50, 100), (0, 0), T0 - SECOND);
+        OBSERVATIONS.with(|observations| observations.borrow_mut().insert((String::from("b"), String::from("c")), VecDeque::from([start, end])));
+        // 每个 c 需要 0.5 b, 每个 b 平均需要 0.375 a
+        let path = [String::from("a"), String::from("b"), String::from("c")];
+        assert_eq!(path_twap(&path, 600).unwrap(), BigDecimal::from_str("0.1875").unwrap());
+    }
+
+    #[test]
+    fn non_nanosecond_timestamps_are_rejected() {
+        // Sonic 若以秒记录 blockTimestampLast, 外推的时长会被放大 10^9 倍
+        let start = observation(T0, (100, 200), (0, 0), T0 / SECOND - 10);
+        let end = observation(T0 + 600 * SECOND, (100, 200), (1_200, 300), T0 / SECOND + 590);
+        OBSERVATIONS.with(|observations| observations.borrow_mut().insert((String::from("a"), String::from("b")), VecDeque::from([start, end])));
+        assert_eq!(pair_twap("b", "a", 600).unwrap_err(), "pair timestamps are not in nanoseconds");
+    }
+
+    #[test]
+    fn empty_pairs_are_rejected() {
+        let start = observation(T0, (0, 0), (0, 0), T0);
+        let end = observation(T0 + 600 * SECOND, (0, 0), (0, 0), T0);
+        OBSERVATIONS.with(|observations| observations.borrow_mut().insert((String::from("a"), String::from("b")), VecDeque::from([start, end])));
+        assert_eq!(pair_twap("b", "a", 600).unwrap_err(), "the pair has no liquidity");
+    }
+}
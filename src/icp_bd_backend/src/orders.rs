@@ -0,0 +1,350 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use bigdecimal::num_bigint::ToBigInt;
+use bigdecimal::{BigDecimal, ToPrimitive};
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use ic_cron::types::{Iterations, SchedulingOptions};
+
+use crate::common::types::{CronTaskKind, Currency, LimitOrder, MarketOrder, Order, OrderDirective, OrderFill, OrderRecord, OrderStatus, SwapExecution, SwapExecutionStatus, TargetPrice};
+use crate::{oracle, swap};
+use crate::{cron_enqueue, token_id_by_currency};
+
+const ORDER_TICK: u64 = 30;  // 限价单检查间隔 秒
+const DEFAULT_SLIPPAGE_BPS: u64 = 100;  // 限价单未设置滑点时 触发时相对当前价格允许的滑点 万分之
+const MAX_OPEN_ORDERS_PER_USER: usize = 20;
+
+thread_local!{
+    static ORDERS:RefCell<BTreeMap<u64, OrderRecord>> = RefCell::default();  // 订单 id -> 订单
+    static NEXT_ORDER_ID:Cell<u64> = const { Cell::new(0) };
+    static FILLS:RefCell<Vec<OrderFill>> = RefCell::default();  // 成交记录 [按时间顺序]
+}
+
+// 升级时保存的订单状态
+#[derive(CandidType, Deserialize, Default)]
+pub struct OrdersStable {
+    orders: BTreeMap<u64, OrderRecord>,
+    next_order_id: u64,
+    fills: Vec<OrderFill>,
+}
+
+pub fn snapshot() -> OrdersStable {
+    OrdersStable {
+        orders: ORDERS.with(|orders| orders.borrow().clone()),
+        next_order_id: NEXT_ORDER_ID.with(|id| id.get()),
+        fills: FILLS.with(|fills| fills.borrow().clone()),
+    }
+}
+
+// 升级打断的执行中订单无法确认结果 标记为失败
+pub fn restore(stable: OrdersStable) {
+    let mut orders = stable.orders;
+    for order in orders.values_mut() {
+        if order.status == OrderStatus::Executing {
+            order.status = OrderStatus::Failed;
+            order.error = Some(String::from("interrupted by upgrade"));
+        }
+    }
+    ORDERS.with(|o| *o.borrow_mut() = orders);
+    NEXT_ORDER_ID.with(|id| id.set(stable.next_order_id));
+    FILLS.with(|fills| *fills.borrow_mut() = stable.fills);
+}
+
+// 注册限价单检查任务 [安装和升级后调用]
+pub fn schedule_evaluation() {
+    let interval_nano = ORDER_TICK * 1_000_000_000;
+    cron_enqueue(
+        CronTaskKind::EvaluateLimitOrders,
+        SchedulingOptions {
+            delay_nano: interval_nano,
+            interval_nano,
+            iterations: Iterations::Infinite,
+        },
+    ).expect("Unable to schedule limit order evaluation");
+}
+
+// 按代币罐区分交易对 [Currency::Token 与别名指向同一个罐时为同一个交易对]
+fn currency_key(currency: &Currency) -> Option<Principal> {
+    token_id_by_currency(currency.clone()).ok()
+}
+
+// 检查所有未成交的限价单: 过期的标记为过期, 达到目标价格的执行
+pub fn evaluate_open_orders() {
+    let now = ic_cdk::api::time();
+    let mut pairs: BTreeMap<(Option<Principal>, Option<Principal>), (Currency, Currency)> = BTreeMap::new();
+    ORDERS.with(|orders|{
+        for order in orders.borrow_mut().values_mut() {
+            if order.status != OrderStatus::Open {
+                continue;
+            }
+            if order.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                order.status = OrderStatus::Expired;
+                order.updtime = now;
+                continue;
+            }
+            if let Order::Limit(limit_order) = &order.order {
+                let market_order = &limit_order.market_order;
+                pairs.insert(
+                    (currency_key(&market_order.give_currency), currency_key(&market_order.take_currency)),
+                    (market_order.give_currency.clone(), market_order.take_currency.clone()),
+                );
+            }
+        }
+    });
+    // 每个交易对只查询一次价格
+    for (_, (give_currency, take_currency)) in pairs {
+        ic_cdk::spawn(evaluate_pair(give_currency, take_currency));
+    }
+}
+
+async fn evaluate_pair(give_currency: Currency, take_currency: Currency) {
+    // 按 TWAP 判断是否触发 避免被单笔交易操纵 [价格记录不足时等下次检查]
+    let price = match oracle::get_twap_price(give_currency.clone(), take_currency.clone(), 0).await {
+        Ok(price) => price,
+        Err(_) => return,
+    };
+    let triggered: Vec<u64> = ORDERS.with(|orders|{
+        orders.borrow().values()
+            .filter(|order| order.status == OrderStatus::Open)
+            .filter_map(|order| match &order.order {
+                Order::Limit(limit_order) if same_pair(limit_order, &give_currency, &take_currency) && target_reached(&limit_order.target_price_condition, price) => Some(order.id),
+                _ => None,
+            })
+            .collect()
+    });
+    for order_id in triggered {
+        let limit_order = match ORDERS.with(|orders| orders.borrow().get(&order_id).map(|order| order.order.clone())) {
+            Some(Order::Limit(limit_order)) => limit_order,
+            _ => continue,
+        };
+        let slippage_bps = ORDERS.with(|orders| orders.borrow().get(&order_id).and_then(|order| order.slippage_bps)).unwrap_or(DEFAULT_SLIPPAGE_BPS);
+        // 没有报价 [例如流动性不足] 时等下次检查
+        let swap_bound = match swap::swap_bound(&limit_order.market_order, slippage_bps).await {
+            Ok(quoted) => quoted,
+            Err(_) => continue,
+        };
+        // 同时只执行一笔兑换 其余等下次检查
+        let _lock = match swap::SwapLock::acquire(None) {
+            Ok(lock) => lock,
+            Err(_) => return,
+        };
+        execute_order(order_id, swap_bound).await;
+    }
+}
+
+fn same_pair(limit_order: &LimitOrder, give_currency: &Currency, take_currency: &Currency) -> bool {
+    currency_key(&limit_order.market_order.give_currency) == currency_key(give_currency)
+        && currency_key(&limit_order.market_order.take_currency) == currency_key(take_currency)
+}
+
+fn target_reached(target: &TargetPrice, price: f64) -> bool {
+    match target {
+        TargetPrice::MoreThan(target) => price > *target,
+        TargetPrice::LessThan(target) => price < *target,
+    }
+}
+
+async fn execute_order(order_id: u64, swap_bound: swap::SwapBound) {
+    let started = ORDERS.with(|orders|{
+        match orders.borrow_mut().get_mut(&order_id) {
+            Some(order) if order.status == OrderStatus::Open => {
+                let market_order = match &order.order {
+                    Order::Limit(limit_order) => limit_order.market_order.clone(),
+                    Order::Market(market_order) => market_order.clone(),
+                };
+                order.status = OrderStatus::Executing;
+                order.updtime = ic_cdk::api::time();
+                Some((order.owner, market_order, order.slippage_bps.unwrap_or(DEFAULT_SLIPPAGE_BPS)))
+            },
+            _ => None,
+        }
+    });
+    let (owner, market_order, slippage_bps) = match started {
+        Some(started) => started,
+        None => return,
+    };
+    let execution_id = swap::start_execution(order_id, owner, market_order, swap_bound, slippage_bps, ic_cdk::api::time() + swap::DEFAULT_SWAP_DEADLINE);
+    ORDERS.with(|orders|{
+        if let Some(order) = orders.borrow_mut().get_mut(&order_id) {
+            order.execution_id = Some(execution_id);
+        }
+    });
+    swap::run(execution_id).await;
+}
+
+// 兑换执行结束或失败时更新订单 [恢复成功后会再次调用]
+pub fn execution_settled(execution: &SwapExecution) {
+    let now = ic_cdk::api::time();
+    let settled = ORDERS.with(|orders|{
+        let mut orders = orders.borrow_mut();
+        let order = orders.get_mut(&execution.order_id)?;
+        order.updtime = now;
+        match execution.status {
+            SwapExecutionStatus::Completed => {
+                order.status = OrderStatus::Filled;
+                order.error = None;
+                Some(order.owner)
+            },
+            SwapExecutionStatus::Refunded => {
+                order.status = OrderStatus::Failed;
+                order.error = Some(String::from("swap did not execute, tokens refunded"));  // 未成交 代币已退还
+                None
+            },
+            SwapExecutionStatus::Failed => {
+                order.status = OrderStatus::Failed;
+                order.error = execution.error.clone();
+                None
+            },
+            SwapExecutionStatus::Running => None,
+        }
+    });
+    if let Some(owner) = settled {
+        let price = match (execution.spent.0.to_bigint(), execution.received.0.to_bigint()) {
+            (Some(spent), Some(received)) if received > 0.into() => (BigDecimal::from(spent) / BigDecimal::from(received)).to_f64().unwrap_or(0.0),
+            _ => 0.0,
+        };
+        FILLS.with(|fills| fills.borrow_mut().push(OrderFill{
+            order_id: execution.order_id,
+            owner,
+            price,
+            amount_in: execution.spent.clone(),
+            amount_out: execution.amount_out.clone(),
+            time: now,
+        }));
+    }
+}
+
+fn validate_market_order(market_order: &MarketOrder, slippage_bps: Option<u64>) -> Result<(), String> {
+    let amount = match &market_order.directive {
+        OrderDirective::GiveExact(amount) | OrderDirective::TakeExact(amount) => amount.clone(),
+    };
+    if amount == 0u64 {
+        return Err(String::from("amount must be greater than zero"));  // 数量必须大于 0
+    }
+    token_id_by_currency(market_order.give_currency.clone())?;
+    token_id_by_currency(market_order.take_currency.clone())?;
+    if slippage_bps.is_some_and(|slippage_bps| slippage_bps > swap::MAX_SLIPPAGE_BPS) {
+        return Err(String::from("slippage tolerance is too large"));  // 滑点过大
+    }
+    Ok(())
+}
+
+fn next_order_id() -> u64 {
+    NEXT_ORDER_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    })
+}
+
+// 立即执行市价单 [需要先授权本罐从调用者转出 give 代币]
+// slippage_bps 为相对当前价格允许的滑点 (万分之), deadline 为兑换截止时间
+#[update]
+pub async fn execute_market_order(market_order: MarketOrder, slippage_bps: u64, deadline: Option<u64>) -> Result<SwapExecution, String> {
+    let caller = ic_cdk::api::caller();
+    validate_market_order(&market_order, Some(slippage_bps))?;
+    let now = ic_cdk::api::time();
+    let deadline = deadline.unwrap_or(now + swap::DEFAULT_SWAP_DEADLINE);
+    if deadline <= now {
+        return Err(String::from("deadline must be in the future"));  // 截止时间必须晚于当前时间
+    }
+    let swap_bound = swap::swap_bound(&market_order, slippage_bps).await?;
+    let _lock = swap::SwapLock::acquire(None)?;
+    let order_id = next_order_id();
+    let now = ic_cdk::api::time();
+    ORDERS.with(|orders| orders.borrow_mut().insert(order_id, OrderRecord{
+        id: order_id,
+        owner: caller,
+        order: Order::Market(market_order.clone()),
+        status: OrderStatus::Executing,
+        error: None,
+        instime: now,
+        expires_at: None,
+        updtime: now,
+        slippage_bps: Some(slippage_bps),
+        execution_id: None,
+    }));
+    let execution_id = swap::start_execution(order_id, caller, market_order, swap_bound, slippage_bps, deadline);
+    ORDERS.with(|orders|{
+        if let Some(order) = orders.borrow_mut().get_mut(&order_id) {
+            order.execution_id = Some(execution_id);
+        }
+    });
+    swap::run(execution_id).await;
+    swap::get_execution(execution_id).ok_or_else(|| String::from("execution does not exist"))
+}
+
+// 提交限价单 [需要先授权本罐从调用者转出 give 代币]
+#[update]
+pub fn place_limit_order(limit_order: LimitOrder, expires_at: Option<u64>, slippage_bps: Option<u64>) -> Result<u64, String> {
+    let caller = ic_cdk::api::caller();
+    let now = ic_cdk::api::time();
+    if expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(String::from("expiry must be in the future"));  // 过期时间必须晚于当前时间
+    }
+    validate_market_order(&limit_order.market_order, slippage_bps)?;
+    let open = ORDERS.with(|orders|{
+        orders.borrow().values().filter(|order| order.owner == caller && order.status == OrderStatus::Open).count()
+    });
+    if open >= MAX_OPEN_ORDERS_PER_USER {
+        return Err(String::from("Too many open orders"));  // 未成交订单数量已达上限
+    }
+    let id = next_order_id();
+    ORDERS.with(|orders| orders.borrow_mut().insert(id, OrderRecord{
+        id,
+        owner: caller,
+        order: Order::Limit(limit_order),
+        status: OrderStatus::Open,
+        error: None,
+        instime: now,
+        expires_at,
+        updtime: now,
+        slippage_bps,
+        execution_id: None,
+    }));
+    Ok(id)
+}
+
+// 撤销未成交的订单
+#[update]
+pub fn cancel_order(order_id: u64) -> String {
+    let caller = ic_cdk::api::caller();
+    ORDERS.with(|orders|{
+        match orders.borrow_mut().get_mut(&order_id) {
+            Some(order) if order.owner == caller => {
+                if order.status != OrderStatus::Open {
+                    return String::from("order is not open");  // 订单不是未成交状态
+                }
+                order.status = OrderStatus::Cancelled;
+                order.updtime = ic_cdk::api::time();
+                String::from("order cancelled")  // 订单已撤销
+            },
+            _ => String::from("order does not exist"),  // 订单不存在
+        }
+    })
+}
+
+// 查询我的订单 [新的在前]
+#[query]
+pub fn my_orders() -> Vec<OrderRecord> {
+    let caller = ic_cdk::api::caller();
+    ORDERS.with(|orders|{
+        orders.borrow().values().rev()
+            .filter(|order| order.owner == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+// 查询我的成交记录 [新的在前]
+#[query]
+pub fn my_order_fills() -> Vec<OrderFill> {
+    let caller = ic_cdk::api::caller();
+    FILLS.with(|fills|{
+        fills.borrow().iter().rev()
+            .filter(|fill| fill.owner == caller)
+            .cloned()
+            .collect()
+    })
+}
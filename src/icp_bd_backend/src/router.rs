@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+
+use bigdecimal::num_bigint::{BigInt, BigUint};
+use bigdecimal::BigDecimal;
+use ic_cdk::export::candid::{Nat, Principal};
+use ic_cdk_macros::update;
+
+use crate::clients::sonic::{Sonic, SonicPairInfo};
+use crate::common::types::{Currency, OrderDirective, PriceImpactQuote, SwapQuote};
+use crate::swap;
+use crate::{get_config, get_state, token_id_by_currency};
+
+const MAX_HOPS: usize = 3;  // 路径最多经过的交易对数
+const FEE_NUMERATOR: u32 = 997;  // 每一跳 0.3% 手续费
+const FEE_DENOMINATOR: u32 = 1000;
+const PRICE_SCALE: i64 = 18;  // 返回价格的小数位数
+const PERCENT_SCALE: i64 = 4;  // 返回价格影响百分比的小数位数
+
+// 交易对储备 [按代币方向存储]
+struct Reserves {
+    reserve_in: BigUint,
+    reserve_out: BigUint,
+}
+
+// 代币图 代币 -> 可兑换的代币 -> 储备
+pub struct TokenGraph {
+    edges: BTreeMap<String, BTreeMap<String, Reserves>>,
+}
+
+impl TokenGraph {
+    pub fn new() -> Self {
+        TokenGraph{ edges: BTreeMap::new() }
+    }
+
+    pub fn from_pairs(pairs: Vec<SonicPairInfo>) -> Self {
+        let mut graph = TokenGraph::new();
+        for pair in pairs {
+            graph.add_pair(pair.token0, pair.token1, pair.reserve0.0, pair.reserve1.0);
+        }
+        graph
+    }
+
+    // 加入交易对 [储备为零的交易对不可兑换 忽略]
+    pub fn add_pair(&mut self, token0: String, token1: String, reserve0: BigUint, reserve1: BigUint) {
+        if reserve0 == BigUint::from(0u32) || reserve1 == BigUint::from(0u32) {
+            return;
+        }
+        self.edges.entry(token0.clone()).or_default().insert(token1.clone(), Reserves{
+            reserve_in: reserve0.clone(),
+            reserve_out: reserve1.clone(),
+        });
+        self.edges.entry(token1).or_default().insert(token0, Reserves{
+            reserve_in: reserve1,
+            reserve_out: reserve0,
+        });
+    }
+
+    // 所有不超过 MAX_HOPS 跳且不重复经过代币的路径
+    pub fn paths(&self, from: &str, to: &str) -> Vec<Vec<String>> {
+        let mut paths = Vec::new();
+        let mut path = vec![from.to_string()];
+        self.walk(to, &mut path, &mut paths);
+        paths
+    }
+
+    fn walk(&self, to: &str, path: &mut Vec<String>, paths: &mut Vec<Vec<String>>) {
+        let last = path.last().unwrap().clone();
+        if last == to {
+            paths.push(path.clone());
+            return;
+        }
+        if path.len() > MAX_HOPS {
+            return;
+        }
+        if let Some(neighbours) = self.edges.get(&last) {
+            for next in neighbours.keys() {
+                if path.contains(next) {
+                    continue;
+                }
+                path.push(next.clone());
+                self.walk(to, path, paths);
+                path.pop();
+            }
+        }
+    }
+
+    fn reserves(&self, from: &str, to: &str) -> Option<&Reserves> {
+        self.edges.get(from)?.get(to)
+    }
+
+    // 沿路径输入 amount_in 得到的数量 [fee_numerator 为 FEE_DENOMINATOR 时不扣手续费]
+    fn amount_out_along(&self, path: &[String], amount_in: &BigUint, fee_numerator: u32) -> Option<BigUint> {
+        let mut amount = amount_in.clone();
+        for hop in path.windows(2) {
+            let reserves = self.reserves(&hop[0], &hop[1])?;
+            amount = amount_out_with_fee(&amount, &reserves.reserve_in, &reserves.reserve_out, fee_numerator)?;
+        }
+        Some(amount)
+    }
+
+    // 沿路径得到 amount_out 需要输入的数量
+    fn amount_in_along(&self, path: &[String], amount_out: &BigUint) -> Option<BigUint> {
+        let mut amount = amount_out.clone();
+        for hop in path.windows(2).rev() {
+            let reserves = self.reserves(&hop[0], &hop[1])?;
+            amount = amount_in(&amount, &reserves.reserve_in, &reserves.reserve_out)?;
+        }
+        Some(amount)
+    }
+
+    // 路径的边际价格 [原始单位下每个 take 需要的 give, 不含手续费]
+    // 按交易对的 token0/token1 确定方向, 返回分子 (各跳付出方储备之积) 和分母 (各跳得到方储备之积)
+    pub fn path_spot_ratio(&self, path: &[String]) -> Option<(BigUint, BigUint)> {
+        let mut numerator = BigUint::from(1u32);
+        let mut denominator = BigUint::from(1u32);
+        for hop in path.windows(2) {
+            let reserves = self.reserves(&hop[0], &hop[1])?;
+            numerator *= &reserves.reserve_in;
+            denominator *= &reserves.reserve_out;
+        }
+        Some((numerator, denominator))
+    }
+
+    pub fn path_spot_price(&self, path: &[String]) -> Option<BigDecimal> {
+        let (numerator, denominator) = self.path_spot_ratio(path)?;
+        Some(to_decimal(&numerator) / to_decimal(&denominator))
+    }
+
+    // 边际价格最低的路径
+    pub fn best_spot_path(&self, from: &str, to: &str) -> Option<(Vec<String>, BigDecimal)> {
+        self.paths(from, to).into_iter()
+            .filter_map(|path| self.path_spot_price(&path).map(|price| (path, price)))
+            .min_by(|a, b| a.1.cmp(&b.1).then(a.0.len().cmp(&b.0.len())))
+    }
+
+    // 沿路径付出 amount_in 的价格影响 [0~1, 不含手续费]
+    // 按边际价格应得 amount_in / spot, 按储备变化实际得到不扣手续费的数量, 两者之差的比例即价格影响
+    pub fn price_impact(&self, path: &[String], amount_in: &BigUint) -> Option<BigDecimal> {
+        let spot_price = self.path_spot_price(path)?;
+        let amount_out = self.amount_out_along(path, amount_in, FEE_DENOMINATOR)?;
+        if *amount_in == BigUint::from(0u32) {
+            return None;
+        }
+        Some(BigDecimal::from(1u64) - to_decimal(&amount_out) * spot_price / to_decimal(amount_in))
+    }
+
+    // 最优路径: GiveExact 时得到最多, TakeExact 时付出最少
+    pub fn best_quote(&self, from: &str, to: &str, directive: &OrderDirective) -> Option<SwapQuote> {
+        let quotes = self.paths(from, to).into_iter().filter_map(|path| {
+            match directive {
+                OrderDirective::GiveExact(amount) => {
+                    let amount_out = self.amount_out_along(&path, &amount.0, FEE_NUMERATOR)?;
+                    Some((path, amount.0.clone(), amount_out))
+                },
+                OrderDirective::TakeExact(amount) => {
+                    let amount_in = self.amount_in_along(&path, &amount.0)?;
+                    Some((path, amount_in, amount.0.clone()))
+                },
+            }
+        });
+        let best = match directive {
+            OrderDirective::GiveExact(_) => quotes.max_by(|a, b| a.2.cmp(&b.2).then(b.0.len().cmp(&a.0.len()))),
+            OrderDirective::TakeExact(_) => quotes.min_by(|a, b| a.1.cmp(&b.1).then(a.0.len().cmp(&b.0.len()))),
+        }?;
+        if best.2 == BigUint::from(0u32) {
+            return None;
+        }
+        Some(SwapQuote{
+            path: best.0,
+            amount_in: Nat(best.1),
+            amount_out: Nat(best.2),
+        })
+    }
+}
+
+pub fn to_decimal(amount: &BigUint) -> BigDecimal {
+    BigDecimal::from(BigInt::from(amount.clone()))
+}
+
+// 恒定乘积 输入 amount_in 得到的数量 [fee_numerator 为 FEE_NUMERATOR 时扣除 0.3% 手续费]
+fn amount_out_with_fee(amount_in: &BigUint, reserve_in: &BigUint, reserve_out: &BigUint, fee_numerator: u32) -> Option<BigUint> {
+    let amount_in_with_fee = amount_in * fee_numerator;
+    let denominator = reserve_in * FEE_DENOMINATOR + &amount_in_with_fee;
+    if denominator == BigUint::from(0u32) {
+        return None;
+    }
+    Some(amount_in_with_fee * reserve_out / denominator)
+}
+
+// 恒定乘积 得到 amount_out 需要输入的数量 [向上取整]
+pub fn amount_in(amount_out: &BigUint, reserve_in: &BigUint, reserve_out: &BigUint) -> Option<BigUint> {
+    if amount_out >= reserve_out {
+        return None;
+    }
+    let numerator = reserve_in * amount_out * FEE_DENOMINATOR;
+    let denominator = (reserve_out - amount_out) * FEE_NUMERATOR;
+    Some(numerator / denominator + 1u32)
+}
+
+// 读取 Sonic 所有交易对并建立代币图
+pub async fn load_graph() -> Result<TokenGraph, String> {
+    let (pairs,) = Sonic::get_all_pairs(&get_state().sonic_swap_canister)
+        .await
+        .map_err(|(code, msg)| format!("Unable to fetch pairs at Sonic: {:?}: {}", code, msg))?;
+    Ok(TokenGraph::from_pairs(pairs))
+}
+
+// 查询两个代币之间的最优兑换路径和数量
+pub async fn best_quote(give_token: Principal, take_token: Principal, directive: &OrderDirective) -> Result<SwapQuote, String> {
+    if give_token == take_token {
+        return Err(String::from("give and take currencies must differ"));  // 兑换的两种代币不能相同
+    }
+    let graph = load_graph().await?;
+    graph.best_quote(&give_token.to_text(), &take_token.to_text(), directive)
+        .ok_or_else(|| String::from("no route between these currencies"))  // 两种代币之间没有可用路径
+}
+
+// 最优路径报价 价格影响超过配置的上限时拒绝
+// 返回报价、路径的边际价格和价格影响
+pub async fn checked_quote(give_token: Principal, take_token: Principal, directive: &OrderDirective) -> Result<(SwapQuote, BigDecimal, BigDecimal), String> {
+    if give_token == take_token {
+        return Err(String::from("give and take currencies must differ"));
+    }
+    let graph = load_graph().await?;
+    let quote = graph.best_quote(&give_token.to_text(), &take_token.to_text(), directive)
+        .ok_or_else(|| String::from("no route between these currencies"))?;
+    let spot_price = graph.path_spot_price(&quote.path)
+        .ok_or_else(|| String::from("no route between these currencies"))?;
+    let impact = graph.price_impact(&quote.path, &quote.amount_in.0)
+        .ok_or_else(|| String::from("no route between these currencies"))?;
+    check_impact(&impact)?;
+    Ok((quote, spot_price, impact))
+}
+
+fn check_impact(impact: &BigDecimal) -> Result<(), String> {
+    let max_price_impact_bps = get_config().max_price_impact_bps;
+    if max_price_impact_bps > 0 && impact * BigDecimal::from(10_000u64) > BigDecimal::from(max_price_impact_bps) {
+        return Err(format!("price impact {}% exceeds the limit of {}%", percent(impact), BigDecimal::from(max_price_impact_bps) / BigDecimal::from(100u64)));  // 价格影响超过上限
+    }
+    Ok(())
+}
+
+fn percent(ratio: &BigDecimal) -> BigDecimal {
+    (ratio * BigDecimal::from(100u64)).with_scale(PERCENT_SCALE)
+}
+
+// 报价 返回最优路径及付出/得到数量
+#[update]
+pub async fn quote_swap(give_currency: Currency, take_currency: Currency, directive: OrderDirective) -> Result<SwapQuote, String> {
+    best_quote(token_id_by_currency(give_currency)?, token_id_by_currency(take_currency)?, &directive).await
+}
+
+// 按付出数量报价 返回预计得到数量、成交均价、价格影响和滑点下的最少得到数量
+// 价格均为原始单位下每个 take 需要的 give, 价格影响超过配置的上限时拒绝
+#[update]
+pub async fn quote_swap_impact(give_currency: Currency, take_currency: Currency, amount_in: Nat, slippage_bps: u64) -> Result<PriceImpactQuote, String> {
+    if slippage_bps > swap::MAX_SLIPPAGE_BPS {
+        return Err(format!("slippage must not exceed {} bps", swap::MAX_SLIPPAGE_BPS));
+    }
+    let give_token = token_id_by_currency(give_currency)?;
+    let take_token = token_id_by_currency(take_currency)?;
+    let (quote, spot_price, impact) = checked_quote(give_token, take_token, &OrderDirective::GiveExact(amount_in)).await?;
+    let effective_price = to_decimal(&quote.amount_in.0) / to_decimal(&quote.amount_out.0);
+    let minimum_received = quote.amount_out.0.clone() * (10_000 - slippage_bps) / 10_000u64;
+    Ok(PriceImpactQuote{
+        path: quote.path,
+        amount_in: quote.amount_in,
+        expected_out: quote.amount_out,
+        minimum_received: Nat(minimum_received),
+        spot_price: spot_price.with_scale(PRICE_SCALE).to_string(),
+        effective_price: effective_price.with_scale(PRICE_SCALE).to_string(),
+        price_impact_percent: percent(&impact).to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bigdecimal::num_bigint::BigUint;
+    use bigdecimal::BigDecimal;
+    use ic_cdk::export::candid::Nat;
+
+    use super::{amount_in, amount_out_with_fee, TokenGraph, FEE_NUMERATOR};
+    use crate::common::types::OrderDirective;
+
+    fn big(n: u64) -> BigUint {
+        BigUint::from(n)
+    }
+
+    // 直接交易对储备很少, 经过 C 的两跳储备充足
+    fn graph() -> TokenGraph {
+        let mut graph = TokenGraph::new();
+        graph.add_pair(String::from("A"), String::from("B"), big(1_000), big(1_000));
+        graph.add_pair(String::from("A"), String::from("C"), big(1_000_000_000), big(1_000_000_000));
+        graph.add_pair(String::from("C"), String::from("B"), big(1_000_000_000), big(1_000_000_000));
+        graph
+    }
+
+    #[test]
+    fn amount_out_deducts_fee() {
+        assert_eq!(amount_out_with_fee(&big(1_000), &big(1_000_000), &big(1_000_000), FEE_NUMERATOR), Some(big(996)));
+    }
+
+    // 按 amount_in 的结果付出 至少得到要求的数量
+    #[test]
+    fn amount_in_round_trips() {
+        assert_eq!(amount_in(&big(996), &big(1_000_000), &big(1_000_000)), Some(big(1_000)));
+        for amount in [1u64, 7, 996, 50_000, 999_000] {
+            let needed = amount_in(&big(amount), &big(1_000_000), &big(1_000_000)).unwrap();
+            let out = amount_out_with_fee(&needed, &big(1_000_000), &big(1_000_000), FEE_NUMERATOR).unwrap();
+            assert!(out >= big(amount));
+            let less = amount_out_with_fee(&(needed - 1u32), &big(1_000_000), &big(1_000_000), FEE_NUMERATOR).unwrap();
+            assert!(less < big(amount));
+        }
+    }
+
+    #[test]
+    fn zero_reserves_have_no_quote() {
+        assert_eq!(amount_out_with_fee(&big(0), &big(0), &big(0), FEE_NUMERATOR), None);
+        assert_eq!(amount_in(&big(1), &big(1_000), &big(0)), None);
+        // 得到的数量不少于储备
+        assert_eq!(amount_in(&big(1_000), &big(1_000), &big(1_000)), None);
+        let mut graph = TokenGraph::new();
+        graph.add_pair(String::from("A"), String::from("B"), big(0), big(1_000));
+        assert!(graph.best_quote("A", "B", &OrderDirective::GiveExact(Nat::from(100u64))).is_none());
+        assert!(graph.best_quote("A", "B", &OrderDirective::TakeExact(Nat::from(100u64))).is_none());
+    }
+
+    #[test]
+    fn best_quote_prefers_deeper_path() {
+        let graph = graph();
+        let give = graph.best_quote("A", "B", &OrderDirective::GiveExact(Nat::from(100u64))).unwrap();
+        assert_eq!(give.path, vec!["A", "C", "B"]);
+        assert_eq!(give.amount_in, Nat::from(100u64));
+        assert!(give.amount_out > 90u64);
+        let take = graph.best_quote("A", "B", &OrderDirective::TakeExact(Nat::from(50u64))).unwrap();
+        assert_eq!(take.path, vec!["A", "C", "B"]);
+        assert_eq!(take.amount_out, Nat::from(50u64));
+        assert!(take.amount_in < 53u64);
+    }
+
+    // 同一交易对两个方向的价格互为倒数 与交易对中 token0/token1 的顺序无关
+    #[test]
+    fn opposite_directions_give_reciprocal_prices() {
+        let x_to_y = vec![String::from("X"), String::from("Y")];
+        let y_to_x = vec![String::from("Y"), String::from("X")];
+        for (token0, token1, reserve0, reserve1) in [("X", "Y", 100, 400), ("Y", "X", 400, 100)] {
+            let mut graph = TokenGraph::new();
+            graph.add_pair(String::from(token0), String::from(token1), big(reserve0), big(reserve1));
+            // 每个 Y 需要 1/4 个 X, 每个 X 需要 4 个 Y
+            assert_eq!(graph.path_spot_ratio(&x_to_y), Some((big(100), big(400))));
+            assert_eq!(graph.path_spot_ratio(&y_to_x), Some((big(400), big(100))));
+            let product = graph.path_spot_price(&x_to_y).unwrap() * graph.path_spot_price(&y_to_x).unwrap();
+            assert_eq!(product, BigDecimal::from(1));
+        }
+
+        // 多跳路径同样互为倒数
+        let mut graph = graph();
+        graph.add_pair(String::from("B"), String::from("D"), big(3), big(7_000));
+        let (forward_numerator, forward_denominator) = graph.path_spot_ratio(&[String::from("A"), String::from("C"), String::from("B"), String::from("D")]).unwrap();
+        let (back_numerator, back_denominator) = graph.path_spot_ratio(&[String::from("D"), String::from("B"), String::from("C"), String::from("A")]).unwrap();
+        assert_eq!(forward_numerator * back_numerator, forward_denominator * back_denominator);
+    }
+
+    // 储备相同时直接交易对少付一次手续费
+    #[test]
+    fn best_quote_prefers_direct_pair_when_deeper() {
+        let mut graph = TokenGraph::new();
+        graph.add_pair(String::from("A"), String::from("B"), big(1_000_000_000), big(1_000_000_000));
+        graph.add_pair(String::from("A"), String::from("C"), big(1_000_000_000), big(1_000_000_000));
+        graph.add_pair(String::from("C"), String::from("B"), big(1_000_000_000), big(1_000_000_000));
+        let give = graph.best_quote("A", "B", &OrderDirective::GiveExact(Nat::from(1_000_000u64))).unwrap();
+        assert_eq!(give.path, vec!["A", "B"]);
+        let (path, _) = graph.best_spot_path("A", "B").unwrap();
+        assert_eq!(path, vec!["A", "B"]);
+        assert!(graph.best_quote("A", "D", &OrderDirective::GiveExact(Nat::from(1u64))).is_none());
+    }
+}
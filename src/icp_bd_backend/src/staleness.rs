@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::query;
+
+use crate::alerts;
+use crate::common::types::{AlertKind, DegradedInfo, OrganizeName, PollFailure, PollFailureCause};
+use crate::{get_config, is_admin, is_owner_or_member, ORGANIZES_TO_CANISTERS, PUBLIC_CANISTERS};
+
+thread_local!{
+    static LAST_FAILURES:RefCell<BTreeMap<Principal, PollFailure>> = RefCell::default();  // 罐 -> 最近一次成功后的读取失败
+    static DEGRADED:RefCell<BTreeMap<Principal, DegradedInfo>> = RefCell::default();  // 罐 -> 监控失效信息
+}
+
+// 升级时保存的监控失效状态
+#[derive(CandidType, Deserialize, Default)]
+pub struct StalenessStable {
+    last_failures: BTreeMap<Principal, PollFailure>,
+    degraded: BTreeMap<Principal, DegradedInfo>,
+}
+
+pub fn snapshot() -> StalenessStable {
+    StalenessStable {
+        last_failures: LAST_FAILURES.with(|failures| failures.borrow().clone()),
+        degraded: DEGRADED.with(|degraded| degraded.borrow().clone()),
+    }
+}
+
+pub fn restore(stable: StalenessStable) {
+    LAST_FAILURES.with(|failures| *failures.borrow_mut() = stable.last_failures);
+    DEGRADED.with(|degraded| *degraded.borrow_mut() = stable.degraded);
+}
+
+// 按照黑洞调用的拒绝码和信息判断读取失败的原因
+// 黑洞本身不可达时由系统拒绝, 黑洞调用管理罐失败时以 CanisterError 返回管理罐的错误信息
+pub fn classify(code: RejectionCode, message: &str) -> PollFailureCause {
+    match code {
+        RejectionCode::DestinationInvalid | RejectionCode::SysFatal | RejectionCode::SysTransient => PollFailureCause::BlackHoleUnavailable,
+        RejectionCode::CanisterError | RejectionCode::CanisterReject => {
+            let message = message.to_lowercase();
+            if message.contains("not found") || message.contains("does not exist") {
+                PollFailureCause::CanisterDeleted
+            } else if message.contains("controller") {
+                PollFailureCause::NotController
+            } else {
+                PollFailureCause::Other
+            }
+        },
+        _ => PollFailureCause::Other,
+    }
+}
+
+// 记录一次读取失败
+pub fn record_failure(canister_id: Principal, cause: PollFailureCause, error: String) {
+    let now = ic_cdk::api::time();
+    LAST_FAILURES.with(|failures|{
+        let mut failures = failures.borrow_mut();
+        let consecutive_failures = failures.get(&canister_id).map_or(0, |failure| failure.consecutive_failures) + 1;
+        failures.insert(canister_id, PollFailure{ cause, error, time: now, consecutive_failures });
+    });
+}
+
+// 读取成功 清除失败记录和监控失效标记, 并解决监控失效告警
+pub fn record_success(canister_id: Principal) {
+    LAST_FAILURES.with(|failures| failures.borrow_mut().remove(&canister_id));
+    if DEGRADED.with(|degraded| degraded.borrow_mut().remove(&canister_id)).is_some() {
+        alerts::resolve_matching(None, &canister_id, |kind| matches!(kind, AlertKind::MonitoringStale{..}));
+    }
+}
+
+// 找出距上次成功读取超过 stale_after_intervals × time_interval 的罐 标记为监控失效并告警
+pub fn check_stale() {
+    let now = ic_cdk::api::time();
+    let stale_after_intervals = get_config().polling.stale_after_intervals;
+    let stale: Vec<(Principal, u64)> = PUBLIC_CANISTERS.with(|public_canisters|{
+        public_canisters.borrow().iter()
+            .filter(|(_, public_canister)| is_stale(now, public_canister.updtime.get(), public_canister.time_interval.get(), stale_after_intervals))
+            .map(|(canister_id, public_canister)| (*canister_id, public_canister.updtime.get()))
+            .collect()
+    });
+    for (canister_id, last_success) in stale {
+        if DEGRADED.with(|degraded| degraded.borrow().contains_key(&canister_id)) {
+            continue;
+        }
+        // 没有失败记录说明读取一直没有返回
+        let (cause, last_error) = LAST_FAILURES.with(|failures|{
+            match failures.borrow().get(&canister_id) {
+                Some(failure) => (failure.cause, Some(failure.error.clone())),
+                None => (PollFailureCause::NoResponse, None),
+            }
+        });
+        DEGRADED.with(|degraded| degraded.borrow_mut().insert(canister_id, DegradedInfo{
+            cause,
+            last_error: last_error.clone(),
+            last_success,
+            since: now,
+        }));
+        alerts::raise_canister_alert(canister_id, AlertKind::MonitoringStale{ cause, last_success, last_error });
+    }
+}
+
+// 距上次成功读取超过 stale_after_intervals 个轮训间隔 [为 0 时不检查]
+fn is_stale(now: u64, updtime: u64, time_interval: u64, stale_after_intervals: u64) -> bool {
+    if stale_after_intervals == 0 {
+        return false;
+    }
+    let stale_after = time_interval.saturating_mul(stale_after_intervals).saturating_mul(1_000_000_000);
+    now.saturating_sub(updtime) > stale_after
+}
+
+// 罐的监控失效信息 [None 表示正常]
+pub fn degraded(canister_id: &Principal) -> Option<DegradedInfo> {
+    DEGRADED.with(|degraded| degraded.borrow().get(canister_id).cloned())
+}
+
+// 罐不再被任何组织收录时清除状态
+pub fn remove_canister(canister_id: &Principal) {
+    LAST_FAILURES.with(|failures| failures.borrow_mut().remove(canister_id));
+    DEGRADED.with(|degraded| degraded.borrow_mut().remove(canister_id));
+}
+
+// 查询组织下监控失效的罐
+#[query]
+pub fn organization_degraded_canisters(organize_name: OrganizeName) -> Result<Vec<(Principal, DegradedInfo)>, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_admin(&caller) && !is_owner_or_member(&caller, &organize_name) {
+        return Err(String::from("No permission to view this organization"));  // 无权查看该组织
+    }
+    let canisters: Vec<Principal> = ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        organizes_to_canisters.borrow().get(&organize_name)
+            .map_or(Vec::new(), |canisters| canisters.borrow().keys().cloned().collect())
+    });
+    Ok(canisters.into_iter()
+        .filter_map(|canister_id| degraded(&canister_id).map(|info| (canister_id, info)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn system_rejects_mean_the_black_hole_is_unavailable() {
+        for code in [RejectionCode::DestinationInvalid, RejectionCode::SysFatal, RejectionCode::SysTransient] {
+            assert_eq!(classify(code, "canister not found"), PollFailureCause::BlackHoleUnavailable);
+        }
+    }
+
+    #[test]
+    fn canister_errors_are_classified_by_message() {
+        for code in [RejectionCode::CanisterError, RejectionCode::CanisterReject] {
+            assert_eq!(classify(code, "Canister abc Not Found"), PollFailureCause::CanisterDeleted);
+            assert_eq!(classify(code, "canister does not exist"), PollFailureCause::CanisterDeleted);
+            assert_eq!(classify(code, "Only the controllers of the canister can read its status"), PollFailureCause::NotController);
+            assert_eq!(classify(code, "out of cycles"), PollFailureCause::Other);
+        }
+        assert_eq!(classify(RejectionCode::Unknown, "not found"), PollFailureCause::Other);
+        assert_eq!(classify(RejectionCode::NoError, ""), PollFailureCause::Other);
+    }
+
+    #[test]
+    fn stale_after_n_intervals() {
+        // 间隔 60 秒 3 个间隔后失效
+        assert!(!is_stale(180 * SECOND, 0, 60, 3));
+        assert!(is_stale(180 * SECOND + 1, 0, 60, 3));
+        assert!(!is_stale(200 * SECOND, 100 * SECOND, 60, 3));
+        // 为 0 时不检查
+        assert!(!is_stale(u64::MAX, 0, 60, 0));
+        // 时钟回退不算失效
+        assert!(!is_stale(0, 10 * SECOND, 60, 3));
+    }
+}
@@ -0,0 +1,54 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use ic_cdk::export::candid::Principal;
+
+use crate::alerts;
+use crate::common::types::{AlertKind, CanisterRunningStatus, CanisterStatusInfo};
+
+thread_local!{
+    static CANISTER_STATUSES:RefCell<BTreeMap<Principal, CanisterStatusInfo>> = RefCell::default();  // 罐最近一次读取的完整状态
+}
+
+pub fn snapshot() -> BTreeMap<Principal, CanisterStatusInfo> {
+    CANISTER_STATUSES.with(|statuses| statuses.borrow().clone())
+}
+
+pub fn restore(statuses: BTreeMap<Principal, CanisterStatusInfo>) {
+    CANISTER_STATUSES.with(|s| *s.borrow_mut() = statuses)
+}
+
+pub fn canister_status(canister_id: &Principal) -> Option<CanisterStatusInfo> {
+    CANISTER_STATUSES.with(|statuses| statuses.borrow().get(canister_id).cloned())
+}
+
+pub fn remove_status(canister_id: &Principal) {
+    CANISTER_STATUSES.with(|statuses| statuses.borrow_mut().remove(canister_id));
+}
+
+// 记录最新状态 与上一次状态比较, 罐停止/升级 (module hash 变化)/控制者变化 时为收录该罐的组织告警
+pub fn record_status(canister_id: Principal, status: CanisterStatusInfo) {
+    let previous = CANISTER_STATUSES.with(|statuses| statuses.borrow_mut().insert(canister_id, status.clone()));
+
+    let was_running = previous.as_ref().is_none_or(|previous| previous.status == CanisterRunningStatus::Running);
+    if was_running && status.status != CanisterRunningStatus::Running {
+        alerts::raise_canister_alert(canister_id, AlertKind::CanisterStopped{ status: status.status.clone() });
+    }
+    // 首次读取没有可比较的状态
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return,
+    };
+    if previous.module_hash != status.module_hash {
+        alerts::raise_canister_alert(canister_id, AlertKind::ModuleHashChanged{
+            old_hash: previous.module_hash,
+            new_hash: status.module_hash,
+        });
+    }
+    if previous.controllers != status.controllers {
+        alerts::raise_canister_alert(canister_id, AlertKind::ControllersChanged{
+            old_controllers: previous.controllers,
+            new_controllers: status.controllers,
+        });
+    }
+}
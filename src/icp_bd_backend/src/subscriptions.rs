@@ -0,0 +1,167 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+
+use crate::common::types::{CyclesThreshold, OrganizeName, Subscription, ThresholdNotification};
+use crate::{is_admin, is_owner, is_owner_or_member, ORGANIZES_TO_CANISTERS};
+
+const MAX_SUBSCRIPTIONS_PER_CANISTER: usize = 5;  // 每个组织每个罐最多订阅数
+
+thread_local!{
+    static SUBSCRIPTIONS:RefCell<BTreeMap<u64, Subscription>> = RefCell::default();  // 订阅 id -> 订阅
+    static NEXT_SUBSCRIPTION_ID:Cell<u64> = const { Cell::new(0) };
+}
+
+// 升级时保存的订阅状态
+#[derive(CandidType, Deserialize, Default)]
+pub struct SubscriptionsStable {
+    subscriptions: BTreeMap<u64, Subscription>,
+    next_subscription_id: u64,
+}
+
+pub fn snapshot() -> SubscriptionsStable {
+    SubscriptionsStable {
+        subscriptions: SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().clone()),
+        next_subscription_id: NEXT_SUBSCRIPTION_ID.with(|id| id.get()),
+    }
+}
+
+pub fn restore(stable: SubscriptionsStable) {
+    SUBSCRIPTIONS.with(|subscriptions| *subscriptions.borrow_mut() = stable.subscriptions);
+    NEXT_SUBSCRIPTION_ID.with(|id| id.set(stable.next_subscription_id));
+}
+
+// 罐低于阈值时 通知该组织订阅了这个罐的回调罐
+pub fn notify_threshold(organize_name: &OrganizeName, canister_id: Principal, crossed: &CyclesThreshold, cycles_balance: u64) {
+    let targets: Vec<Subscription> = SUBSCRIPTIONS.with(|subscriptions|{
+        subscriptions.borrow().values()
+            .filter(|subscription| subscription.organize_name == *organize_name && subscription.canister_id == canister_id)
+            .cloned()
+            .collect()
+    });
+    let notification = ThresholdNotification{
+        organize_name: organize_name.clone(),
+        canister_id,
+        level: crossed.level,
+        cycles_threshold: crossed.cycles,
+        cycles_balance,
+        action: crossed.action,
+        time: ic_cdk::api::time(),
+    };
+    for subscription in targets {
+        deliver(subscription, notification.clone());
+    }
+}
+
+// 单向调用回调方法 不等待回复 [回调罐由组织所有者指定, 不回复的回调罐不能让本罐无法停止或升级]
+// 单向调用无法得知回调罐是否处理或拒绝了通知, 只能记录发出调用时的同步错误 (如本罐消息队列已满)
+// 因此不会因回调罐失效自动取消订阅, 由组织所有者根据回调罐自身的情况取消
+fn deliver(subscription: Subscription, notification: ThresholdNotification) {
+    let result = ic_cdk::api::call::notify(subscription.callback_canister, &subscription.callback_method, (notification,));
+    SUBSCRIPTIONS.with(|subscriptions|{
+        if let Some(current) = subscriptions.borrow_mut().get_mut(&subscription.id) {
+            match result {
+                Ok(()) => current.last_delivered = Some(ic_cdk::api::time()),
+                Err(code) => {
+                    current.total_failures += 1;
+                    current.last_error = Some(format!("{:?}", code));
+                },
+            }
+        }
+    });
+}
+
+// 罐不再被任何组织收录时删除订阅
+pub fn remove_canister(canister_id: &Principal) {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().retain(|_, subscription| subscription.canister_id != *canister_id));
+}
+
+// 组织移除该罐时删除该组织对这个罐的订阅
+pub fn remove_from_organize(canister_id: &Principal, organize_name: &OrganizeName) {
+    SUBSCRIPTIONS.with(|subscriptions|{
+        subscriptions.borrow_mut().retain(|_, subscription| subscription.canister_id != *canister_id || subscription.organize_name != *organize_name)
+    });
+}
+
+// 组织解散时清除该组织的订阅
+pub fn remove_organize(organize_name: &OrganizeName) {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().retain(|_, subscription| subscription.organize_name != *organize_name));
+}
+
+// 组织所有人 为组织下的罐订阅阈值通知 返回订阅 id
+// 回调方法签名为 (ThresholdNotification) -> ()
+#[update]
+pub fn organization_owner_subscribe(organize_name: OrganizeName, canister_id: Principal, callback_canister: Principal, callback_method: String) -> Result<u64, String> {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return Err(String::from("Non-organization owners cannot add subscriptions"));  // 非组织所有者不可添加订阅
+    }
+    let exists = ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        organizes_to_canisters.borrow().get(&organize_name).is_some_and(|canisters| canisters.borrow().contains_key(&canister_id))
+    });
+    if !exists {
+        return Err(String::from("The canister does not exist under this organization"));  // 罐不存在
+    }
+    if callback_method.is_empty() {
+        return Err(String::from("callback method must not be empty"));  // 回调方法不能为空
+    }
+    let count = SUBSCRIPTIONS.with(|subscriptions|{
+        subscriptions.borrow().values()
+            .filter(|subscription| subscription.organize_name == organize_name && subscription.canister_id == canister_id)
+            .count()
+    });
+    if count >= MAX_SUBSCRIPTIONS_PER_CANISTER {
+        return Err(String::from("Too many subscriptions for this canister"));  // 该罐订阅数量已达上限
+    }
+    let id = NEXT_SUBSCRIPTION_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().insert(id, Subscription{
+        id,
+        organize_name,
+        canister_id,
+        callback_canister,
+        callback_method,
+        total_failures: 0,
+        last_error: None,
+        last_delivered: None,
+        instime: ic_cdk::api::time(),
+    }));
+    Ok(id)
+}
+
+// 组织所有人 取消订阅
+#[update]
+pub fn organization_owner_unsubscribe(organize_name: OrganizeName, subscription_id: u64) -> String {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return String::from("Non-organization owners cannot remove subscriptions");  // 非组织所有者不可取消订阅
+    }
+    SUBSCRIPTIONS.with(|subscriptions|{
+        let mut subscriptions = subscriptions.borrow_mut();
+        match subscriptions.get(&subscription_id) {
+            Some(subscription) if subscription.organize_name == organize_name => {
+                subscriptions.remove(&subscription_id);
+                String::from("unsubscribed")  // 已取消订阅
+            },
+            _ => String::from("subscription does not exist"),  // 订阅不存在
+        }
+    })
+}
+
+// 查询组织的订阅
+#[query]
+pub fn organization_subscriptions(organize_name: OrganizeName) -> Result<Vec<Subscription>, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_admin(&caller) && !is_owner_or_member(&caller, &organize_name) {
+        return Err(String::from("No permission to view this organization"));  // 无权查看该组织
+    }
+    Ok(SUBSCRIPTIONS.with(|subscriptions|{
+        subscriptions.borrow().values()
+            .filter(|subscription| subscription.organize_name == organize_name)
+            .cloned()
+            .collect()
+    }))
+}
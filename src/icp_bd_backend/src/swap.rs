@@ -0,0 +1,560 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use bigdecimal::ToPrimitive;
+use ic_cdk::export::candid::{CandidType, Deserialize, Int, Nat, Principal};
+use ic_cdk_macros::{query, update};
+use ic_ledger_types::{AccountIdentifier, DEFAULT_FEE, DEFAULT_SUBACCOUNT};
+
+use crate::clients::sonic::Sonic;
+use crate::common::types::{Currency, MarketOrder, OrderDirective, SwapExecution, SwapExecutionStatus, SwapStep, SwapStepReceipt, WrapRecord};
+use crate::{orders, router, tokens, wrap};
+use crate::{get_state, is_admin, token_id_by_currency};
+
+pub const DEFAULT_SWAP_DEADLINE: u64 = 5 * 60 * 1_000_000_000;  // 默认兑换截止时间 纳秒
+pub const MAX_SLIPPAGE_BPS: u64 = 5_000;  // 最大滑点 万分之
+
+thread_local!{
+    static SWAP_IN_FLIGHT:Cell<bool> = const { Cell::new(false) };  // 同时只执行一笔兑换 [按余额差计算成交数量]
+    static EXECUTIONS:RefCell<BTreeMap<u64, SwapExecution>> = RefCell::default();  // 执行 id -> 兑换执行记录
+    static NEXT_EXECUTION_ID:Cell<u64> = const { Cell::new(0) };
+}
+
+// 升级时保存的兑换执行记录
+#[derive(CandidType, Deserialize, Default)]
+pub struct SwapStable {
+    executions: BTreeMap<u64, SwapExecution>,
+    next_execution_id: u64,
+}
+
+pub fn snapshot() -> SwapStable {
+    SwapStable {
+        executions: EXECUTIONS.with(|executions| executions.borrow().clone()),
+        next_execution_id: NEXT_EXECUTION_ID.with(|id| id.get()),
+    }
+}
+
+// 升级打断的执行 标记为失败 可通过 recover_swap_execution 恢复
+// 在授权、存入或兑换的调用中被打断的执行 调用前已保存为未确认, 恢复时按授权或余额判断是否已经执行
+pub fn restore(stable: SwapStable) {
+    let mut executions = stable.executions;
+    for execution in executions.values_mut() {
+        if execution.status == SwapExecutionStatus::Running {
+            execution.status = SwapExecutionStatus::Failed;
+            execution.error = Some(String::from("interrupted by upgrade"));
+        }
+    }
+    EXECUTIONS.with(|e| *e.borrow_mut() = executions);
+    NEXT_EXECUTION_ID.with(|id| id.set(stable.next_execution_id));
+}
+
+// 兑换锁 离开作用域时释放 [回调 trap 时由 cleanup 释放, 不会一直占用]
+pub struct SwapLock(());
+
+impl SwapLock {
+    // 尝试占用兑换锁 recovering 为正在恢复的执行
+    // 有未确认的存入或兑换时 其余执行不能改变本罐在 Sonic 的余额, 否则无法判断该调用是否已经执行
+    pub fn acquire(recovering: Option<u64>) -> Result<SwapLock, String> {
+        let blocking = EXECUTIONS.with(|executions|{
+            executions.borrow().values()
+                .find(|execution| {
+                    execution.status == SwapExecutionStatus::Failed
+                        && execution.unconfirmed
+                        && matches!(execution.next_step, SwapStep::Deposit | SwapStep::Swap)
+                        && Some(execution.id) != recovering
+                })
+                .map(|execution| execution.id)
+        });
+        if let Some(execution_id) = blocking {
+            return Err(format!("swap execution {} must be recovered first", execution_id));  // 需要先恢复未确认的执行
+        }
+        if SWAP_IN_FLIGHT.with(|in_flight| in_flight.replace(true)) {
+            return Err(String::from("another swap is in progress, try again later"));  // 有兑换正在执行 稍后重试
+        }
+        Ok(SwapLock(()))
+    }
+}
+
+impl Drop for SwapLock {
+    fn drop(&mut self) {
+        SWAP_IN_FLIGHT.with(|in_flight| in_flight.set(false));
+    }
+}
+
+pub fn get_execution(execution_id: u64) -> Option<SwapExecution> {
+    EXECUTIONS.with(|executions| executions.borrow().get(&execution_id).cloned())
+}
+
+// 兑换路径、滑点限制和需要从用户转入的数量
+pub struct SwapBound {
+    pub path: Vec<String>,
+    pub bound: Nat,  // GiveExact 时的最少得到数量, TakeExact 时存入 Sonic 的最多付出数量
+    pub amount_in: Nat,  // 从用户转入的数量 [含授权和存入的手续费]
+}
+
+// 按照最优路径的报价和滑点计算 GiveExact 的最少得到数量 或 TakeExact 的最多付出数量
+// 报价按扣除授权和存入手续费后实际兑换的数量计算, 价格影响超过配置的上限时拒绝
+pub async fn swap_bound(market_order: &MarketOrder, slippage_bps: u64) -> Result<SwapBound, String> {
+    let give_token = token_id_by_currency(market_order.give_currency.clone())?;
+    let take_token = token_id_by_currency(market_order.take_currency.clone())?;
+    let give_fee = token_fee(&give_token).await?;
+    let fees = give_fee.clone() + give_fee.clone();
+    let directive = match &market_order.directive {
+        OrderDirective::GiveExact(amount) => OrderDirective::GiveExact(swappable_amount(amount, &give_fee)
+            .ok_or_else(|| String::from("amount is too small to cover the token fee"))?),
+        OrderDirective::TakeExact(amount) => OrderDirective::TakeExact(amount.clone()),
+    };
+    let (quote, _, _) = router::checked_quote(give_token, take_token, &directive).await?;
+    Ok(match &market_order.directive {
+        OrderDirective::GiveExact(amount) => SwapBound {
+            path: quote.path,
+            bound: Nat(quote.amount_out.0 * (10_000 - slippage_bps) / 10_000u64),
+            amount_in: amount.clone(),
+        },
+        OrderDirective::TakeExact(_) => {
+            let bound = Nat(quote.amount_in.0 * (10_000 + slippage_bps) / 10_000u64);
+            SwapBound {
+                path: quote.path,
+                amount_in: bound.clone() + fees,
+                bound,
+            }
+        },
+    })
+}
+
+// 创建兑换执行记录 [之后调用 run 执行]
+pub fn start_execution(order_id: u64, owner: Principal, market_order: MarketOrder, swap_bound: SwapBound, slippage_bps: u64, deadline: u64) -> u64 {
+    let id = NEXT_EXECUTION_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    let now = ic_cdk::api::time();
+    let zero = Nat::from(0u64);
+    let SwapBound{ path, bound, amount_in } = swap_bound;
+    EXECUTIONS.with(|executions| executions.borrow_mut().insert(id, SwapExecution{
+        id,
+        order_id,
+        owner,
+        market_order,
+        path,
+        slippage_bps,
+        bound,
+        deadline,
+        amount_in,
+        status: SwapExecutionStatus::Running,
+        next_step: SwapStep::TransferIn,
+        refunding: false,
+        unconfirmed: false,
+        wrap_id: None,
+        wallet_give: zero.clone(),
+        sonic_give: zero.clone(),
+        sonic_take: zero.clone(),
+        sonic_give_before: zero.clone(),
+        sonic_take_before: zero.clone(),
+        wallet_take: zero.clone(),
+        spent: zero.clone(),
+        received: zero.clone(),
+        amount_out: zero.clone(),
+        refunded: zero,
+        receipts: Vec::new(),
+        error: None,
+        instime: now,
+        updtime: now,
+    }));
+    id
+}
+
+// 从 next_step 开始逐步执行直到完成或某一步失败 [调用前需要持有兑换锁]
+// 每一步的结果都写入回执, 失败时停在该步 由 recover 决定重试或退款
+pub async fn run(execution_id: u64) {
+    let (give_fee, take_fee) = match get_execution(execution_id) {
+        Some(execution) => {
+            let give_fee = match token_id_by_currency(execution.market_order.give_currency.clone()) {
+                Ok(token) => token_fee(&token).await,
+                Err(err) => Err(err),
+            };
+            let take_fee = match token_id_by_currency(execution.market_order.take_currency.clone()) {
+                Ok(token) => token_fee(&token).await,
+                Err(err) => Err(err),
+            };
+            match (give_fee, take_fee) {
+                (Ok(give_fee), Ok(take_fee)) => (give_fee, take_fee),
+                (Err(err), _) | (_, Err(err)) => {
+                    fail(execution_id, execution.next_step, err);
+                    return;
+                },
+            }
+        },
+        None => return,
+    };
+    loop {
+        let mut execution = match get_execution(execution_id) {
+            Some(execution) => execution,
+            None => return,
+        };
+        if execution.next_step == SwapStep::Done {
+            execution.status = if execution.refunding { SwapExecutionStatus::Refunded } else { SwapExecutionStatus::Completed };
+            execution.updtime = ic_cdk::api::time();
+            EXECUTIONS.with(|executions| executions.borrow_mut().insert(execution_id, execution.clone()));
+            orders::execution_settled(&execution);
+            return;
+        }
+        let step = execution.next_step;
+        match run_step(&mut execution, &give_fee, &take_fee).await {
+            Ok(tx) => {
+                let now = ic_cdk::api::time();
+                execution.receipts.push(SwapStepReceipt{ step, time: now, tx, error: None });
+                execution.updtime = now;
+                EXECUTIONS.with(|executions| executions.borrow_mut().insert(execution_id, execution));
+            },
+            Err(err) => {
+                fail(execution_id, step, err);
+                return;
+            },
+        }
+    }
+}
+
+fn fail(execution_id: u64, step: SwapStep, error: String) {
+    let now = ic_cdk::api::time();
+    let failed = EXECUTIONS.with(|executions|{
+        let mut executions = executions.borrow_mut();
+        let execution = executions.get_mut(&execution_id)?;
+        execution.receipts.push(SwapStepReceipt{ step, time: now, tx: None, error: Some(error.clone()) });
+        execution.status = SwapExecutionStatus::Failed;
+        execution.error = Some(error);
+        execution.updtime = now;
+        Some(execution.clone())
+    });
+    if let Some(execution) = failed {
+        orders::execution_settled(&execution);
+    }
+}
+
+// 执行一步 成功时更新执行记录中各处持有的数量并前进到下一步
+async fn run_step(execution: &mut SwapExecution, give_fee: &Nat, take_fee: &Nat) -> Result<Option<Nat>, String> {
+    let sonic = get_state().sonic_swap_canister;
+    let me = ic_cdk::id();
+    let zero = Nat::from(0u64);
+    let give_token = token_id_by_currency(execution.market_order.give_currency.clone())?;
+    let take_token = token_id_by_currency(execution.market_order.take_currency.clone())?;
+    match execution.next_step {
+        // 从用户转入 [先检查余额和授权 不足时不发起转账]
+        SwapStep::TransferIn => {
+            if swappable_amount(&execution.amount_in, give_fee).is_none() {
+                return Err(String::from("amount is too small to cover the token fee"));  // 数量不足以支付手续费
+            }
+            // ICP 由用户先转到本罐的子账户 [select_canister_account_id], 包装为 WICP 后按 WICP 兑换
+            if matches!(execution.market_order.give_currency, Currency::ICP) {
+                let owner = execution.owner;
+                let amount = to_e8s(&execution.amount_in)?;
+                if execution.wrap_id.is_none() {
+                    let balance = wrap::icp_balance(Some(owner)).await?;
+                    if balance < amount + DEFAULT_FEE.e8s() {
+                        return Err(format!("insufficient ICP balance: {} e8s available, {} e8s required", balance, amount + DEFAULT_FEE.e8s()));  // ICP 余额不足
+                    }
+                }
+                let wrapped = wrap_leg(execution, || wrap::create_wrap(amount, Some(owner))).await?;
+                execution.wallet_give = execution.amount_in.clone();
+                execution.next_step = SwapStep::Approve;
+                return Ok(wrapped.tx);
+            }
+            tokens::check_transfer_from(&give_token, execution.owner, me, &execution.amount_in, give_fee).await?;
+            let tx = tokens::transfer_from(&give_token, execution.owner, me, execution.amount_in.clone()).await?;
+            execution.wallet_give = execution.amount_in.clone();
+            execution.next_step = SwapStep::Approve;
+            Ok(Some(tx))
+        },
+        // 授权 Sonic 转出 [授权手续费从本罐持有的数量中扣除]
+        SwapStep::Approve => {
+            save_unconfirmed(execution, true);
+            let tx = tokens::approve(&give_token, sonic, execution.wallet_give.clone()).await?;
+            execution.unconfirmed = false;
+            execution.wallet_give = after_approve(&execution.wallet_give, give_fee);
+            execution.next_step = SwapStep::Deposit;
+            Ok(Some(tx))
+        },
+        // 存入 Sonic [Sonic 调用 transferFrom 手续费从本罐扣除]
+        SwapStep::Deposit => {
+            let amount = deposit_amount(&execution.wallet_give, give_fee)
+                .ok_or_else(|| String::from("amount is too small to cover the token fee"))?;
+            execution.sonic_give_before = sonic_balance(&sonic, &give_token, me).await?;
+            save_unconfirmed(execution, true);
+            let (deposited,) = Sonic::deposit(&sonic, give_token, amount.clone())
+                .await
+                .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?;
+            save_unconfirmed(execution, false);
+            let tx = deposited.to_res()?;
+            execution.wallet_give = zero;
+            execution.sonic_give = amount;
+            execution.next_step = SwapStep::Swap;
+            Ok(Some(tx))
+        },
+        // 兑换 [成功后立即保存结果, 成交数量在 Settle 中按本罐在 Sonic 的余额差计算]
+        SwapStep::Swap => {
+            if ic_cdk::api::time() >= execution.deadline {
+                return Err(String::from("deadline has passed"));  // 已超过截止时间
+            }
+            execution.sonic_give_before = sonic_balance(&sonic, &give_token, me).await?;
+            execution.sonic_take_before = sonic_balance(&sonic, &take_token, me).await?;
+            // 升级前创建的执行没有路径 按直接交易对兑换
+            let path = if execution.path.is_empty() { vec![give_token.to_text(), take_token.to_text()] } else { execution.path.clone() };
+            let deadline = Int::from(execution.deadline);
+            save_unconfirmed(execution, true);
+            let swapped = match &execution.market_order.directive {
+                OrderDirective::GiveExact(_) => Sonic::swap_exact_tokens_for_tokens(&sonic, execution.sonic_give.clone(), execution.bound.clone(), path, me, deadline).await,
+                OrderDirective::TakeExact(amount) => Sonic::swap_tokens_for_exact_tokens(&sonic, amount.clone(), execution.sonic_give.clone(), path, me, deadline).await,
+            };
+            let (swapped,) = swapped.map_err(|(code, msg)| format!("{:?}: {}", code, msg))?;
+            save_unconfirmed(execution, false);
+            let tx = swapped.to_res()?;
+            execution.next_step = SwapStep::Settle;
+            Ok(Some(tx))
+        },
+        // 按兑换前后的余额差计算成交数量 [兑换已完成 失败时只重试本步]
+        SwapStep::Settle => {
+            let give_after = sonic_balance(&sonic, &give_token, me).await?;
+            let take_after = sonic_balance(&sonic, &take_token, me).await?;
+            let give_before = execution.sonic_give_before.clone();
+            let take_before = execution.sonic_take_before.clone();
+            let spent = if give_before > give_after { give_before - give_after } else { zero.clone() };
+            let received = if take_after > take_before { take_after - take_before } else { zero.clone() };
+            execution.sonic_give = if execution.sonic_give > spent { execution.sonic_give.clone() - spent.clone() } else { zero };
+            execution.sonic_take = received.clone();
+            execution.spent = spent;
+            execution.received = received;
+            execution.next_step = SwapStep::WithdrawTake;
+            Ok(None)
+        },
+        // 取出兑换所得
+        SwapStep::WithdrawTake => {
+            let tx = sonic_withdraw(&sonic, take_token, &execution.sonic_take).await?;
+            execution.wallet_take = execution.wallet_take.clone() + execution.sonic_take.clone();
+            execution.sonic_take = zero;
+            execution.next_step = SwapStep::TransferTake;
+            Ok(tx)
+        },
+        // 兑换所得转给用户 [不足手续费的零头留在本罐]
+        SwapStep::TransferTake => {
+            let currency = execution.market_order.take_currency.clone();
+            let (tx, paid) = transfer_out(execution, &currency, &take_token, execution.wallet_take.clone(), take_fee).await?;
+            execution.amount_out = paid;
+            execution.wallet_take = zero;
+            execution.next_step = SwapStep::WithdrawGive;
+            Ok(tx)
+        },
+        // 取出未用完的部分
+        SwapStep::WithdrawGive => {
+            let tx = sonic_withdraw(&sonic, give_token, &execution.sonic_give).await?;
+            execution.wallet_give = execution.wallet_give.clone() + execution.sonic_give.clone();
+            execution.sonic_give = zero;
+            execution.next_step = SwapStep::TransferGive;
+            Ok(tx)
+        },
+        // 未用完的部分退还用户
+        SwapStep::TransferGive => {
+            let currency = execution.market_order.give_currency.clone();
+            let (tx, paid) = transfer_out(execution, &currency, &give_token, execution.wallet_give.clone(), give_fee).await?;
+            execution.refunded = paid;
+            execution.wallet_give = zero;
+            execution.next_step = SwapStep::Done;
+            Ok(tx)
+        },
+        SwapStep::Done => Ok(None),
+    }
+}
+
+// 调用前保存为未确认 [连同兑换前的余额], 得到答复后清除
+// 调用被拒绝或被升级打断时 保存的记录保留标记
+fn save_unconfirmed(execution: &mut SwapExecution, unconfirmed: bool) {
+    execution.unconfirmed = unconfirmed;
+    EXECUTIONS.with(|executions| executions.borrow_mut().insert(execution.id, execution.clone()));
+}
+
+// 授权后本罐持有的数量 [DIP20 和 ICRC-2 的授权都收取手续费]
+fn after_approve(wallet_give: &Nat, fee: &Nat) -> Nat {
+    if wallet_give > fee { wallet_give.clone() - fee.clone() } else { Nat::from(0u64) }
+}
+
+// 存入 Sonic 的数量 [transferFrom 手续费从本罐扣除] 不足手续费时为 None
+fn deposit_amount(wallet_give: &Nat, fee: &Nat) -> Option<Nat> {
+    (wallet_give > fee).then(|| wallet_give.clone() - fee.clone())
+}
+
+// 从用户转入 amount_in 后实际存入 Sonic 用于兑换的数量
+pub fn swappable_amount(amount_in: &Nat, fee: &Nat) -> Option<Nat> {
+    deposit_amount(&after_approve(amount_in, fee), fee)
+}
+
+async fn sonic_withdraw(sonic: &Principal, token: Principal, amount: &Nat) -> Result<Option<Nat>, String> {
+    if *amount == 0u64 {
+        return Ok(None);
+    }
+    Sonic::withdraw(sonic, token, amount.clone())
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+        .0
+        .to_res()
+        .map(Some)
+}
+
+// 转给用户 返回交易编号和用户实际收到的数量 [不足手续费的零头留在本罐]
+// ICP 由 WICP 解包到用户的 ICP 账户, WICP 从中扣除 ICP 转账手续费
+async fn transfer_out(execution: &mut SwapExecution, currency: &Currency, token: &Principal, amount: Nat, token_fee: &Nat) -> Result<(Option<Nat>, Nat), String> {
+    let icp = matches!(currency, Currency::ICP);
+    let fee = if icp { Nat::from(DEFAULT_FEE.e8s()) } else { token_fee.clone() };
+    if amount <= fee {
+        return Ok((None, Nat::from(0u64)));
+    }
+    let paid = amount.clone() - fee;
+    if !icp {
+        return tokens::transfer(token, execution.owner, paid.clone()).await.map(|tx| (Some(tx), paid));
+    }
+    let e8s = to_e8s(&amount)?;
+    let to = AccountIdentifier::new(&execution.owner, &DEFAULT_SUBACCOUNT);
+    let unwrapped = wrap_leg(execution, || wrap::create_unwrap(e8s, to)).await?;
+    Ok((unwrapped.tx, paid))
+}
+
+// 执行 ICP 包装或解包 记录 id 在调用前保存到执行记录 恢复时继续同一条记录 [账本和 WICP 据此去重]
+async fn wrap_leg(execution: &mut SwapExecution, create: impl FnOnce() -> Result<u64, String>) -> Result<WrapRecord, String> {
+    let wrap_id = match execution.wrap_id {
+        Some(wrap_id) => wrap_id,
+        None => {
+            let wrap_id = create()?;
+            execution.wrap_id = Some(wrap_id);
+            EXECUTIONS.with(|executions| executions.borrow_mut().insert(execution.id, execution.clone()));
+            wrap_id
+        },
+    };
+    let wrapped = wrap::execute(wrap_id).await?;
+    execution.wrap_id = None;
+    Ok(wrapped)
+}
+
+fn to_e8s(amount: &Nat) -> Result<u64, String> {
+    amount.0.to_u64().ok_or_else(|| String::from("ICP amount is too large"))  // ICP 数量过大
+}
+
+async fn sonic_balance(sonic: &Principal, token: &Principal, who: Principal) -> Result<Nat, String> {
+    Sonic::balance_of(sonic, token.to_text(), who)
+        .await
+        .map(|(balance,)| balance)
+        .map_err(|(code, msg)| format!("balance query failed: {:?}: {}", code, msg))
+}
+
+// 代币手续费 [使用元数据缓存]
+async fn token_fee(token: &Principal) -> Result<Nat, String> {
+    tokens::metadata(token).await.map(|metadata| metadata.fee)
+}
+
+// 查询我的兑换执行记录 [新的在前]
+#[query]
+pub fn my_swap_executions() -> Vec<SwapExecution> {
+    let caller = ic_cdk::api::caller();
+    EXECUTIONS.with(|executions|{
+        executions.borrow().values().rev()
+            .filter(|execution| execution.owner == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+// 未确认的调用是否已经执行 按本罐在 Sonic 的余额判断 [兑换锁保证期间没有其他执行改变余额]
+// 授权无法可靠判断 按已收取授权手续费退款
+async fn resolve_unconfirmed(execution: &mut SwapExecution) -> Result<(), String> {
+    let sonic = get_state().sonic_swap_canister;
+    let me = ic_cdk::id();
+    let give_token = token_id_by_currency(execution.market_order.give_currency.clone())?;
+    let take_token = token_id_by_currency(execution.market_order.take_currency.clone())?;
+    let give_fee = token_fee(&give_token).await?;
+    match execution.next_step {
+        SwapStep::Approve => {
+            execution.wallet_give = after_approve(&execution.wallet_give, &give_fee);
+            execution.next_step = SwapStep::WithdrawGive;
+        },
+        SwapStep::Deposit => {
+            let give_now = sonic_balance(&sonic, &give_token, me).await?;
+            if let Some(amount) = deposit_amount(&execution.wallet_give, &give_fee) {
+                if give_now >= execution.sonic_give_before.clone() + amount.clone() {
+                    execution.wallet_give = Nat::from(0u64);
+                    execution.sonic_give = amount;
+                }
+            }
+            execution.next_step = SwapStep::WithdrawGive;
+        },
+        SwapStep::Swap => {
+            let give_now = sonic_balance(&sonic, &give_token, me).await?;
+            let take_now = sonic_balance(&sonic, &take_token, me).await?;
+            // 余额有变化说明兑换已经执行 按余额差结算, 否则退款
+            execution.next_step = if give_now < execution.sonic_give_before || take_now > execution.sonic_take_before {
+                SwapStep::Settle
+            } else {
+                SwapStep::WithdrawGive
+            };
+        },
+        _ => {},
+    }
+    execution.refunding = execution.next_step == SwapStep::WithdrawGive;
+    execution.unconfirmed = false;
+    Ok(())
+}
+
+// 恢复失败的兑换 [用户或管理员]
+// 兑换前的步骤失败时退还已转入的代币, 兑换后的步骤失败时重试该步 [兑换成功后不会再走退款]
+// 未确认的授权、存入或兑换先按余额判断是否已经执行
+#[update]
+pub async fn recover_swap_execution(execution_id: u64) -> Result<SwapExecution, String> {
+    let caller = ic_cdk::api::caller();
+    let execution = match get_execution(execution_id) {
+        Some(execution) if execution.owner == caller || is_admin(&caller) => execution,
+        _ => return Err(String::from("execution does not exist")),  // 执行记录不存在
+    };
+    if execution.status != SwapExecutionStatus::Failed {
+        return Err(String::from("execution has not failed"));  // 执行没有失败
+    }
+    // ICP 转入已创建包装记录时 继续该记录
+    if execution.next_step == SwapStep::TransferIn && execution.wrap_id.is_none() {
+        return Err(String::from("no tokens were transferred, nothing to recover"));  // 没有转入代币 无需恢复
+    }
+    let _lock = SwapLock::acquire(Some(execution_id))?;
+    let mut execution = execution;
+    if execution.unconfirmed {
+        resolve_unconfirmed(&mut execution).await?;
+    } else if matches!(execution.next_step, SwapStep::Approve | SwapStep::Deposit | SwapStep::Swap) {
+        execution.refunding = true;
+        execution.next_step = SwapStep::WithdrawGive;
+    }
+    execution.status = SwapExecutionStatus::Running;
+    execution.error = None;
+    execution.updtime = ic_cdk::api::time();
+    EXECUTIONS.with(|executions| executions.borrow_mut().insert(execution_id, execution));
+    run(execution_id).await;
+    get_execution(execution_id).ok_or_else(|| String::from("execution does not exist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_cdk::export::candid::Nat;
+
+    use super::{after_approve, deposit_amount, swappable_amount};
+
+    // DIP20 的授权和 transferFrom 各收取一次手续费
+    #[test]
+    fn dip20_fees_come_off_the_wallet() {
+        let fee = Nat::from(10u64);
+        let wallet_give = after_approve(&Nat::from(1_000u64), &fee);
+        assert_eq!(wallet_give, Nat::from(990u64));
+        assert_eq!(deposit_amount(&wallet_give, &fee), Some(Nat::from(980u64)));
+        assert_eq!(swappable_amount(&Nat::from(1_000u64), &fee), Some(Nat::from(980u64)));
+    }
+
+    #[test]
+    fn too_small_amounts_cannot_be_deposited() {
+        let fee = Nat::from(10u64);
+        assert_eq!(after_approve(&Nat::from(5u64), &fee), Nat::from(0u64));
+        assert_eq!(swappable_amount(&Nat::from(20u64), &fee), None);
+        assert_eq!(swappable_amount(&Nat::from(21u64), &fee), Some(Nat::from(1u64)));
+    }
+}
@@ -0,0 +1,291 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+
+use crate::{alerts, subscriptions};
+use crate::common::types::{AlertKind, CanisterInfo, CyclesThreshold, OrganizeName, ThresholdAction, ThresholdLevel};
+use crate::{is_admin, is_owner, is_owner_or_member, CANISTERS_TO_ORGANIZES, ORGANIZES_TO_CANISTERS, PUBLIC_CANISTERS};
+
+thread_local!{
+    static DEFAULT_THRESHOLDS:RefCell<BTreeMap<OrganizeName, Vec<CyclesThreshold>>> = RefCell::default();  // 组织默认分级阈值
+    static CROSSED_LEVELS:RefCell<BTreeMap<(Principal, OrganizeName), ThresholdLevel>> = RefCell::default();  // (罐, 组织) 当前低于的最严重级别
+}
+
+// 升级时保存的分级阈值状态
+#[derive(CandidType, Deserialize, Default)]
+pub struct ThresholdsStable {
+    defaults: BTreeMap<OrganizeName, Vec<CyclesThreshold>>,
+    crossed: BTreeMap<(Principal, OrganizeName), ThresholdLevel>,
+}
+
+pub fn snapshot() -> ThresholdsStable {
+    ThresholdsStable {
+        defaults: DEFAULT_THRESHOLDS.with(|m| m.borrow().clone()),
+        crossed: CROSSED_LEVELS.with(|m| m.borrow().clone()),
+    }
+}
+
+pub fn restore(stable: ThresholdsStable) {
+    DEFAULT_THRESHOLDS.with(|m| *m.borrow_mut() = stable.defaults);
+    CROSSED_LEVELS.with(|m| *m.borrow_mut() = stable.crossed);
+}
+
+// 罐生效的分级阈值 罐设置 > 组织默认 > 以最低Cycles作为 Warning 级别只通知
+// 自动充值从本罐扣除 cycles, 需要组织或罐显式设置带充值动作的阈值
+pub fn effective_thresholds(organize_name: &OrganizeName, canister_info: &CanisterInfo) -> Vec<CyclesThreshold> {
+    if let Some(thresholds) = &canister_info.thresholds {
+        return thresholds.clone();
+    }
+    let defaults = DEFAULT_THRESHOLDS.with(|defaults| defaults.borrow().get(organize_name).cloned());
+    defaults.unwrap_or_else(|| vec![CyclesThreshold{
+        level: ThresholdLevel::Warning,
+        cycles: canister_info.cycles_minimum.get(),
+        action: ThresholdAction::NotifyOnly,
+    }])
+}
+
+// 每个级别最多一个阈值
+fn validate(thresholds: &[CyclesThreshold]) -> Result<(), String> {
+    let levels: BTreeSet<ThresholdLevel> = thresholds.iter().map(|threshold| threshold.level).collect();
+    if levels.len() != thresholds.len() {
+        return Err(String::from("Each threshold level can only be set once"));  // 每个级别只能设置一次
+    }
+    Ok(())
+}
+
+// 轮训后为收录该罐的每个组织评估分级阈值
+// 进入更严重的级别时告警, 余额恢复后重新计算; 返回需要充值到的目标余额
+pub fn evaluate(canister_id: Principal, cycles_balance: u64) -> Option<u64> {
+    let organizes: Vec<OrganizeName> = CANISTERS_TO_ORGANIZES.with(|canisters_to_organizes|{
+        match canisters_to_organizes.borrow().get(&canister_id) {
+            Some(organizes) => organizes.iter().map(|(_, organize_name)| organize_name.clone()).collect(),
+            None => Vec::new(),
+        }
+    });
+    let public_highest = PUBLIC_CANISTERS.with(|public_canisters|{
+        public_canisters.borrow().get(&canister_id).map_or(0, |public_canister| public_canister.cycles_highest.get())
+    });
+
+    let mut top_up_target: Option<u64> = None;
+    for organize_name in organizes {
+        let evaluated = ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+            let organizes_to_canisters = organizes_to_canisters.borrow();
+            let canister_info = organizes_to_canisters.get(&organize_name)?.borrow().get(&canister_id)?.borrow().clone();
+            Some((effective_thresholds(&organize_name, &canister_info), canister_info.cycles_highest.get()))
+        });
+        let (thresholds, cycles_highest) = match evaluated {
+            Some(evaluated) => evaluated,
+            None => continue,
+        };
+        let crossed = match update_crossing((canister_id, organize_name.clone()), &thresholds, cycles_balance) {
+            None => continue,
+            // 余额恢复 解决该组织下的阈值告警
+            Some(Crossing::Recovered) => {
+                alerts::resolve_matching(Some(&organize_name), &canister_id, |kind| matches!(kind, AlertKind::ThresholdCrossed{..}));
+                continue;
+            },
+            Some(Crossing::Crossed{ threshold, escalated }) => {
+                if escalated {
+                    subscriptions::notify_threshold(&organize_name, canister_id, &threshold, cycles_balance);
+                    alerts::raise_alert(organize_name, canister_id, AlertKind::ThresholdCrossed{
+                        level: threshold.level,
+                        cycles_threshold: threshold.cycles,
+                        cycles_balance,
+                        action: threshold.action,
+                    });
+                }
+                threshold
+            },
+        };
+        let target = match crossed.action {
+            ThresholdAction::NotifyOnly => None,
+            ThresholdAction::NotifyAndTopUp => Some(cycles_highest),
+            ThresholdAction::PageAndTopUpToMax => Some(public_highest.max(cycles_highest)),
+        };
+        top_up_target = top_up_target.max(target);
+    }
+    top_up_target
+}
+
+// 一个 (罐, 组织) 本次评估的结果
+enum Crossing {
+    Recovered,  // 余额恢复到所有阈值之上 [之前低于过阈值]
+    Crossed { threshold: CyclesThreshold, escalated: bool },  // 低于阈值 escalated 为进入了更严重的级别, 需要告警
+}
+
+// 按余额更新 (罐, 组织) 低于的级别 没有变化需要处理时返回 None
+fn update_crossing(key: (Principal, OrganizeName), thresholds: &[CyclesThreshold], cycles_balance: u64) -> Option<Crossing> {
+    // 低于的阈值中级别最严重的 同级别取阈值最低的
+    let crossed = thresholds.iter()
+        .filter(|threshold| cycles_balance < threshold.cycles)
+        .max_by(|a, b| a.level.cmp(&b.level).then(b.cycles.cmp(&a.cycles)))
+        .cloned();
+    match crossed {
+        Some(threshold) => {
+            let previous = CROSSED_LEVELS.with(|levels| levels.borrow_mut().insert(key, threshold.level));
+            let escalated = previous.is_none_or(|previous| threshold.level > previous);
+            Some(Crossing::Crossed{ threshold, escalated })
+        },
+        None => CROSSED_LEVELS.with(|levels| levels.borrow_mut().remove(&key)).map(|_| Crossing::Recovered),
+    }
+}
+
+// 罐不再被任何组织收录时清除状态
+pub fn remove_canister(canister_id: &Principal) {
+    CROSSED_LEVELS.with(|levels| levels.borrow_mut().retain(|(id, _), _| id != canister_id));
+}
+
+// 组织移除该罐时清除该组织越过的级别 [其他组织仍收录该罐]
+pub fn remove_from_organize(canister_id: &Principal, organize_name: &OrganizeName) {
+    CROSSED_LEVELS.with(|levels| levels.borrow_mut().remove(&(*canister_id, organize_name.clone())));
+}
+
+// 组织解散时清除默认阈值和越过的级别
+pub fn remove_organize(organize_name: &OrganizeName) {
+    DEFAULT_THRESHOLDS.with(|defaults| defaults.borrow_mut().remove(organize_name));
+    CROSSED_LEVELS.with(|levels| levels.borrow_mut().retain(|(_, name), _| name != organize_name));
+}
+
+// 查询组织默认分级阈值
+#[query]
+pub fn get_default_thresholds(organize_name: OrganizeName) -> Result<Vec<CyclesThreshold>, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_admin(&caller) && !is_owner_or_member(&caller, &organize_name) {
+        return Err(String::from("No permission to view this organization"));  // 无权查看该组织
+    }
+    Ok(DEFAULT_THRESHOLDS.with(|defaults| defaults.borrow().get(&organize_name).cloned().unwrap_or_default()))
+}
+
+// 组织所有人 设置组织默认分级阈值 [为空时恢复为以最低Cycles只通知]
+#[update]
+pub fn organization_owner_set_default_thresholds(organize_name: OrganizeName, thresholds: Vec<CyclesThreshold>) -> String {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return String::from("Non-organization owners cannot modify thresholds");  // 非组织所有者不可修改阈值
+    }
+    if let Err(err) = validate(&thresholds) {
+        return err;
+    }
+    DEFAULT_THRESHOLDS.with(|defaults|{
+        if thresholds.is_empty() {
+            defaults.borrow_mut().remove(&organize_name);
+        } else {
+            defaults.borrow_mut().insert(organize_name, thresholds);
+        }
+    });
+    String::from("thresholds updated successfully")  // 阈值更新成功
+}
+
+// 组织所有人 设置罐的分级阈值 [None 时使用组织默认]
+#[update]
+pub fn organization_owner_set_jar_thresholds(organize_name: OrganizeName, canister_id: Principal, thresholds: Option<Vec<CyclesThreshold>>) -> String {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return String::from("Non-organization owners cannot modify thresholds");  // 非组织所有者不可修改阈值
+    }
+    if let Some(thresholds) = &thresholds {
+        if let Err(err) = validate(thresholds) {
+            return err;
+        }
+    }
+    ORGANIZES_TO_CANISTERS.with(|organizes_to_canisters|{
+        let organizes_to_canisters = organizes_to_canisters.borrow();
+        let canisters = match organizes_to_canisters.get(&organize_name) {
+            Some(canisters) => canisters.borrow(),
+            None => return String::from("The canister does not exist under this organization"),  // 罐不存在
+        };
+        match canisters.get(&canister_id) {
+            Some(canister_info) => {
+                canister_info.borrow_mut().thresholds = thresholds;
+                String::from("thresholds updated successfully")  // 阈值更新成功
+            },
+            None => String::from("The canister does not exist under this organization"),  // 罐不存在
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn threshold(level: ThresholdLevel, cycles: u64) -> CyclesThreshold {
+        CyclesThreshold{ level, cycles, action: ThresholdAction::NotifyOnly }
+    }
+
+    fn canister_info(cycles_minimum: u64, thresholds: Option<Vec<CyclesThreshold>>) -> CanisterInfo {
+        CanisterInfo{
+            nickname: String::new(),
+            instime: Cell::new(0),
+            updtime: Cell::new(0),
+            cycles_balance: Cell::new(0),
+            time_interval: Cell::new(60),
+            cycles_minimum: Cell::new(cycles_minimum),
+            cycles_highest: Cell::new(0),
+            status: None,
+            minimum_days_above_freeze: Cell::new(None),
+            thresholds,
+            degraded: None,
+        }
+    }
+
+    fn key() -> (Principal, OrganizeName) {
+        (Principal::from_slice(&[1]), String::from("org"))
+    }
+
+    // 返回 (越过的级别, 是否告警), 恢复时为 None
+    fn step(thresholds: &[CyclesThreshold], cycles_balance: u64) -> Option<(ThresholdLevel, bool)> {
+        match update_crossing(key(), thresholds, cycles_balance)? {
+            Crossing::Recovered => None,
+            Crossing::Crossed{ threshold, escalated } => Some((threshold.level, escalated)),
+        }
+    }
+
+    #[test]
+    fn the_most_severe_crossed_level_wins() {
+        let thresholds = [threshold(ThresholdLevel::Info, 1_000), threshold(ThresholdLevel::Critical, 100), threshold(ThresholdLevel::Warning, 500)];
+        assert!(update_crossing(key(), &thresholds, 1_000).is_none());
+        assert_eq!(step(&thresholds, 999), Some((ThresholdLevel::Info, true)));
+        // 停留在同一级别不重复告警
+        assert_eq!(step(&thresholds, 800), Some((ThresholdLevel::Info, false)));
+        assert_eq!(step(&thresholds, 499), Some((ThresholdLevel::Warning, true)));
+        // 直接越过多个级别时取最严重的
+        assert_eq!(step(&thresholds, 10), Some((ThresholdLevel::Critical, true)));
+        assert_eq!(step(&thresholds, 5), Some((ThresholdLevel::Critical, false)));
+    }
+
+    #[test]
+    fn recovery_rearms_the_alert() {
+        let thresholds = [threshold(ThresholdLevel::Warning, 500), threshold(ThresholdLevel::Critical, 100)];
+        assert_eq!(step(&thresholds, 50), Some((ThresholdLevel::Critical, true)));
+        // 回到较轻的级别后 再次进入严重级别重新告警
+        assert_eq!(step(&thresholds, 300), Some((ThresholdLevel::Warning, false)));
+        assert_eq!(step(&thresholds, 50), Some((ThresholdLevel::Critical, true)));
+        // 恢复到所有阈值之上 只报告一次恢复
+        assert!(matches!(update_crossing(key(), &thresholds, 600), Some(Crossing::Recovered)));
+        assert!(update_crossing(key(), &thresholds, 600).is_none());
+        assert!(CROSSED_LEVELS.with(|levels| levels.borrow().is_empty()));
+        // 恢复后再次低于阈值重新告警
+        assert_eq!(step(&thresholds, 400), Some((ThresholdLevel::Warning, true)));
+    }
+
+    #[test]
+    fn thresholds_fall_back_to_the_organization_default() {
+        let organize_name = String::from("org");
+        // 没有任何设置时 以最低Cycles作为 Warning 级别只通知
+        let fallback = effective_thresholds(&organize_name, &canister_info(700, None));
+        assert_eq!(fallback.len(), 1);
+        assert!(fallback[0].level == ThresholdLevel::Warning && fallback[0].cycles == 700 && fallback[0].action == ThresholdAction::NotifyOnly);
+
+        DEFAULT_THRESHOLDS.with(|defaults| defaults.borrow_mut().insert(organize_name.clone(), vec![threshold(ThresholdLevel::Critical, 300)]));
+        let defaults = effective_thresholds(&organize_name, &canister_info(700, None));
+        assert!(defaults.len() == 1 && defaults[0].level == ThresholdLevel::Critical && defaults[0].cycles == 300);
+
+        // 罐自己的设置优先于组织默认
+        let own = effective_thresholds(&organize_name, &canister_info(700, Some(vec![threshold(ThresholdLevel::Info, 900)])));
+        assert!(own.len() == 1 && own[0].level == ThresholdLevel::Info && own[0].cycles == 900);
+        // 其他组织不受影响
+        assert_eq!(effective_thresholds(&String::from("other"), &canister_info(700, None))[0].cycles, 700);
+    }
+}
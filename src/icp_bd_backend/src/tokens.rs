@@ -0,0 +1,372 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::{query, update};
+use ic_cron::types::{Iterations, SchedulingOptions};
+
+use crate::clients::dip20::Dip20;
+use crate::clients::icrc::{Icrc, IcrcAccount, IcrcAllowanceArgs, IcrcApproveArgs, IcrcMetadataValue, IcrcTransferArg, IcrcTransferFromArgs};
+use crate::common::guards::admin_guard;
+use crate::common::types::{CronTaskKind, TokenInfo, TokenMetadata, TokenStandard};
+use crate::cron_enqueue;
+
+const METADATA_TICK: u64 = 600;  // 检查元数据缓存的间隔 秒
+const METADATA_TTL: u64 = 3_600;  // 元数据缓存超过该时间后重新读取 秒
+
+thread_local!{
+    static TOKENS:RefCell<BTreeMap<Principal, TokenInfo>> = RefCell::default();  // 代币罐 -> 代币信息
+    static METADATA:RefCell<BTreeMap<Principal, TokenMetadata>> = RefCell::default();  // 代币罐 -> 缓存的代币元数据
+}
+
+// 升级时保存的代币登记表和元数据缓存
+#[derive(CandidType, Deserialize, Default)]
+pub struct TokensStable {
+    tokens: BTreeMap<Principal, TokenInfo>,
+    #[serde(default)]
+    metadata: BTreeMap<Principal, TokenMetadata>,
+}
+
+pub fn snapshot() -> TokensStable {
+    TokensStable {
+        tokens: TOKENS.with(|tokens| tokens.borrow().clone()),
+        metadata: METADATA.with(|metadata| metadata.borrow().clone()),
+    }
+}
+
+pub fn restore(stable: TokensStable) {
+    TOKENS.with(|tokens| *tokens.borrow_mut() = stable.tokens);
+    METADATA.with(|metadata| *metadata.borrow_mut() = stable.metadata);
+}
+
+// 注册元数据刷新任务 [安装和升级后调用]
+pub fn schedule_metadata_refresh() {
+    let interval_nano = METADATA_TICK * 1_000_000_000;
+    cron_enqueue(
+        CronTaskKind::RefreshTokenMetadata,
+        SchedulingOptions {
+            delay_nano: interval_nano,
+            interval_nano,
+            iterations: Iterations::Infinite,
+        },
+    ).expect("Unable to schedule token metadata refresh");
+}
+
+// 重新读取超过 METADATA_TTL 的缓存
+pub fn refresh_stale_metadata() {
+    let now = ic_cdk::api::time();
+    let stale: Vec<Principal> = METADATA.with(|metadata|{
+        metadata.borrow().iter()
+            .filter(|(_, cached)| now.saturating_sub(cached.fetched_at) > METADATA_TTL * 1_000_000_000)
+            .map(|(token, _)| *token)
+            .collect()
+    });
+    for token in stale {
+        ic_cdk::spawn(async move {
+            if let Err(err) = fetch_metadata(token, standard_of(&token)).await {
+                ic_cdk::println!("metadata refresh for {} failed: {}", token, err);
+            }
+        });
+    }
+}
+
+// 代币元数据 [优先使用缓存, 没有缓存时读取并缓存]
+pub async fn metadata(token: &Principal) -> Result<TokenMetadata, String> {
+    if let Some(cached) = METADATA.with(|metadata| metadata.borrow().get(token).cloned()) {
+        return Ok(cached);
+    }
+    fetch_metadata(*token, standard_of(token)).await
+}
+
+// 代币标准 [未登记的代币按 DIP20 处理]
+pub fn standard_of(token: &Principal) -> TokenStandard {
+    get(token).map_or(TokenStandard::DIP20, |token| token.standard)
+}
+
+// 从代币罐读取元数据 写入缓存并同步到登记表
+async fn fetch_metadata(token: Principal, standard: TokenStandard) -> Result<TokenMetadata, String> {
+    let now = ic_cdk::api::time();
+    let cached = match standard {
+        TokenStandard::DIP20 => {
+            let (metadata,) = Dip20::get_metadata(&token)
+                .await
+                .map_err(|(code, msg)| format!("metadata query failed: {:?}: {}", code, msg))?;
+            TokenMetadata {
+                symbol: metadata.symbol,
+                name: metadata.name,
+                decimals: metadata.decimals,
+                fee: metadata.fee,
+                fetched_at: now,
+            }
+        },
+        TokenStandard::ICRC => {
+            let (metadata,) = Icrc::icrc1_metadata(&token)
+                .await
+                .map_err(|(code, msg)| format!("metadata query failed: {:?}: {}", code, msg))?;
+            let find = |key: &str| metadata.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone());
+            // 元数据中缺少的项 回退到对应的单独查询接口
+            let symbol = match find("icrc1:symbol") {
+                Some(IcrcMetadataValue::Text(symbol)) => symbol,
+                _ => Icrc::icrc1_symbol(&token).await.map_err(|(code, msg)| format!("icrc1_symbol query failed: {:?}: {}", code, msg))?.0,
+            };
+            let name = match find("icrc1:name") {
+                Some(IcrcMetadataValue::Text(name)) => name,
+                _ => Icrc::icrc1_name(&token).await.map_err(|(code, msg)| format!("icrc1_name query failed: {:?}: {}", code, msg))?.0,
+            };
+            let decimals = match find("icrc1:decimals") {
+                Some(IcrcMetadataValue::Nat(decimals)) => u8::try_from(decimals.0).map_err(|_| String::from("invalid icrc1:decimals"))?,
+                _ => Icrc::icrc1_decimals(&token).await.map_err(|(code, msg)| format!("icrc1_decimals query failed: {:?}: {}", code, msg))?.0,
+            };
+            let fee = match find("icrc1:fee") {
+                Some(IcrcMetadataValue::Nat(fee)) => fee,
+                _ => Icrc::icrc1_fee(&token).await.map_err(|(code, msg)| format!("icrc1_fee query failed: {:?}: {}", code, msg))?.0,
+            };
+            TokenMetadata {
+                symbol,
+                name,
+                decimals,
+                fee,
+                fetched_at: now,
+            }
+        },
+    };
+    METADATA.with(|metadata| metadata.borrow_mut().insert(token, cached.clone()));
+    TOKENS.with(|tokens|{
+        if let Some(registered) = tokens.borrow_mut().get_mut(&token) {
+            registered.symbol = cached.symbol.clone();
+            registered.name = cached.name.clone();
+            registered.decimals = cached.decimals;
+            registered.fee = cached.fee.clone();
+            registered.updtime = now;
+        }
+    });
+    Ok(cached)
+}
+
+// 从 owner 转出给 to [ICRC 代币需要 ICRC-2 授权]
+pub async fn transfer_from(token: &Principal, owner: Principal, to: Principal, amount: Nat) -> Result<Nat, String> {
+    match standard_of(token) {
+        TokenStandard::DIP20 => Dip20::transfer_from(token, owner, to, amount)
+            .await
+            .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+            .0
+            .map_err(|err| format!("{:?}", err)),
+        TokenStandard::ICRC => Icrc::icrc2_transfer_from(token, IcrcTransferFromArgs {
+            spender_subaccount: None,
+            from: IcrcAccount::of(owner),
+            to: IcrcAccount::of(to),
+            amount,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        })
+            .await
+            .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+            .0
+            .map_err(|err| format!("{:?}", err)),
+    }
+}
+
+// 授权 spender 转出
+pub async fn approve(token: &Principal, spender: Principal, amount: Nat) -> Result<Nat, String> {
+    match standard_of(token) {
+        TokenStandard::DIP20 => Dip20::approve(token, spender, amount)
+            .await
+            .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+            .0
+            .map_err(|err| format!("{:?}", err)),
+        TokenStandard::ICRC => Icrc::icrc2_approve(token, IcrcApproveArgs {
+            from_subaccount: None,
+            spender: IcrcAccount::of(spender),
+            amount,
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        })
+            .await
+            .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+            .0
+            .map_err(|err| format!("{:?}", err)),
+    }
+}
+
+// 从本罐转给 to
+pub async fn transfer(token: &Principal, to: Principal, amount: Nat) -> Result<Nat, String> {
+    match standard_of(token) {
+        TokenStandard::DIP20 => Dip20::transfer(token, to, amount)
+            .await
+            .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+            .0
+            .map_err(|err| format!("{:?}", err)),
+        TokenStandard::ICRC => Icrc::icrc1_transfer(token, IcrcTransferArg {
+            from_subaccount: None,
+            to: IcrcAccount::of(to),
+            amount,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        })
+            .await
+            .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+            .0
+            .map_err(|err| format!("{:?}", err)),
+    }
+}
+
+pub async fn balance_of(token: &Principal, owner: Principal) -> Result<Nat, String> {
+    let balance = match standard_of(token) {
+        TokenStandard::DIP20 => Dip20::balance_of(token, owner).await,
+        TokenStandard::ICRC => Icrc::icrc1_balance_of(token, IcrcAccount::of(owner)).await,
+    };
+    balance
+        .map(|(balance,)| balance)
+        .map_err(|(code, msg)| format!("balance query failed: {:?}: {}", code, msg))
+}
+
+// owner 授权 spender 转出的数量
+pub async fn allowance(token: &Principal, owner: Principal, spender: Principal) -> Result<Nat, String> {
+    let allowance = match standard_of(token) {
+        TokenStandard::DIP20 => Dip20::allowance(token, owner, spender).await,
+        TokenStandard::ICRC => Icrc::icrc2_allowance(token, IcrcAllowanceArgs {
+            account: IcrcAccount::of(owner),
+            spender: IcrcAccount::of(spender),
+        })
+            .await
+            .map(|(allowance,)| (allowance.allowance,)),
+    };
+    allowance
+        .map(|(allowance,)| allowance)
+        .map_err(|(code, msg)| format!("allowance query failed: {:?}: {}", code, msg))
+}
+
+// 转出前检查 owner 的余额和授权是否足够 [transferFrom 的手续费由 owner 另付]
+pub async fn check_transfer_from(token: &Principal, owner: Principal, spender: Principal, amount: &Nat, fee: &Nat) -> Result<(), String> {
+    let required = amount.clone() + fee.clone();
+    let balance = balance_of(token, owner).await?;
+    if balance < required {
+        return Err(format!("insufficient balance: {} available, {} required", balance, required));  // 余额不足
+    }
+    let allowance = allowance(token, owner, spender).await?;
+    if allowance < required {
+        return Err(format!("insufficient allowance: {} approved, {} required", allowance, required));  // 授权不足
+    }
+    Ok(())
+}
+
+pub fn get(canister_id: &Principal) -> Option<TokenInfo> {
+    TOKENS.with(|tokens| tokens.borrow().get(canister_id).cloned())
+}
+
+// 已登记且启用的代币 [Currency::Token 只接受这些代币]
+pub fn enabled(canister_id: Principal) -> Result<Principal, String> {
+    match get(&canister_id) {
+        Some(token) if token.enabled => Ok(canister_id),
+        Some(_) => Err(format!("token {} is disabled", canister_id)),  // 代币已停用
+        None => Err(format!("token {} is not registered", canister_id)),  // 代币未登记
+    }
+}
+
+// 所有已登记且启用的代币罐
+pub fn enabled_ids() -> Vec<Principal> {
+    TOKENS.with(|tokens|{
+        tokens.borrow().values()
+            .filter(|token| token.enabled)
+            .map(|token| token.canister_id)
+            .collect()
+    })
+}
+
+// 从代币罐读取元数据
+async fn fetch_token(canister_id: Principal, standard: TokenStandard) -> Result<TokenInfo, String> {
+    let now = ic_cdk::api::time();
+    let metadata = fetch_metadata(canister_id, standard).await?;
+    Ok(TokenInfo {
+        canister_id,
+        standard,
+        symbol: metadata.symbol,
+        name: metadata.name,
+        decimals: metadata.decimals,
+        fee: metadata.fee,
+        enabled: true,
+        instime: now,
+        updtime: now,
+    })
+}
+
+// 登记代币 元数据从代币罐读取 [已登记时更新元数据]
+#[update(guard = "admin_guard")]
+pub async fn admin_register_token(canister_id: Principal, standard: TokenStandard) -> Result<TokenInfo, String> {
+    let mut token = fetch_token(canister_id, standard).await?;
+    if let Some(existing) = get(&canister_id) {
+        token.enabled = existing.enabled;
+        token.instime = existing.instime;
+    }
+    TOKENS.with(|tokens| tokens.borrow_mut().insert(canister_id, token.clone()));
+    Ok(token)
+}
+
+// 重新读取已登记代币的元数据
+#[update(guard = "admin_guard")]
+pub async fn admin_refresh_token(canister_id: Principal) -> Result<TokenInfo, String> {
+    let standard = match get(&canister_id) {
+        Some(token) => token.standard,
+        None => return Err(String::from("token is not registered")),
+    };
+    admin_register_token(canister_id, standard).await
+}
+
+// 启用或停用代币 [停用后不可用于新的报价和订单]
+#[update(guard = "admin_guard")]
+pub fn admin_set_token_enabled(canister_id: Principal, enabled: bool) -> String {
+    TOKENS.with(|tokens|{
+        match tokens.borrow_mut().get_mut(&canister_id) {
+            Some(token) => {
+                token.enabled = enabled;
+                token.updtime = ic_cdk::api::time();
+                String::from("token updated successfully")  // 代币修改成功
+            },
+            None => String::from("token is not registered"),  // 代币未登记
+        }
+    })
+}
+
+// 删除已登记的代币
+#[update(guard = "admin_guard")]
+pub fn admin_remove_token(canister_id: Principal) -> String {
+    TOKENS.with(|tokens|{
+        match tokens.borrow_mut().remove(&canister_id) {
+            Some(_) => String::from("token removed successfully"),  // 代币删除成功
+            None => String::from("token is not registered"),
+        }
+    })
+}
+
+// 查询已登记的代币
+#[query]
+pub fn registered_tokens() -> Vec<TokenInfo> {
+    TOKENS.with(|tokens| tokens.borrow().values().cloned().collect())
+}
+
+// 清除元数据缓存 [None 清除全部] 下次使用时重新读取, 返回清除的数量
+#[update(guard = "admin_guard")]
+pub fn admin_invalidate_token_metadata(token: Option<Principal>) -> u64 {
+    METADATA.with(|metadata|{
+        let mut metadata = metadata.borrow_mut();
+        match token {
+            Some(token) => metadata.remove(&token).map_or(0, |_| 1),
+            None => {
+                let count = metadata.len() as u64;
+                metadata.clear();
+                count
+            },
+        }
+    })
+}
+
+// 查询缓存的代币元数据
+#[query]
+pub fn cached_token_metadata() -> Vec<(Principal, TokenMetadata)> {
+    METADATA.with(|metadata| metadata.borrow().iter().map(|(token, cached)| (*token, cached.clone())).collect())
+}
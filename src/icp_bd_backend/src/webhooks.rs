@@ -0,0 +1,459 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+
+use ic_cdk::api::management_canister::http_request::{http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs, TransformContext};
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use ic_cron::types::{Iterations, SchedulingOptions};
+use sha2::{Digest, Sha256};
+
+use crate::common::types::{AlertEvent, AlertKind, AlertSeverity, CronTaskKind, OrganizeName, Profile, Webhook, WebhookDelivery, WebhookDeliveryStatus, WebhookInfo};
+use crate::{cron_enqueue, get_config, is_admin, is_owner, is_owner_or_member};
+
+const DELIVERY_TICK: u64 = 10;  // 投递任务间隔 秒
+const MAX_ATTEMPTS: u32 = 6;  // 最多尝试次数 超过后标记为失败
+const BACKOFF_BASE: u64 = 30;  // 首次重试等待 秒 之后每次翻倍
+const BACKOFF_MAX: u64 = 3_600;  // 重试等待上限 秒
+const MAX_RESPONSE_BYTES: u64 = 2_048;  // 只关心状态码 响应体由 transform 丢弃
+const MAX_WEBHOOKS_PER_ORGANIZE: usize = 10;
+const MAX_DELIVERIES_PER_ORGANIZE: usize = 500;  // 每个组织最多保留的投递记录
+
+thread_local!{
+    static WEBHOOKS:RefCell<BTreeMap<u64, Webhook>> = RefCell::default();  // webhook id -> webhook
+    static NEXT_WEBHOOK_ID:Cell<u64> = const { Cell::new(0) };
+    static DELIVERIES:RefCell<BTreeMap<u64, WebhookDelivery>> = RefCell::default();  // 投递 id -> 投递记录
+    static NEXT_DELIVERY_ID:Cell<u64> = const { Cell::new(0) };
+    static DELIVERIES_IN_FLIGHT:RefCell<BTreeSet<u64>> = RefCell::default();  // 正在投递的记录
+}
+
+// 升级时保存的 webhook 状态
+#[derive(CandidType, Deserialize, Default)]
+pub struct WebhooksStable {
+    webhooks: BTreeMap<u64, Webhook>,
+    next_webhook_id: u64,
+    deliveries: BTreeMap<u64, WebhookDelivery>,
+    next_delivery_id: u64,
+}
+
+pub fn snapshot() -> WebhooksStable {
+    WebhooksStable {
+        webhooks: WEBHOOKS.with(|webhooks| webhooks.borrow().clone()),
+        next_webhook_id: NEXT_WEBHOOK_ID.with(|id| id.get()),
+        deliveries: DELIVERIES.with(|deliveries| deliveries.borrow().clone()),
+        next_delivery_id: NEXT_DELIVERY_ID.with(|id| id.get()),
+    }
+}
+
+pub fn restore(stable: WebhooksStable) {
+    WEBHOOKS.with(|webhooks| *webhooks.borrow_mut() = stable.webhooks);
+    NEXT_WEBHOOK_ID.with(|id| id.set(stable.next_webhook_id));
+    DELIVERIES.with(|deliveries| *deliveries.borrow_mut() = stable.deliveries);
+    NEXT_DELIVERY_ID.with(|id| id.set(stable.next_delivery_id));
+}
+
+// 注册投递定时任务 [安装和升级后调用]
+pub fn schedule_delivery() {
+    let interval_nano = DELIVERY_TICK * 1_000_000_000;
+    cron_enqueue(
+        CronTaskKind::DeliverWebhooks,
+        SchedulingOptions {
+            delay_nano: interval_nano,
+            interval_nano,
+            iterations: Iterations::Infinite,
+        },
+    ).expect("Unable to schedule webhook delivery");
+}
+
+// 告警产生时 为组织下订阅了该级别的 webhook 生成投递记录
+pub fn enqueue_alert(alert: &AlertEvent) {
+    let targets: Vec<u64> = WEBHOOKS.with(|webhooks|{
+        webhooks.borrow().values()
+            .filter(|webhook| webhook.organize_name == alert.organize_name && alert.severity >= webhook.min_severity)
+            .map(|webhook| webhook.id)
+            .collect()
+    });
+    if targets.is_empty() {
+        return;
+    }
+    let payload = alert_payload(alert);
+    let now = ic_cdk::api::time();
+    DELIVERIES.with(|deliveries|{
+        let mut deliveries = deliveries.borrow_mut();
+        for webhook_id in targets {
+            let id = NEXT_DELIVERY_ID.with(|next| {
+                let id = next.get();
+                next.set(id + 1);
+                id
+            });
+            deliveries.insert(id, WebhookDelivery{
+                id,
+                webhook_id,
+                organize_name: alert.organize_name.clone(),
+                alert_id: alert.id,
+                payload: payload.clone(),
+                status: WebhookDeliveryStatus::Pending,
+                attempts: 0,
+                last_status_code: None,
+                last_error: None,
+                instime: now,
+                next_attempt: now,
+                updtime: now,
+            });
+        }
+        // 超出保留数量时删除该组织最早的已结束投递
+        let organize_deliveries: Vec<u64> = deliveries.values()
+            .filter(|delivery| delivery.organize_name == alert.organize_name)
+            .map(|delivery| delivery.id)
+            .collect();
+        if organize_deliveries.len() > MAX_DELIVERIES_PER_ORGANIZE {
+            let excess = organize_deliveries.len() - MAX_DELIVERIES_PER_ORGANIZE;
+            let expired: Vec<u64> = organize_deliveries.into_iter()
+                .filter(|id| deliveries.get(id).is_some_and(|delivery| delivery.status != WebhookDeliveryStatus::Pending))
+                .take(excess)
+                .collect();
+            for id in expired {
+                deliveries.remove(&id);
+            }
+        }
+    });
+}
+
+// 组织解散时清除该组织的 webhook 和投递记录
+pub fn remove_organize(organize_name: &OrganizeName) {
+    WEBHOOKS.with(|webhooks| webhooks.borrow_mut().retain(|_, webhook| webhook.organize_name != *organize_name));
+    DELIVERIES.with(|deliveries| deliveries.borrow_mut().retain(|_, delivery| delivery.organize_name != *organize_name));
+}
+
+// 投递所有到期的记录
+pub fn deliver_due() {
+    let now = ic_cdk::api::time();
+    let due: Vec<u64> = DELIVERIES.with(|deliveries|{
+        deliveries.borrow().values()
+            .filter(|delivery| delivery.status == WebhookDeliveryStatus::Pending && delivery.next_attempt <= now)
+            .map(|delivery| delivery.id)
+            .collect()
+    });
+    for delivery_id in due {
+        if DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(delivery_id)) {
+            ic_cdk::spawn(deliver(delivery_id));
+        }
+    }
+}
+
+async fn deliver(delivery_id: u64) {
+    let request = DELIVERIES.with(|deliveries|{
+        let deliveries = deliveries.borrow();
+        let delivery = deliveries.get(&delivery_id)?;
+        let webhook = WEBHOOKS.with(|webhooks| webhooks.borrow().get(&delivery.webhook_id).cloned());
+        Some((delivery.clone(), webhook))
+    });
+    let result = match request {
+        Some((delivery, Some(webhook))) => send(&webhook, &delivery).await,
+        Some((_, None)) => Err(String::from("webhook has been removed")),  // webhook 已删除
+        None => {
+            DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&delivery_id));
+            return;
+        },
+    };
+    let now = ic_cdk::api::time();
+    DELIVERIES.with(|deliveries|{
+        if let Some(delivery) = deliveries.borrow_mut().get_mut(&delivery_id) {
+            delivery.attempts += 1;
+            delivery.updtime = now;
+            match result {
+                Ok(status_code) if (200..300).contains(&status_code) => {
+                    delivery.status = WebhookDeliveryStatus::Delivered;
+                    delivery.last_status_code = Some(status_code);
+                    delivery.last_error = None;
+                },
+                Ok(status_code) => {
+                    delivery.last_status_code = Some(status_code);
+                    delivery.last_error = Some(format!("unexpected status code {}", status_code));
+                },
+                Err(err) => {
+                    delivery.last_status_code = None;
+                    delivery.last_error = Some(err);
+                },
+            }
+            if delivery.status == WebhookDeliveryStatus::Pending {
+                if delivery.attempts >= MAX_ATTEMPTS || !WEBHOOKS.with(|webhooks| webhooks.borrow().contains_key(&delivery.webhook_id)) {
+                    delivery.status = WebhookDeliveryStatus::Failed;
+                } else {
+                    let backoff = BACKOFF_BASE.saturating_mul(1u64 << (delivery.attempts - 1)).min(BACKOFF_MAX);
+                    delivery.next_attempt = now + backoff * 1_000_000_000;
+                }
+            }
+        }
+    });
+    DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&delivery_id));
+}
+
+// 发送一次请求 返回状态码
+// 每个副本都会发出请求, 接收方按 X-Webhook-Delivery 去重
+async fn send(webhook: &Webhook, delivery: &WebhookDelivery) -> Result<u16, String> {
+    let timestamp = (delivery.instime / 1_000_000_000).to_string();
+    let signature = hex::encode(hmac_sha256(webhook.secret.as_bytes(), format!("{}.{}", timestamp, delivery.payload).as_bytes()));
+    let request = CanisterHttpRequestArgument {
+        url: webhook.url.clone(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader{ name: String::from("Content-Type"), value: String::from("application/json") },
+            HttpHeader{ name: String::from("X-Webhook-Delivery"), value: delivery.id.to_string() },
+            HttpHeader{ name: String::from("X-Webhook-Timestamp"), value: timestamp },
+            HttpHeader{ name: String::from("X-Webhook-Signature"), value: format!("sha256={}", signature) },
+        ],
+        body: Some(delivery.payload.as_bytes().to_vec()),
+        transform: Some(TransformContext::new(transform_webhook_response, vec![])),
+    };
+    match http_request(request).await {
+        Ok((response,)) => Ok(response.status.0.to_string().parse::<u16>().unwrap_or(0)),
+        Err((code, msg)) => Err(format!("{:?}: {}", code, msg)),
+    }
+}
+
+// 去掉响应中各副本可能不同的部分 只保留状态码 使副本达成共识
+#[query]
+fn transform_webhook_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+// HMAC-SHA256 [RFC 2104]
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+// 告警的 JSON 内容
+fn alert_payload(alert: &AlertEvent) -> String {
+    let (kind, details) = match &alert.kind {
+        AlertKind::BurnRateAnomaly{ burn_rate_per_day, baseline_per_day } =>
+            ("BurnRateAnomaly", format!("{{\"burn_rate_per_day\":{},\"baseline_per_day\":{}}}", burn_rate_per_day, baseline_per_day)),
+        AlertKind::CanisterStopped{ status } =>
+            ("CanisterStopped", format!("{{\"status\":{}}}", json_string(&format!("{:?}", status)))),
+        AlertKind::ModuleHashChanged{ old_hash, new_hash } =>
+            ("ModuleHashChanged", format!("{{\"old_hash\":{},\"new_hash\":{}}}", json_hash(old_hash), json_hash(new_hash))),
+        AlertKind::ControllersChanged{ old_controllers, new_controllers } =>
+            ("ControllersChanged", format!("{{\"old_controllers\":[{}],\"new_controllers\":[{}]}}",
+                old_controllers.iter().map(|p| json_string(&p.to_text())).collect::<Vec<String>>().join(","),
+                new_controllers.iter().map(|p| json_string(&p.to_text())).collect::<Vec<String>>().join(","))),
+        AlertKind::ThresholdCrossed{ level, cycles_threshold, cycles_balance, action } =>
+            ("ThresholdCrossed", format!("{{\"level\":{},\"cycles_threshold\":{},\"cycles_balance\":{},\"action\":{}}}",
+                json_string(&format!("{:?}", level)), cycles_threshold, cycles_balance, json_string(&format!("{:?}", action)))),
+        AlertKind::PollFailed{ error } =>
+            ("PollFailed", format!("{{\"error\":{}}}", json_string(error))),
+        AlertKind::TopUpSucceeded{ amount } =>
+            ("TopUpSucceeded", format!("{{\"amount\":{}}}", amount)),
+        AlertKind::TopUpFailed{ amount, error } =>
+            ("TopUpFailed", format!("{{\"amount\":{},\"error\":{}}}", amount, json_string(error))),
+        AlertKind::SubscriberUnsubscribed{ callback_canister, callback_method, error } =>
+            ("SubscriberUnsubscribed", format!("{{\"callback_canister\":{},\"callback_method\":{},\"error\":{}}}",
+                json_string(&callback_canister.to_text()), json_string(callback_method), json_string(error))),
+        AlertKind::MonitoringStale{ cause, last_success, last_error } =>
+            ("MonitoringStale", format!("{{\"cause\":{},\"last_success\":{},\"last_error\":{}}}",
+                json_string(&format!("{:?}", cause)), last_success, last_error.as_ref().map_or(String::from("null"), |error| json_string(error)))),
+    };
+    format!(
+        "{{\"id\":{},\"organize_name\":{},\"canister_id\":{},\"kind\":{},\"severity\":{},\"time\":{},\"details\":{}}}",
+        alert.id,
+        json_string(&alert.organize_name),
+        json_string(&alert.canister_id.to_text()),
+        json_string(kind),
+        json_string(&format!("{:?}", alert.severity)),
+        alert.time,
+        details,
+    )
+}
+
+fn json_hash(hash: &Option<Vec<u8>>) -> String {
+    match hash {
+        Some(hash) => json_string(&hex::encode(hash)),
+        None => String::from("null"),
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// 校验 webhook 地址 [本地 profile 允许 http 以便使用本地替身服务]
+fn validate_url(url: &str) -> Result<(), String> {
+    if url.starts_with("https://") {
+        return Ok(());
+    }
+    if url.starts_with("http://") && get_config().profile == Profile::Local {
+        return Ok(());
+    }
+    Err(String::from("webhook url must use https"))  // webhook 地址必须为 https
+}
+
+// 组织所有人 注册 webhook 返回 webhook id
+#[update]
+pub fn organization_owner_add_webhook(organize_name: OrganizeName, url: String, secret: String, min_severity: AlertSeverity) -> Result<u64, String> {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return Err(String::from("Non-organization owners cannot add webhooks"));  // 非组织所有者不可添加 webhook
+    }
+    validate_url(&url)?;
+    if secret.len() < 16 {
+        return Err(String::from("webhook secret must be at least 16 bytes"));  // 密钥至少 16 字节
+    }
+    let count = WEBHOOKS.with(|webhooks| webhooks.borrow().values().filter(|webhook| webhook.organize_name == organize_name).count());
+    if count >= MAX_WEBHOOKS_PER_ORGANIZE {
+        return Err(String::from("Too many webhooks in this organization"));  // 组织 webhook 数量已达上限
+    }
+    let id = NEXT_WEBHOOK_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    WEBHOOKS.with(|webhooks| webhooks.borrow_mut().insert(id, Webhook{
+        id,
+        organize_name,
+        url,
+        secret,
+        min_severity,
+        instime: ic_cdk::api::time(),
+    }));
+    Ok(id)
+}
+
+// 组织所有人 删除 webhook [未完成的投递将标记为失败]
+#[update]
+pub fn organization_owner_remove_webhook(organize_name: OrganizeName, webhook_id: u64) -> String {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return String::from("Non-organization owners cannot remove webhooks");  // 非组织所有者不可删除 webhook
+    }
+    let removed = WEBHOOKS.with(|webhooks|{
+        let mut webhooks = webhooks.borrow_mut();
+        match webhooks.get(&webhook_id) {
+            Some(webhook) if webhook.organize_name == organize_name => webhooks.remove(&webhook_id).is_some(),
+            _ => false,
+        }
+    });
+    if !removed {
+        return String::from("webhook does not exist");  // webhook 不存在
+    }
+    DELIVERIES.with(|deliveries|{
+        for delivery in deliveries.borrow_mut().values_mut() {
+            if delivery.webhook_id == webhook_id && delivery.status == WebhookDeliveryStatus::Pending {
+                delivery.status = WebhookDeliveryStatus::Failed;
+                delivery.last_error = Some(String::from("webhook has been removed"));
+            }
+        }
+    });
+    String::from("webhook removed")  // webhook 已删除
+}
+
+// 查询组织的 webhook [不返回密钥]
+#[query]
+pub fn organization_webhooks(organize_name: OrganizeName) -> Result<Vec<WebhookInfo>, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_admin(&caller) && !is_owner_or_member(&caller, &organize_name) {
+        return Err(String::from("No permission to view this organization"));  // 无权查看该组织
+    }
+    Ok(WEBHOOKS.with(|webhooks|{
+        webhooks.borrow().values()
+            .filter(|webhook| webhook.organize_name == organize_name)
+            .map(|webhook| WebhookInfo{
+                id: webhook.id,
+                url: webhook.url.clone(),
+                min_severity: webhook.min_severity,
+                instime: webhook.instime,
+            })
+            .collect()
+    }))
+}
+
+// 查询组织的 webhook 投递记录 [新的在前, 分页]
+#[query]
+pub fn organization_webhook_deliveries(organize_name: OrganizeName, offset: u64, limit: u64) -> Result<Vec<WebhookDelivery>, String> {
+    let caller = ic_cdk::api::caller();
+    if !is_admin(&caller) && !is_owner_or_member(&caller, &organize_name) {
+        return Err(String::from("No permission to view this organization"));  // 无权查看该组织
+    }
+    Ok(DELIVERIES.with(|deliveries|{
+        deliveries.borrow().values().rev()
+            .filter(|delivery| delivery.organize_name == organize_name)
+            .skip(offset as usize)
+            .take(limit.min(100) as usize)
+            .cloned()
+            .collect()
+    }))
+}
+
+// 组织所有人 立即重新投递失败的记录
+#[update]
+pub fn organization_owner_retry_webhook_delivery(organize_name: OrganizeName, delivery_id: u64) -> String {
+    if !is_owner(&ic_cdk::api::caller(), &organize_name) {
+        return String::from("Non-organization owners cannot retry deliveries");  // 非组织所有者不可重新投递
+    }
+    DELIVERIES.with(|deliveries|{
+        match deliveries.borrow_mut().get_mut(&delivery_id) {
+            Some(delivery) if delivery.organize_name == organize_name
+                && delivery.status == WebhookDeliveryStatus::Failed
+                && WEBHOOKS.with(|webhooks| webhooks.borrow().contains_key(&delivery.webhook_id)) => {
+                delivery.status = WebhookDeliveryStatus::Pending;
+                delivery.attempts = 0;
+                delivery.next_attempt = ic_cdk::api::time();
+                String::from("delivery rescheduled")  // 已重新安排投递
+            },
+            _ => String::from("delivery does not exist or has not failed"),  // 投递不存在或未失败
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hmac_sha256;
+
+    // RFC 4231 HMAC-SHA-256 测试向量
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        let cases: Vec<(Vec<u8>, Vec<u8>, &str)> = vec![
+            (vec![0x0b; 20], b"Hi There".to_vec(), "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            (b"Jefe".to_vec(), b"what do ya want for nothing?".to_vec(), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            (vec![0xaa; 20], vec![0xdd; 50], "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+            ((1u8..=25).collect(), vec![0xcd; 50], "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+            // 密钥长于一个分组 先做哈希
+            (vec![0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First".to_vec(), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+            (
+                vec![0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than block-size data. The key needs to be hashed before being used by the HMAC algorithm.".to_vec(),
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(hex::encode(hmac_sha256(&key, &message)), expected);
+        }
+    }
+}
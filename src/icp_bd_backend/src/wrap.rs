@@ -0,0 +1,291 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::{query, update};
+use ic_ledger_types::{AccountBalanceArgs, AccountIdentifier, Memo, Subaccount, Timestamp, Tokens, TransferArgs, TransferError, DEFAULT_FEE, DEFAULT_SUBACCOUNT};
+
+use crate::clients::wicp::{WICPError, WICP};
+use crate::common::guards::admin_guard;
+use crate::common::types::{WrapKind, WrapRecord, WrapStatus};
+use crate::get_state;
+
+thread_local!{
+    static WRAPS:RefCell<BTreeMap<u64, WrapRecord>> = RefCell::default();  // 记录 id -> ICP 与 WICP 的兑换记录
+    static NEXT_WRAP_ID:Cell<u64> = const { Cell::new(0) };
+}
+
+// 升级时保存的兑换记录
+#[derive(CandidType, Deserialize, Default)]
+pub struct WrapStable {
+    wraps: BTreeMap<u64, WrapRecord>,
+    next_wrap_id: u64,
+}
+
+pub fn snapshot() -> WrapStable {
+    WrapStable {
+        wraps: WRAPS.with(|wraps| wraps.borrow().clone()),
+        next_wrap_id: NEXT_WRAP_ID.with(|id| id.get()),
+    }
+}
+
+// 升级打断的兑换 标记为失败 可通过 admin_retry_wrap 重试 [Unwrap 可能已经执行 标记为未确认]
+pub fn restore(stable: WrapStable) {
+    let mut wraps = stable.wraps;
+    for wrap in wraps.values_mut() {
+        if wrap.status == WrapStatus::Pending {
+            wrap.status = WrapStatus::Failed;
+            wrap.error = Some(String::from("interrupted by upgrade"));
+            wrap.unconfirmed = wrap.kind == WrapKind::Unwrap || wrap.block_index.is_some();
+        }
+    }
+    WRAPS.with(|w| *w.borrow_mut() = wraps);
+    NEXT_WRAP_ID.with(|id| id.set(stable.next_wrap_id));
+}
+
+pub fn get_wrap(id: u64) -> Option<WrapRecord> {
+    WRAPS.with(|wraps| wraps.borrow().get(&id).cloned())
+}
+
+fn save(wrap: &mut WrapRecord) {
+    wrap.updtime = ic_cdk::api::time();
+    WRAPS.with(|wraps| wraps.borrow_mut().insert(wrap.id, wrap.clone()));
+}
+
+fn new_wrap(kind: WrapKind, amount: u64, to: AccountIdentifier, from: Option<Principal>) -> u64 {
+    let id = NEXT_WRAP_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    let now = ic_cdk::api::time();
+    let mut wrap = WrapRecord {
+        id,
+        kind,
+        amount,
+        to: to.to_string(),
+        from,
+        block_index: None,
+        tx: None,
+        status: WrapStatus::Pending,
+        error: None,
+        unconfirmed: false,
+        instime: now,
+        updtime: now,
+    };
+    save(&mut wrap);
+    id
+}
+
+// 创建 ICP 换为 WICP 的记录 [之后调用 execute 执行] from 为 None 时从本罐默认账户转出, 否则从该用户在本罐的子账户转出
+pub fn create_wrap(amount: u64, from: Option<Principal>) -> Result<u64, String> {
+    if amount == 0 {
+        return Err(String::from("amount must be greater than zero"));  // 数量必须大于 0
+    }
+    let wicp_account = AccountIdentifier::new(&get_state().wicp_canister, &DEFAULT_SUBACCOUNT);
+    Ok(new_wrap(WrapKind::Wrap, amount, wicp_account, from))
+}
+
+// 创建 WICP 换回 ICP 的记录 [WICP 从中扣除 ICP 转账手续费]
+pub fn create_unwrap(amount: u64, to: AccountIdentifier) -> Result<u64, String> {
+    if amount <= DEFAULT_FEE.e8s() {
+        return Err(String::from("amount is too small to cover the ICP fee"));  // 数量不足以支付手续费
+    }
+    Ok(new_wrap(WrapKind::Unwrap, amount, to, None))
+}
+
+// 执行兑换记录的剩余步骤 [已完成时直接返回]
+// 未确认的 Unwrap 不执行 避免重复取出, 核对 WICP 余额后用 admin_confirm_wrap 处理
+pub async fn execute(id: u64) -> Result<WrapRecord, String> {
+    let mut wrap = match get_wrap(id) {
+        Some(wrap) => wrap,
+        None => return Err(String::from("wrap does not exist")),  // 兑换记录不存在
+    };
+    match wrap.status {
+        WrapStatus::Completed => return Ok(wrap),
+        WrapStatus::Pending => return Err(String::from("wrap is in progress")),  // 兑换正在执行
+        WrapStatus::Failed => {},
+    }
+    if wrap.unconfirmed && wrap.kind == WrapKind::Unwrap {
+        return Err(String::from("unwrap may have been executed, check the WICP balance before unwrapping again"));  // 可能已经取出
+    }
+    run(&mut wrap).await;
+    finish(wrap)
+}
+
+// 将本罐的 amount e8s ICP 换为 WICP: 先转到 WICP 罐的账户, 再用转账的区块高度铸造
+pub async fn wrap_icp(amount: u64) -> Result<WrapRecord, String> {
+    let id = create_wrap(amount, None)?;
+    execute_new(id).await
+}
+
+// 将本罐的 amount e8s WICP 换回 ICP 转到 to 账户
+pub async fn unwrap_wicp(amount: u64, to: AccountIdentifier) -> Result<WrapRecord, String> {
+    let id = create_unwrap(amount, to)?;
+    execute_new(id).await
+}
+
+async fn execute_new(id: u64) -> Result<WrapRecord, String> {
+    let mut wrap = get_wrap(id).ok_or_else(|| String::from("wrap does not exist"))?;
+    run(&mut wrap).await;
+    finish(wrap)
+}
+
+// 本罐账户的 ICP 余额 e8s [owner 为 None 时为默认账户, 否则为该用户在本罐的子账户]
+pub async fn icp_balance(owner: Option<Principal>) -> Result<u64, String> {
+    let subaccount = owner.map_or(DEFAULT_SUBACCOUNT, Subaccount::from);
+    ic_ledger_types::account_balance(get_state().icp_canister, AccountBalanceArgs {
+        account: AccountIdentifier::new(&ic_cdk::id(), &subaccount),
+    })
+        .await
+        .map(|balance| balance.e8s())
+        .map_err(|(code, msg)| format!("ICP balance query failed: {:?}: {}", code, msg))
+}
+
+fn finish(wrap: WrapRecord) -> Result<WrapRecord, String> {
+    match wrap.status {
+        WrapStatus::Failed => Err(format!("wrap {} failed: {}", wrap.id, wrap.error.unwrap_or_default())),
+        _ => Ok(wrap),
+    }
+}
+
+// 执行剩余的步骤 [已有区块高度的 Wrap 只需要铸造]
+async fn run(wrap: &mut WrapRecord) {
+    wrap.status = WrapStatus::Pending;
+    wrap.error = None;
+    save(wrap);
+    let result = match wrap.kind {
+        WrapKind::Wrap => {
+            let block_index = match wrap.block_index {
+                Some(block_index) => Ok(block_index),
+                None => transfer_to_wicp(wrap).await,
+            };
+            match block_index {
+                Ok(block_index) => {
+                    // 转账成功后立即保存区块高度 铸造失败时可重试铸造
+                    wrap.block_index = Some(block_index);
+                    save(wrap);
+                    mint(block_index).await
+                },
+                Err(err) => Err(WicpCallError::Rejected(err)),
+            }
+        },
+        WrapKind::Unwrap => withdraw(wrap.amount, wrap.to.clone()).await.map(Some),
+    };
+    match result {
+        Ok(tx) => {
+            // 区块已用于铸造时没有交易 id
+            if tx.is_some() {
+                wrap.tx = tx;
+            }
+            wrap.unconfirmed = false;
+            wrap.status = WrapStatus::Completed;
+        },
+        Err(WicpCallError::Unconfirmed(err)) => {
+            wrap.unconfirmed = true;
+            wrap.error = Some(err);
+            wrap.status = WrapStatus::Failed;
+        },
+        Err(WicpCallError::Rejected(err)) => {
+            wrap.error = Some(err);
+            wrap.status = WrapStatus::Failed;
+        },
+    }
+    save(wrap);
+}
+
+// memo 为记录 id, created_at_time 为记录创建时间 重试时账本识别为同一笔转账
+// 重复的转账返回原转账的区块高度, 账本只在 24 小时内去重 超过后的重试返回 TxTooOld
+async fn transfer_to_wicp(wrap: &WrapRecord) -> Result<u64, String> {
+    let state = get_state();
+    let transferred = ic_ledger_types::transfer(state.icp_canister, TransferArgs {
+        memo: Memo(wrap.id),
+        amount: Tokens::from_e8s(wrap.amount),
+        fee: DEFAULT_FEE,
+        from_subaccount: wrap.from.map(Subaccount::from),
+        to: AccountIdentifier::new(&state.wicp_canister, &DEFAULT_SUBACCOUNT),
+        created_at_time: Some(Timestamp{ timestamp_nanos: wrap.instime }),
+    })
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?;
+    match transferred {
+        Ok(block_index) | Err(TransferError::TxDuplicate{ duplicate_of: block_index }) => Ok(block_index),
+        // 无法再去重 需要在账本中核对 memo 为记录 id 的转账后重新发起
+        Err(TransferError::TxTooOld{ .. }) => Err(format!("wrap {} is older than the ledger deduplication window, check the ledger for a transfer with memo {} before wrapping again", wrap.id, wrap.id)),
+        Err(err) => Err(format!("ICP transfer failed: {}", err)),
+    }
+}
+
+enum WicpCallError {
+    Rejected(String),  // 答复失败 没有执行
+    Unconfirmed(String),  // 调用被拒绝 可能已经执行
+}
+
+// 区块已用于铸造说明之前的铸造已经执行 [答复丢失后的重试] 视为成功
+async fn mint(block_index: u64) -> Result<Option<Nat>, WicpCallError> {
+    let (minted,) = WICP::mint(&get_state().wicp_canister, None, block_index)
+        .await
+        .map_err(|(code, msg)| WicpCallError::Unconfirmed(format!("{:?}: {}", code, msg)))?;
+    match minted {
+        Ok(tx) => Ok(Some(tx)),
+        Err(WICPError::BlockUsed) => Ok(None),
+        Err(err) => Err(WicpCallError::Rejected(err.message().to_string())),
+    }
+}
+
+// WICP 的 withdraw 不去重, 没有答复时不能确定是否已经取出
+async fn withdraw(amount: u64, to: String) -> Result<Nat, WicpCallError> {
+    WICP::withdraw(&get_state().wicp_canister, amount, to)
+        .await
+        .map_err(|(code, msg)| WicpCallError::Unconfirmed(format!("{:?}: {}", code, msg)))?
+        .0
+        .map_err(|err| WicpCallError::Rejected(err.message().to_string()))
+}
+
+// 管理员 将本罐的 ICP 换为 WICP 数量为 e8s
+#[update(guard = "admin_guard")]
+pub async fn admin_wrap_icp(amount: u64) -> Result<WrapRecord, String> {
+    wrap_icp(amount).await
+}
+
+// 管理员 将本罐的 WICP 换回 ICP 到本罐账户 数量为 e8s
+#[update(guard = "admin_guard")]
+pub async fn admin_unwrap_wicp(amount: u64) -> Result<WrapRecord, String> {
+    unwrap_wicp(amount, AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT)).await
+}
+
+// 管理员 重试失败的兑换 [已转出 ICP 的 Wrap 只重试铸造, 未确认的铸造重试时按区块已使用判断]
+// 未确认的 Unwrap 不重试 避免重复取出
+#[update(guard = "admin_guard")]
+pub async fn admin_retry_wrap(id: u64) -> Result<WrapRecord, String> {
+    match get_wrap(id) {
+        Some(wrap) if wrap.status != WrapStatus::Failed => return Err(String::from("wrap has not failed")),  // 兑换没有失败
+        Some(_) => {},
+        None => return Err(String::from("wrap does not exist")),
+    }
+    execute(id).await
+}
+
+// 管理员 核对 WICP 余额后处理未确认的 Unwrap
+// executed 为 true 时记为完成, 否则清除标记 之后可以重试 [兑换中的 ICP 转出随恢复继续]
+#[update(guard = "admin_guard")]
+pub fn admin_confirm_wrap(id: u64, executed: bool) -> Result<WrapRecord, String> {
+    let mut wrap = match get_wrap(id) {
+        Some(wrap) if wrap.status == WrapStatus::Failed && wrap.unconfirmed => wrap,
+        Some(_) => return Err(String::from("wrap is not unconfirmed")),  // 兑换不是未确认状态
+        None => return Err(String::from("wrap does not exist")),
+    };
+    wrap.unconfirmed = false;
+    if executed {
+        wrap.status = WrapStatus::Completed;
+        wrap.error = None;
+    }
+    save(&mut wrap);
+    Ok(wrap)
+}
+
+// 查询 ICP 与 WICP 的兑换记录 [新的在前]
+#[query(guard = "admin_guard")]
+pub fn wrap_records() -> Vec<WrapRecord> {
+    WRAPS.with(|wraps| wraps.borrow().values().rev().cloned().collect())
+}